@@ -0,0 +1,120 @@
+//! 字句解析と構文解析のスループットを測る
+//!
+//! トークン列をすべてベクタに集めてから構文解析する2パスの方法と、
+//! 構文解析器が字句解析器からトークンを1つずつ取り出す方法を比べる。
+//! `cargo bench --bench lexer` で実行する。`BENCH_MB` で入力の大きさ(MB)を変えられる。
+//! 入力はファイルに書いてから読む。`BENCH_FILE` で1つの式を書いた既存のファイルを使える
+
+use parser::{lex, parse, Lexer, OperatorTable};
+use std::hint::black_box;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+/// 同じ処理を何回か繰り返し、最も速かった時間を使う
+const ROUNDS: usize = 5;
+
+/// 疑似乱数。入力を毎回同じにするため線形合同法で作る
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self, n: u64) -> u64 {
+        self.0 = self
+            .0
+            .wrapping_mul(6364136223846793005)
+            .wrapping_add(1442695040888963407);
+        (self.0 >> 33) % n
+    }
+}
+
+/// 深さdepthの釣り合った二分木の式を書く。木が深くならないので大きな式でも再帰は浅い
+fn expression(depth: u32, rng: &mut Rng, buf: &mut String) {
+    if depth == 0 {
+        match rng.next(4) {
+            0 => buf.push_str(&format!("{}.{}", rng.next(100), rng.next(100))),
+            1 => buf.push('x'),
+            _ => buf.push_str(&rng.next(100_000).to_string()),
+        }
+        return;
+    }
+    let op = ["+", "-", "*", "/"][rng.next(4) as usize];
+    buf.push('(');
+    expression(depth - 1, rng, buf);
+    buf.push(' ');
+    buf.push_str(op);
+    buf.push(' ');
+    expression(depth - 1, rng, buf);
+    buf.push(')');
+}
+
+/// おおよそmegabytes MBの式を作る
+fn input(megabytes: usize) -> String {
+    let mut depth = 1;
+    loop {
+        let mut buf = String::new();
+        expression(depth, &mut Rng(42), &mut buf);
+        if buf.len() >= megabytes << 20 {
+            return buf;
+        }
+        depth += 1;
+    }
+}
+
+fn measure(name: &str, bytes: usize, mut f: impl FnMut()) {
+    let best = (0..ROUNDS)
+        .map(|_| {
+            let start = Instant::now();
+            f();
+            start.elapsed()
+        })
+        .min()
+        .unwrap_or(Duration::ZERO);
+    let mb = bytes as f64 / (1 << 20) as f64;
+    println!(
+        "{:<28} {:>8.2} ms {:>8.1} MB/s",
+        name,
+        best.as_secs_f64() * 1000.0,
+        mb / best.as_secs_f64()
+    );
+}
+
+fn main() {
+    let megabytes = std::env::var("BENCH_MB")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or(4);
+    // 指定がなければ生成した式を一時ファイルに書き、終わったら消す
+    let (path, generated) = match std::env::var_os("BENCH_FILE") {
+        Some(path) => (PathBuf::from(path), false),
+        None => {
+            let path = std::env::temp_dir().join("parser-bench-lexer.calc");
+            std::fs::write(&path, input(megabytes)).unwrap();
+            (path, true)
+        }
+    };
+    let src = std::fs::read_to_string(&path).unwrap();
+    let ops = OperatorTable::default();
+    println!("input: {} ({} bytes)", path.display(), src.len());
+
+    measure("lex: collect into Vec", src.len(), || {
+        black_box(lex(&src).unwrap());
+    });
+    measure("lex: iterate", src.len(), || {
+        black_box(Lexer::new(&src).map(Result::unwrap).count());
+    });
+    measure("parse: two passes", src.len(), || {
+        let tokens = lex(&src).unwrap();
+        black_box(parse(tokens).unwrap());
+    });
+    measure("parse: streaming", src.len(), || {
+        black_box(ops.parse(&src).unwrap());
+    });
+    // ファイルの読み込みも含めた時間。トークンは読み込んだ文字列を借用する
+    measure("parse: read file, streaming", src.len(), || {
+        let src = std::fs::read_to_string(&path).unwrap();
+        black_box(ops.parse(&src).unwrap());
+    });
+
+    if generated {
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,22 @@
+//! 任意のバイト列を構文木にしようとしてもパニックしないことを確かめる
+//!
+//! `cargo +nightly fuzz run parse` で実行する(`cargo install cargo-fuzz` が必要)。
+//! UTF-8でない入力は文字列にできないので読み飛ばす。
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use parser::{Ast, SourceMap};
+
+fuzz_target!(|data: &[u8]| {
+    let src = match std::str::from_utf8(data) {
+        Ok(src) => src,
+        Err(_) => return,
+    };
+    // エラーになったときは診断を描くところまでパニックしないこと
+    if let Err(e) = src.parse::<Ast>() {
+        let sm = SourceMap::new("fuzz", src);
+        for d in e.diagnostics(src) {
+            d.render(&sm);
+        }
+    }
+});
@@ -0,0 +1,543 @@
+//! 構文木のデータ型と組み立て用の関数
+
+use crate::lexer::{Annot, Loc};
+use std::fmt;
+
+/// ASTを表すデータ型
+/// 長い演算子の連鎖から作った木は深くなるので、Clone、PartialEq、Debugは再帰を`ensure_stack`の中で行う
+pub enum AstKind {
+    /// 数値
+    Num(u64),
+    /// 小数
+    Float(f64),
+    /// 真偽値
+    Bool(bool),
+    /// 変数の参照
+    Var(String),
+    /// 変数の束縛
+    Let { var: Annot<String>, e: Box<Ast> },
+    /// 関数の定義
+    FnDef {
+        name: Annot<String>,
+        params: Vec<Annot<String>>,
+        body: Box<Ast>,
+    },
+    /// 関数の呼び出し
+    Call { name: Annot<String>, args: Vec<Ast> },
+    /// 条件式
+    If {
+        cond: Box<Ast>,
+        then: Box<Ast>,
+        els: Box<Ast>,
+    },
+    /// 単項演算
+    UniOp { op: UniOp, e: Box<Ast> },
+    /// 二項演算
+    BinOp { op: BinOp, l: Box<Ast>, r: Box<Ast> },
+    /// 構文エラーの箇所。エラーから回復して解析を続けた木にだけ現れる
+    Error,
+}
+
+pub type Ast = Annot<AstKind>;
+
+/// 木を再帰でたどる関数はこの中で子に降りる。スタックの残りが少なくなったら
+/// ヒープに確保した新しいスタックに切り替えるので、深い木でもスタックを使い切らない
+pub(crate) fn ensure_stack<R>(f: impl FnOnce() -> R) -> R {
+    // 評価器の1段は数KBのスタックを使うので、十分な余裕を残して切り替える
+    const RED_ZONE: usize = 128 * 1024;
+    const STACK_SIZE: usize = 1024 * 1024;
+    stacker::maybe_grow(RED_ZONE, STACK_SIZE, f)
+}
+
+impl Clone for AstKind {
+    fn clone(&self) -> Self {
+        use self::AstKind::*;
+        ensure_stack(|| match self {
+            Num(n) => Num(*n),
+            Float(f) => Float(*f),
+            Bool(b) => Bool(*b),
+            Var(name) => Var(name.clone()),
+            Let { var, e } => Let {
+                var: var.clone(),
+                e: e.clone(),
+            },
+            FnDef { name, params, body } => FnDef {
+                name: name.clone(),
+                params: params.clone(),
+                body: body.clone(),
+            },
+            Call { name, args } => Call {
+                name: name.clone(),
+                args: args.clone(),
+            },
+            If { cond, then, els } => If {
+                cond: cond.clone(),
+                then: then.clone(),
+                els: els.clone(),
+            },
+            UniOp { op, e } => UniOp {
+                op: op.clone(),
+                e: e.clone(),
+            },
+            BinOp { op, l, r } => BinOp {
+                op: op.clone(),
+                l: l.clone(),
+                r: r.clone(),
+            },
+            Error => Error,
+        })
+    }
+}
+
+impl PartialEq for AstKind {
+    fn eq(&self, other: &Self) -> bool {
+        use self::AstKind::*;
+        ensure_stack(|| match (self, other) {
+            (Num(a), Num(b)) => a == b,
+            (Float(a), Float(b)) => a == b,
+            (Bool(a), Bool(b)) => a == b,
+            (Var(a), Var(b)) => a == b,
+            (Let { var: v1, e: e1 }, Let { var: v2, e: e2 }) => v1 == v2 && e1 == e2,
+            (
+                FnDef {
+                    name: n1,
+                    params: p1,
+                    body: b1,
+                },
+                FnDef {
+                    name: n2,
+                    params: p2,
+                    body: b2,
+                },
+            ) => n1 == n2 && p1 == p2 && b1 == b2,
+            (Call { name: n1, args: a1 }, Call { name: n2, args: a2 }) => n1 == n2 && a1 == a2,
+            (
+                If {
+                    cond: c1,
+                    then: t1,
+                    els: e1,
+                },
+                If {
+                    cond: c2,
+                    then: t2,
+                    els: e2,
+                },
+            ) => c1 == c2 && t1 == t2 && e1 == e2,
+            (UniOp { op: o1, e: e1 }, UniOp { op: o2, e: e2 }) => o1 == o2 && e1 == e2,
+            (
+                BinOp {
+                    op: o1,
+                    l: l1,
+                    r: r1,
+                },
+                BinOp {
+                    op: o2,
+                    l: l2,
+                    r: r2,
+                },
+            ) => o1 == o2 && l1 == l2 && r1 == r2,
+            (Error, Error) => true,
+            _ => false,
+        })
+    }
+}
+
+/// deriveしたときと同じ形式で書く
+impl fmt::Debug for AstKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::AstKind::*;
+        ensure_stack(|| match self {
+            Num(n) => f.debug_tuple("Num").field(n).finish(),
+            Float(x) => f.debug_tuple("Float").field(x).finish(),
+            Bool(b) => f.debug_tuple("Bool").field(b).finish(),
+            Var(name) => f.debug_tuple("Var").field(name).finish(),
+            Let { var, e } => f
+                .debug_struct("Let")
+                .field("var", var)
+                .field("e", e)
+                .finish(),
+            FnDef { name, params, body } => f
+                .debug_struct("FnDef")
+                .field("name", name)
+                .field("params", params)
+                .field("body", body)
+                .finish(),
+            Call { name, args } => f
+                .debug_struct("Call")
+                .field("name", name)
+                .field("args", args)
+                .finish(),
+            If { cond, then, els } => f
+                .debug_struct("If")
+                .field("cond", cond)
+                .field("then", then)
+                .field("els", els)
+                .finish(),
+            UniOp { op, e } => f
+                .debug_struct("UniOp")
+                .field("op", op)
+                .field("e", e)
+                .finish(),
+            BinOp { op, l, r } => f
+                .debug_struct("BinOp")
+                .field("op", op)
+                .field("l", l)
+                .field("r", r)
+                .finish(),
+            Error => f.write_str("Error"),
+        })
+    }
+}
+
+// ヘルパメソッドを定義しておく
+impl Ast {
+    pub fn num(n: u64, loc: Loc) -> Self {
+        // impl<T> Annot<T>で実装したnewを呼ぶ
+        Self::new(AstKind::Num(n), loc)
+    }
+
+    pub fn float(f: f64, loc: Loc) -> Self {
+        Self::new(AstKind::Float(f), loc)
+    }
+
+    pub fn bool(b: bool, loc: Loc) -> Self {
+        Self::new(AstKind::Bool(b), loc)
+    }
+
+    pub fn var(name: &str, loc: Loc) -> Self {
+        Self::new(AstKind::Var(name.to_string()), loc)
+    }
+
+    pub fn let_(var: Annot<String>, e: Ast, loc: Loc) -> Self {
+        Self::new(
+            AstKind::Let {
+                var,
+                e: Box::new(e),
+            },
+            loc,
+        )
+    }
+
+    pub fn fn_def(name: Annot<String>, params: Vec<Annot<String>>, body: Ast, loc: Loc) -> Self {
+        Self::new(
+            AstKind::FnDef {
+                name,
+                params,
+                body: Box::new(body),
+            },
+            loc,
+        )
+    }
+
+    pub fn call(name: Annot<String>, args: Vec<Ast>, loc: Loc) -> Self {
+        Self::new(AstKind::Call { name, args }, loc)
+    }
+
+    pub fn if_(cond: Ast, then: Ast, els: Ast, loc: Loc) -> Self {
+        Self::new(
+            AstKind::If {
+                cond: Box::new(cond),
+                then: Box::new(then),
+                els: Box::new(els),
+            },
+            loc,
+        )
+    }
+
+    pub fn uniop(op: UniOp, e: Ast, loc: Loc) -> Self {
+        Self::new(AstKind::UniOp { op, e: Box::new(e) }, loc)
+    }
+
+    pub fn binop(op: BinOp, l: Ast, r: Ast, loc: Loc) -> Self {
+        Self::new(
+            AstKind::BinOp {
+                op,
+                l: Box::new(l),
+                r: Box::new(r),
+            },
+            loc,
+        )
+    }
+
+    pub fn error(loc: Loc) -> Self {
+        Self::new(AstKind::Error, loc)
+    }
+}
+
+/// 単項演算子を表すデータ型
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum UniOpKind {
+    /// 正号
+    Plus,
+    /// 負号
+    Minus,
+    /// 論理否定
+    Not,
+}
+
+pub type UniOp = Annot<UniOpKind>;
+
+impl UniOp {
+    pub fn plus(loc: Loc) -> Self {
+        Self::new(UniOpKind::Plus, loc)
+    }
+
+    pub fn minus(loc: Loc) -> Self {
+        Self::new(UniOpKind::Minus, loc)
+    }
+
+    pub fn not(loc: Loc) -> Self {
+        Self::new(UniOpKind::Not, loc)
+    }
+}
+
+impl UniOpKind {
+    /// 演算子の記号。演算子表の登録名と同じ
+    pub fn symbol(&self) -> &'static str {
+        use self::UniOpKind::*;
+        match self {
+            Plus => "+",
+            Minus => "-",
+            Not => "!",
+        }
+    }
+}
+
+/// 二項演算子を表すデータ型
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub enum BinOpKind {
+    /// 加算
+    Add,
+    /// 減算
+    Sub,
+    /// 乗算
+    Mult,
+    /// 除算
+    Div,
+    /// 剰余
+    Mod,
+    /// べき乗
+    Pow,
+    /// 等しい
+    Eq,
+    /// 等しくない
+    Ne,
+    /// 小なり
+    Lt,
+    /// 以下
+    Le,
+    /// 大なり
+    Gt,
+    /// 以上
+    Ge,
+    /// 論理積。右辺は左辺が真のときだけ評価する
+    And,
+    /// 論理和。右辺は左辺が偽のときだけ評価する
+    Or,
+}
+
+impl BinOpKind {
+    /// 演算子の記号。演算子表の登録名と同じ
+    pub fn symbol(&self) -> &'static str {
+        use self::BinOpKind::*;
+        match self {
+            Add => "+",
+            Sub => "-",
+            Mult => "*",
+            Div => "/",
+            Mod => "%",
+            Pow => "^",
+            Eq => "==",
+            Ne => "!=",
+            Lt => "<",
+            Le => "<=",
+            Gt => ">",
+            Ge => ">=",
+            And => "&&",
+            Or => "||",
+        }
+    }
+}
+
+pub type BinOp = Annot<BinOpKind>;
+
+impl BinOp {
+    pub fn add(loc: Loc) -> Self {
+        Self::new(BinOpKind::Add, loc)
+    }
+    pub fn sub(loc: Loc) -> Self {
+        Self::new(BinOpKind::Sub, loc)
+    }
+    pub fn mult(loc: Loc) -> Self {
+        Self::new(BinOpKind::Mult, loc)
+    }
+    pub fn div(loc: Loc) -> Self {
+        Self::new(BinOpKind::Div, loc)
+    }
+    pub fn modulo(loc: Loc) -> Self {
+        Self::new(BinOpKind::Mod, loc)
+    }
+    pub fn pow(loc: Loc) -> Self {
+        Self::new(BinOpKind::Pow, loc)
+    }
+    pub fn eq(loc: Loc) -> Self {
+        Self::new(BinOpKind::Eq, loc)
+    }
+    pub fn ne(loc: Loc) -> Self {
+        Self::new(BinOpKind::Ne, loc)
+    }
+    pub fn lt(loc: Loc) -> Self {
+        Self::new(BinOpKind::Lt, loc)
+    }
+    pub fn le(loc: Loc) -> Self {
+        Self::new(BinOpKind::Le, loc)
+    }
+    pub fn gt(loc: Loc) -> Self {
+        Self::new(BinOpKind::Gt, loc)
+    }
+    pub fn ge(loc: Loc) -> Self {
+        Self::new(BinOpKind::Ge, loc)
+    }
+    pub fn and(loc: Loc) -> Self {
+        Self::new(BinOpKind::And, loc)
+    }
+    pub fn or(loc: Loc) -> Self {
+        Self::new(BinOpKind::Or, loc)
+    }
+}
+
+/// 生成する式の位置。でたらめでよいが、エラーの位置がそろうことを確かめるため区別はつける
+#[cfg(test)]
+fn arb_loc() -> impl proptest::strategy::Strategy<Value = Loc> {
+    use proptest::prelude::*;
+    (0usize..64).prop_map(|p| Loc(p, p + 1))
+}
+
+/// opsのどれかを演算子にした二項演算の式を生成する
+#[cfg(test)]
+fn arb_binop(
+    ops: &'static [BinOpKind],
+    l: impl proptest::strategy::Strategy<Value = Ast>,
+    r: impl proptest::strategy::Strategy<Value = Ast>,
+) -> impl proptest::strategy::Strategy<Value = Ast> {
+    use proptest::prelude::*;
+    (prop::sample::select(ops), l, r, arb_loc(), arb_loc())
+        .prop_map(|(op, l, r, op_loc, loc)| Ast::binop(BinOp::new(op, op_loc), l, r, loc))
+}
+
+/// 評価器とVMを比べるための式を生成する
+/// 実行時エラーばかりにならないよう、ほとんどは型の合った式にする
+#[cfg(test)]
+pub(crate) fn arb_ast() -> impl proptest::strategy::Strategy<Value = Ast> {
+    use self::BinOpKind::*;
+    use proptest::prelude::*;
+    const ARITH: &[BinOpKind] = &[Add, Sub, Mult, Div, Mod, Pow];
+    const COMPARE: &[BinOpKind] = &[Eq, Ne, Lt, Le, Gt, Ge];
+    const LOGIC: &[BinOpKind] = &[And, Or];
+    const MIXED: &[BinOpKind] = &[Add, Pow, Eq, Lt, And];
+
+    use self::arb_binop as binop;
+    use self::arb_loc as loc;
+    fn call(
+        names: &'static [&'static str],
+        args: impl Strategy<Value = Vec<Ast>>,
+    ) -> impl Strategy<Value = Ast> {
+        (prop::sample::select(names), args, loc()).prop_map(|(name, args, loc)| {
+            Ast::call(Annot::new(name.to_string(), loc.clone()), args, loc)
+        })
+    }
+
+    // 数値の式。変数xには数値が、mには符号を反転すると溢れる-2^63が束縛されている
+    let num_leaf = prop_oneof![
+        6 => (0u64..10, loc()).prop_map(|(n, loc)| Ast::num(n, loc)),
+        1 => (any::<u64>(), loc()).prop_map(|(n, loc)| Ast::num(n, loc)),
+        2 => (-1e3f64..1e3, loc()).prop_map(|(f, loc)| Ast::float(f, loc)),
+        1 => loc().prop_map(|loc| Ast::var("x", loc)),
+        1 => loc().prop_map(|loc| Ast::var("m", loc)),
+    ];
+    let num = num_leaf.prop_recursive(4, 32, 3, |num| {
+        prop_oneof![
+            binop(ARITH, num.clone(), num.clone()),
+            (num.clone(), loc(), loc()).prop_map(|(e, op_loc, loc)| Ast::uniop(
+                UniOp::minus(op_loc),
+                e,
+                loc
+            )),
+            (
+                binop(COMPARE, num.clone(), num.clone()),
+                num.clone(),
+                num.clone(),
+                loc()
+            )
+                .prop_map(|(c, t, e, loc)| Ast::if_(c, t, e, loc)),
+            (num.clone(), loc()).prop_map(|(e, loc)| {
+                Ast::let_(Annot::new("x".to_string(), loc.clone()), e, loc)
+            }),
+            call(&["abs"], prop::collection::vec(num.clone(), 1)),
+            call(&["min", "max", "pow"], prop::collection::vec(num, 2)),
+        ]
+    });
+    // 真偽値の式。変数yには真偽値が束縛されている
+    let cond = prop_oneof![
+        4 => binop(COMPARE, num.clone(), num.clone()),
+        1 => (any::<bool>(), loc()).prop_map(|(b, loc)| Ast::bool(b, loc)),
+        1 => loc().prop_map(|loc| Ast::var("y", loc)),
+    ];
+    let boolean = cond.prop_recursive(2, 8, 2, |b| {
+        prop_oneof![
+            binop(LOGIC, b.clone(), b.clone()),
+            (b, loc(), loc()).prop_map(|(e, op_loc, loc)| Ast::uniop(UniOp::not(op_loc), e, loc)),
+        ]
+    });
+    // 型の合わない式や未定義の名前も少しだけ混ぜる
+    let any_ast = prop_oneof![num.clone(), boolean.clone()];
+    let ill_typed = prop_oneof![
+        binop(MIXED, any_ast.clone(), any_ast.clone()),
+        (any_ast.clone(), loc(), loc()).prop_map(|(e, op_loc, loc)| Ast::uniop(
+            UniOp::minus(op_loc),
+            e,
+            loc
+        )),
+        (any_ast.clone(), any_ast.clone(), loc()).prop_map(|(c, t, loc)| Ast::if_(
+            c,
+            t.clone(),
+            t,
+            loc
+        )),
+        call(&["abs", "nope"], prop::collection::vec(any_ast, 0..3)),
+        loc().prop_map(|loc| Ast::var("z", loc)),
+    ];
+    prop_oneof![4 => num, 2 => boolean, 1 => ill_typed]
+}
+
+/// 評価器を参照実装と比べるための整数と有理数の式を生成する
+/// 正確に計算できるよう浮動小数点数は使わず、べき乗の指数は小さな整数のリテラルにする
+#[cfg(test)]
+pub(crate) fn arb_arith_ast() -> impl proptest::strategy::Strategy<Value = Ast> {
+    use self::BinOpKind::*;
+    use proptest::prelude::*;
+    const ARITH: &[BinOpKind] = &[Add, Sub, Mult, Div, Mod];
+
+    let leaf = prop_oneof![
+        6 => (0u64..10, arb_loc()).prop_map(|(n, loc)| Ast::num(n, loc)),
+        1 => (any::<u64>(), arb_loc()).prop_map(|(n, loc)| Ast::num(n, loc)),
+        1 => arb_loc().prop_map(|loc| Ast::var("x", loc)),
+    ];
+    let exp = prop_oneof![
+        (0u64..4, arb_loc()).prop_map(|(n, loc)| Ast::num(n, loc)),
+        (1u64..4, arb_loc(), arb_loc(), arb_loc()).prop_map(|(n, n_loc, op_loc, loc)| {
+            Ast::uniop(UniOp::minus(op_loc), Ast::num(n, n_loc), loc)
+        }),
+    ];
+    leaf.prop_recursive(5, 48, 2, move |e| {
+        prop_oneof![
+            4 => arb_binop(ARITH, e.clone(), e.clone()),
+            1 => arb_binop(&[Pow], e.clone(), exp.clone()),
+            1 => (e, arb_loc(), arb_loc()).prop_map(|(e, op_loc, loc)| Ast::uniop(
+                UniOp::minus(op_loc),
+                e,
+                loc
+            )),
+        ]
+    })
+}
@@ -0,0 +1,474 @@
+//! 電卓言語の言語サーバ。標準入出力でLSPのJSON-RPCをやり取りする
+//!
+//! - 字句エラーと構文エラーを診断として送る(textDocument/publishDiagnostics)
+//! - 変数や式にカーソルを合わせると値と型を表示する(textDocument/hover)
+//! - letとfnで定義した名前や関数の引数に移動する(textDocument/definition)
+//! - 整形器で文書を整形する(textDocument/formatting)
+
+use parser::{
+    format_source, Annot, Ast, AstFormatter, AstKind, Diagnostic, Error, Interpreter, Loc,
+    OperatorTable, Type, TypeChecker,
+};
+use serde_json::{json, Value as Json};
+use std::collections::HashMap;
+use std::io::{self, BufRead, Write};
+
+/// JSON-RPCのエラーコード
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+
+/// ヘッダと本体からなるメッセージを1つ読み、本体を返す。入力が終わったらNone
+fn read_message(input: &mut impl BufRead) -> io::Result<Option<Vec<u8>>> {
+    let mut len = None;
+    loop {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(None);
+        }
+        // ヘッダは空行で終わる。Content-Type などほかのヘッダは無視する
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some(value) = line.strip_prefix("Content-Length:") {
+            len = value.trim().parse().ok();
+        }
+    }
+    let len = len.ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "no Content-Length"))?;
+    let mut body = vec![0; len];
+    input.read_exact(&mut body)?;
+    Ok(Some(body))
+}
+
+fn write_message(out: &mut impl Write, msg: &Json) -> io::Result<()> {
+    let body = msg.to_string();
+    write!(out, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+    out.flush()
+}
+
+/// バイト位置をLSPの位置にする。行は0始まり、列はUTF-16の符号単位で数える
+fn position(src: &str, pos: usize) -> Json {
+    let mut pos = pos.min(src.len());
+    while !src.is_char_boundary(pos) {
+        pos -= 1;
+    }
+    let line_start = src[..pos].rfind('\n').map_or(0, |i| i + 1);
+    let line = src[..line_start].matches('\n').count();
+    let character = src[line_start..pos].encode_utf16().count();
+    json!({ "line": line, "character": character })
+}
+
+/// LSPの位置をバイト位置にする。行や列が範囲を越えていたら行末か入力の終わりに丸める
+fn offset(src: &str, pos: &Json) -> Option<usize> {
+    let line = pos["line"].as_u64()? as usize;
+    let character = pos["character"].as_u64()? as usize;
+    let line_start = match line {
+        0 => 0,
+        _ => src
+            .match_indices('\n')
+            .nth(line - 1)
+            .map_or(src.len(), |(i, _)| i + 1),
+    };
+    let text = src[line_start..].split('\n').next().unwrap_or("");
+    let mut units = 0;
+    for (i, c) in text.char_indices() {
+        if units >= character {
+            return Some(line_start + i);
+        }
+        units += c.len_utf16();
+    }
+    Some(line_start + text.len())
+}
+
+fn range(src: &str, loc: &Loc) -> Json {
+    json!({ "start": position(src, loc.0), "end": position(src, loc.1) })
+}
+
+#[test]
+fn test_position() {
+    let src = "let x = 1\n\"あ\" + 𝑥\n";
+    assert_eq!(position(src, 4), json!({ "line": 0, "character": 4 }));
+    // 全角文字は1単位、サロゲートペアになる文字は2単位
+    assert_eq!(position(src, 16), json!({ "line": 1, "character": 4 }));
+    assert_eq!(position(src, 22), json!({ "line": 1, "character": 8 }));
+    // 入力の終わりより後ろは終わりに丸める
+    assert_eq!(position(src, 100), json!({ "line": 2, "character": 0 }));
+
+    for pos in [0, 4, 10, 16, 18, 22, 23] {
+        assert_eq!(offset(src, &position(src, pos)), Some(pos));
+    }
+    assert_eq!(offset(src, &json!({ "line": 0, "character": 99 })), Some(9));
+    assert_eq!(offset(src, &json!({ "line": 9, "character": 0 })), Some(23));
+}
+
+/// 開いている文書。変更のたびに文ごとに解析し直す
+struct Document {
+    text: String,
+    stmts: Vec<(Loc, Result<Ast, Error>)>,
+}
+
+impl Document {
+    fn new(ops: &OperatorTable, text: String) -> Self {
+        let stmts = ops.parse_script(&text);
+        Document { text, stmts }
+    }
+
+    /// 位置を含む文の番号と構文木。構文エラーのある文はNone
+    fn stmt_at(&self, pos: usize) -> Option<(usize, &Ast)> {
+        let index = self
+            .stmts
+            .iter()
+            .position(|(span, _)| span.0 <= pos && pos <= span.1)?;
+        let ast = self.stmts[index].1.as_ref().ok()?;
+        Some((index, ast))
+    }
+
+    /// index番目より前の文を新しいものから順に返す
+    fn before(&self, index: usize) -> impl Iterator<Item = &Ast> {
+        self.stmts[..index]
+            .iter()
+            .rev()
+            .filter_map(|(_, result)| result.as_ref().ok())
+    }
+
+    /// 字句エラーと構文エラーをLSPの診断にする
+    fn diagnostics(&self, uri: &str) -> Vec<Json> {
+        let src = &self.text;
+        let to_lsp = |d: Diagnostic| {
+            let related: Vec<_> = d
+                .secondary
+                .iter()
+                .map(|label| {
+                    json!({
+                        "location": { "uri": uri, "range": range(src, &label.loc) },
+                        "message": label.message,
+                    })
+                })
+                .collect();
+            let message = std::iter::once(d.message)
+                .chain(d.notes.into_iter().map(|note| format!("note: {}", note)))
+                .collect::<Vec<_>>()
+                .join("\n");
+            json!({
+                "range": range(src, &d.primary.loc),
+                "severity": 1,
+                "code": d.code,
+                "source": "parser",
+                "message": message,
+                "relatedInformation": related,
+            })
+        };
+        self.stmts
+            .iter()
+            .filter_map(|(span, result)| Some(result.as_ref().err()?.diagnostics(&src[..span.1])))
+            .flatten()
+            .map(to_lsp)
+            .collect()
+    }
+}
+
+/// カーソルの位置にある名前か式
+enum Target<'a> {
+    /// 式。囲んでいる関数の引数を持つ
+    Expr(&'a Ast, &'a [Annot<String>]),
+    /// letで束縛する変数の名前と式
+    Let(&'a Annot<String>, &'a Ast),
+    /// 定義している関数の名前
+    Fn(&'a Annot<String>),
+    /// 関数の引数の名前
+    Param(&'a Annot<String>),
+    /// 呼び出している関数の名前
+    Call(&'a Annot<String>),
+}
+
+/// 位置にある最も内側の名前か式を探す。paramsは囲んでいる関数の引数
+fn target_at<'a>(ast: &'a Ast, pos: usize, params: &'a [Annot<String>]) -> Option<Target<'a>> {
+    use self::AstKind::*;
+    let inside = |loc: &Loc| loc.0 <= pos && pos < loc.1;
+    if !inside(&ast.loc) {
+        return None;
+    }
+    let child = match &ast.value {
+        Let { var, e } if inside(&var.loc) => return Some(Target::Let(var, e)),
+        Let { e, .. } => target_at(e, pos, params),
+        FnDef { name, .. } if inside(&name.loc) => return Some(Target::Fn(name)),
+        FnDef { params, body, .. } => match params.iter().find(|p| inside(&p.loc)) {
+            Some(param) => return Some(Target::Param(param)),
+            None => target_at(body, pos, params),
+        },
+        Call { name, .. } if inside(&name.loc) => return Some(Target::Call(name)),
+        Call { args, .. } => args.iter().find_map(|arg| target_at(arg, pos, params)),
+        If { cond, then, els } => [cond, then, els]
+            .iter()
+            .find_map(|e| target_at(e, pos, params)),
+        UniOp { e, .. } => target_at(e, pos, params),
+        BinOp { l, r, .. } => target_at(l, pos, params).or_else(|| target_at(r, pos, params)),
+        Num(_) | Float(_) | Bool(_) | Var(_) | Error => None,
+    };
+    // 子に当たらなければこの式そのもの。letとfnは文なので式としては扱わない
+    child.or(match ast.value {
+        Let { .. } | FnDef { .. } | Error => None,
+        _ => Some(Target::Expr(ast, params)),
+    })
+}
+
+struct Server {
+    ops: OperatorTable,
+    docs: HashMap<String, Document>,
+    /// shutdownを受け取ったかどうか
+    shutdown: bool,
+    /// exitを受け取ったら終了ステータスが入る
+    exit: Option<i32>,
+}
+
+impl Server {
+    fn new() -> Self {
+        Server {
+            ops: OperatorTable::default(),
+            docs: HashMap::new(),
+            shutdown: false,
+            exit: None,
+        }
+    }
+
+    /// 1つのメッセージを処理し、送り返すメッセージを返す
+    fn handle(&mut self, msg: &Json) -> Vec<Json> {
+        let method = msg["method"].as_str();
+        let params = &msg["params"];
+        match (msg.get("id"), method) {
+            // リクエスト
+            (Some(id), Some(method)) => {
+                let response = match self.request(method, params) {
+                    Ok(result) => json!({ "jsonrpc": "2.0", "id": id, "result": result }),
+                    Err((code, message)) => json!({
+                        "jsonrpc": "2.0",
+                        "id": id,
+                        "error": { "code": code, "message": message },
+                    }),
+                };
+                vec![response]
+            }
+            // 通知
+            (None, Some(method)) => self.notification(method, params),
+            // クライアントへのリクエストは送らないので、応答が来ることもない
+            (Some(_), None) if msg.get("result").is_some() || msg.get("error").is_some() => {
+                vec![]
+            }
+            _ => vec![json!({
+                "jsonrpc": "2.0",
+                "id": Json::Null,
+                "error": { "code": INVALID_REQUEST, "message": "invalid request" },
+            })],
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Json) -> Result<Json, (i64, String)> {
+        let invalid = || (INVALID_PARAMS, format!("invalid params for {}", method));
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // 変更のたびに文書全体を受け取る
+                    "textDocumentSync": 1,
+                    "hoverProvider": true,
+                    "definitionProvider": true,
+                    "documentFormattingProvider": true,
+                },
+                "serverInfo": { "name": "parser-lsp" },
+            })),
+            "shutdown" => {
+                self.shutdown = true;
+                Ok(Json::Null)
+            }
+            "textDocument/hover" => {
+                let (_, doc, pos) = self.document_position(params).ok_or_else(invalid)?;
+                Ok(self.hover(doc, pos).unwrap_or(Json::Null))
+            }
+            "textDocument/definition" => {
+                let (uri, doc, pos) = self.document_position(params).ok_or_else(invalid)?;
+                Ok(definition(doc, pos).map_or(
+                    Json::Null,
+                    |loc| json!({ "uri": uri, "range": range(&doc.text, &loc) }),
+                ))
+            }
+            "textDocument/formatting" => {
+                let uri = params["textDocument"]["uri"].as_str().ok_or_else(invalid)?;
+                let doc = self.docs.get(uri).ok_or_else(invalid)?;
+                // 構文エラーのある文書は整形しない
+                Ok(match format_source(&self.ops, &doc.text) {
+                    Ok(text) if text == doc.text => json!([]),
+                    Ok(text) => json!([{
+                        "range": range(&doc.text, &Loc(0, doc.text.len())),
+                        "newText": text,
+                    }]),
+                    Err(_) => Json::Null,
+                })
+            }
+            _ => Err((METHOD_NOT_FOUND, format!("unknown method {}", method))),
+        }
+    }
+
+    fn notification(&mut self, method: &str, params: &Json) -> Vec<Json> {
+        let uri = match params["textDocument"]["uri"].as_str() {
+            Some(uri) => uri.to_string(),
+            None => {
+                if method == "exit" {
+                    self.exit = Some(if self.shutdown { 0 } else { 1 });
+                }
+                return vec![];
+            }
+        };
+        let text = match method {
+            "textDocument/didOpen" => params["textDocument"]["text"].as_str(),
+            // 全体を送ってもらうので最後の変更だけを見ればよい
+            "textDocument/didChange" => params["contentChanges"]
+                .as_array()
+                .and_then(|changes| changes.last())
+                .and_then(|change| change["text"].as_str()),
+            "textDocument/didClose" => {
+                self.docs.remove(&uri);
+                return vec![publish_diagnostics(&uri, vec![])];
+            }
+            _ => None,
+        };
+        match text {
+            Some(text) => {
+                let doc = Document::new(&self.ops, text.to_string());
+                let diagnostics = doc.diagnostics(&uri);
+                self.docs.insert(uri.clone(), doc);
+                vec![publish_diagnostics(&uri, diagnostics)]
+            }
+            None => vec![],
+        }
+    }
+
+    /// リクエストの文書と位置
+    fn document_position<'a>(&'a self, params: &'a Json) -> Option<(&'a str, &'a Document, usize)> {
+        let uri = params["textDocument"]["uri"].as_str()?;
+        let doc = self.docs.get(uri)?;
+        let pos = offset(&doc.text, &params["position"])?;
+        Some((uri, doc, pos))
+    }
+
+    /// 位置にある変数や式の値と型。前の文を順に実行した環境で評価する
+    fn hover(&self, doc: &Document, pos: usize) -> Option<Json> {
+        let (index, stmt) = doc.stmt_at(pos)?;
+        let target = target_at(stmt, pos, &[])?;
+        let mut interp = Interpreter::new();
+        let mut checker = TypeChecker::new();
+        // 関数の名前は定義している文の中でも使える
+        let fn_def = match stmt.value {
+            AstKind::FnDef { .. } => Some(stmt),
+            _ => None,
+        };
+        let prev: Vec<_> = doc.before(index).collect();
+        for stmt in prev.into_iter().rev().chain(fn_def) {
+            // 型エラーのある文は実行しない。実行時エラーは無視して次の文に進む
+            if checker.check(stmt).is_ok() {
+                let _ = interp.exec(stmt);
+            }
+        }
+        let mut describe = |name: String, expr: &Ast| {
+            let ty = checker.type_of(expr).ok()?;
+            Some(match interp.eval(expr) {
+                Ok(value) => format!("{}: {} = {}", name, ty, value),
+                Err(_) => format!("{}: {}", name, ty),
+            })
+        };
+        let (text, loc) = match target {
+            Target::Expr(expr, _) => {
+                let text = AstFormatter::new(&self.ops).format(expr);
+                (describe(text, expr)?, &expr.loc)
+            }
+            Target::Let(var, expr) => (describe(var.value.clone(), expr)?, &var.loc),
+            Target::Fn(name) | Target::Call(name) => {
+                let (_, params) = interp.functions().find(|(f, _)| *f == name.value)?;
+                let ty = checker.return_type(&name.value).unwrap_or(&Type::Any);
+                let signature = format!("fn {}({}) -> {}", name.value, params.join(", "), ty);
+                (signature, &name.loc)
+            }
+            Target::Param(_) => return None,
+        };
+        Some(json!({
+            "contents": { "kind": "plaintext", "value": text },
+            "range": range(&doc.text, loc),
+        }))
+    }
+}
+
+/// 位置にある名前を定義している場所
+fn definition(doc: &Document, pos: usize) -> Option<Loc> {
+    let (index, stmt) = doc.stmt_at(pos)?;
+    match target_at(stmt, pos, &[])? {
+        Target::Let(name, _) | Target::Fn(name) | Target::Param(name) => Some(name.loc.clone()),
+        Target::Expr(
+            Ast {
+                value: AstKind::Var(var),
+                ..
+            },
+            params,
+        ) => match params.iter().find(|p| p.value == *var) {
+            Some(param) => Some(param.loc.clone()),
+            // 変数は前の文で束縛したもののうち最後のもの
+            None => doc.before(index).find_map(|prev| match &prev.value {
+                AstKind::Let { var: name, .. } if name.value == *var => Some(name.loc.clone()),
+                _ => None,
+            }),
+        },
+        Target::Expr(..) => None,
+        // 関数は再帰できるので定義している文の中でも探す
+        Target::Call(call) => std::iter::once(stmt)
+            .chain(doc.before(index))
+            .find_map(|prev| match &prev.value {
+                AstKind::FnDef { name, .. } if name.value == call.value => Some(name.loc.clone()),
+                _ => None,
+            }),
+    }
+}
+
+fn publish_diagnostics(uri: &str, diagnostics: Vec<Json>) -> Json {
+    json!({
+        "jsonrpc": "2.0",
+        "method": "textDocument/publishDiagnostics",
+        "params": { "uri": uri, "diagnostics": diagnostics },
+    })
+}
+
+fn main() {
+    let stdin = io::stdin();
+    let mut input = stdin.lock();
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut server = Server::new();
+    let code = loop {
+        let body = match read_message(&mut input) {
+            Ok(Some(body)) => body,
+            // exitを受け取らずに入力が終わったのは異常終了
+            Ok(None) => break 1,
+            Err(e) => {
+                eprintln!("parser-lsp: {}", e);
+                break 1;
+            }
+        };
+        let replies = match serde_json::from_slice(&body) {
+            Ok(msg) => server.handle(&msg),
+            Err(e) => vec![json!({
+                "jsonrpc": "2.0",
+                "id": Json::Null,
+                "error": { "code": PARSE_ERROR, "message": e.to_string() },
+            })],
+        };
+        if let Err(e) = replies
+            .iter()
+            .try_for_each(|msg| write_message(&mut out, msg))
+        {
+            eprintln!("parser-lsp: {}", e);
+            break 1;
+        }
+        if let Some(code) = server.exit {
+            break code;
+        }
+    };
+    std::process::exit(code);
+}
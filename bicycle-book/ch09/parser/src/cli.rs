@@ -0,0 +1,481 @@
+//! `parser`コマンドの引数を解釈し、スクリプトの処理と整形を行う
+
+use crate::repl::{self, Mode, ReplOptions};
+use parser::compile::{DotEmitter, LatexEmitter, RpnCompiler, SexprEmitter, Vm};
+#[cfg(test)]
+use parser::Ast;
+use parser::{
+    format_source, Diagnostic, Emitter, ErrorFormat, Interpreter, OperatorTable, Optimizer,
+    SourceMap, TypeChecker, TypeError,
+};
+use std::fmt;
+use std::io;
+use std::io::Write;
+
+const USAGE: &str = "\
+usage: parser (check|rpn|run) [--emit=ast|sexpr|latex|dot|rpn|value] [--bigint] [--vm] [--error-format=json] [file]
+       parser [--rpn|--wat|--from-rpn] [--bigint] [--vm] [--error-format=json]
+       parser --fmt [--error-format=json] [file...]";
+
+/// ファイルを整形して書き戻す。ファイルがなければ標準入力を整形して標準出力に書く
+/// エラーがあったファイルは書き換えない。すべて成功したらtrueを返す
+fn run_fmt(ops: &OperatorTable, files: &[String], format: ErrorFormat) -> bool {
+    use std::io::Read;
+    let show_errors = |path: &str, src: &str, diagnostics: Vec<Diagnostic>| {
+        format.emit(&SourceMap::new(path, src), &diagnostics)
+    };
+    if files.is_empty() {
+        let mut src = String::new();
+        if let Err(e) = io::stdin().read_to_string(&mut src) {
+            eprintln!("<stdin>: {}", e);
+            return false;
+        }
+        return match format_source(ops, &src) {
+            Ok(out) => {
+                print!("{}", out);
+                true
+            }
+            Err(errors) => {
+                show_errors("<stdin>", &src, errors);
+                false
+            }
+        };
+    }
+    let mut ok = true;
+    for path in files {
+        let result = std::fs::read_to_string(path).and_then(|src| {
+            match format_source(ops, &src) {
+                // 変わらないファイルは触らない
+                Ok(out) if out == src => Ok(true),
+                Ok(out) => std::fs::write(path, out).map(|_| true),
+                Err(errors) => {
+                    show_errors(path, &src, errors);
+                    Ok(false)
+                }
+            }
+        });
+        match result {
+            Ok(formatted) => ok &= formatted,
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                ok = false;
+            }
+        }
+    }
+    ok
+}
+
+/// サブコマンド。ファイルの文をすべて読んでから処理する
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Command {
+    /// 構文解析だけを行う
+    Check,
+    /// 逆ポーランド記法にコンパイルする
+    Rpn,
+    /// 評価する
+    Run,
+}
+
+/// --emitで選べる出力。サブコマンドが進んだ段階までしか出力できない
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum Emit {
+    Ast,
+    Sexpr,
+    Latex,
+    Dot,
+    Rpn,
+    Value,
+}
+
+impl Emit {
+    /// この出力に必要な段階。構文木から直接書き出すものは構文解析まででよい
+    fn stage(self) -> Emit {
+        match self {
+            Emit::Sexpr | Emit::Latex | Emit::Dot => Emit::Ast,
+            _ => self,
+        }
+    }
+
+    /// 構文木から直接書き出す出力なら、その書き出し器を返す
+    fn emitter(self, ops: &OperatorTable) -> Option<Box<dyn Emitter + '_>> {
+        match self {
+            Emit::Sexpr => Some(Box::new(SexprEmitter::new())),
+            Emit::Latex => Some(Box::new(LatexEmitter::new(ops))),
+            Emit::Dot => Some(Box::new(DotEmitter::new())),
+            Emit::Ast | Emit::Rpn | Emit::Value => None,
+        }
+    }
+}
+
+impl Command {
+    /// --emitを指定しなかったときの出力。checkは何も出力しない
+    fn default_emit(self) -> Option<Emit> {
+        match self {
+            Command::Check => None,
+            Command::Rpn => Some(Emit::Rpn),
+            Command::Run => Some(Emit::Value),
+        }
+    }
+
+    /// このサブコマンドが出力できる最も後の段階
+    fn max_emit(self) -> Emit {
+        match self {
+            Command::Check => Emit::Ast,
+            Command::Rpn => Emit::Rpn,
+            Command::Run => Emit::Value,
+        }
+    }
+}
+
+/// `parser <command> [options] [file]`のコマンドライン
+#[derive(Debug, Clone, PartialEq)]
+struct CliOptions {
+    cmd: Command,
+    emit: Option<Emit>,
+    bigint: bool,
+    vm: bool,
+    format: ErrorFormat,
+    /// Noneまたは"-"なら標準入力から読む
+    file: Option<String>,
+}
+
+impl CliOptions {
+    /// サブコマンドで始まらなければOk(None)を返す
+    fn parse(args: &[String]) -> Result<Option<Self>, String> {
+        let cmd = match args.first().map(String::as_str) {
+            Some("check") => Command::Check,
+            Some("rpn") => Command::Rpn,
+            Some("run") => Command::Run,
+            _ => return Ok(None),
+        };
+        let mut opts = CliOptions {
+            cmd,
+            emit: cmd.default_emit(),
+            bigint: false,
+            vm: false,
+            format: ErrorFormat::Human,
+            file: None,
+        };
+        for arg in &args[1..] {
+            match arg.as_str() {
+                "--bigint" => opts.bigint = true,
+                "--vm" => opts.vm = true,
+                "--error-format=json" => opts.format = ErrorFormat::Json,
+                "--error-format=human" => opts.format = ErrorFormat::Human,
+                "--emit=ast" => opts.emit = Some(Emit::Ast),
+                "--emit=sexpr" => opts.emit = Some(Emit::Sexpr),
+                "--emit=latex" => opts.emit = Some(Emit::Latex),
+                "--emit=dot" => opts.emit = Some(Emit::Dot),
+                "--emit=rpn" => opts.emit = Some(Emit::Rpn),
+                "--emit=value" => opts.emit = Some(Emit::Value),
+                _ if arg.starts_with("--emit=") => {
+                    return Err(format!("unknown emit kind `{}`", &arg[7..]))
+                }
+                _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+                _ if opts.file.is_some() => return Err(format!("unexpected argument `{}`", arg)),
+                _ => opts.file = Some(arg.clone()),
+            }
+        }
+        if let Some(emit) = opts.emit {
+            if emit.stage() > cmd.max_emit() {
+                return Err(format!("`{:?}` cannot emit {:?}", cmd, emit).to_lowercase());
+            }
+        }
+        Ok(Some(opts))
+    }
+}
+
+#[test]
+fn test_cli_options() {
+    let parse = |args: &[&str]| {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        CliOptions::parse(&args)
+    };
+    assert_eq!(parse(&["--rpn"]), Ok(None));
+    assert_eq!(
+        parse(&["run", "--vm", "--error-format=json", "a.calc"]),
+        Ok(Some(CliOptions {
+            cmd: Command::Run,
+            emit: Some(Emit::Value),
+            bigint: false,
+            vm: true,
+            format: ErrorFormat::Json,
+            file: Some("a.calc".to_string()),
+        }))
+    );
+    let check = parse(&["check", "--emit=ast"]).unwrap().unwrap();
+    assert_eq!((check.cmd, check.emit), (Command::Check, Some(Emit::Ast)));
+    assert!(parse(&["check", "--emit=value"]).is_err());
+    assert!(parse(&["rpn", "--emit=sexp"]).is_err());
+    let check = parse(&["check", "--emit=dot"]).unwrap().unwrap();
+    assert_eq!(check.emit, Some(Emit::Dot));
+    assert!(parse(&["run", "--rpn"]).is_err());
+    assert!(parse(&["run", "a", "b"]).is_err());
+}
+
+/// スクリプトを処理してoutに結果を書く。失敗したら診断を返す
+/// 構文エラーはすべての文について報告し、型や実行時のエラーは最初の1つで止まる
+fn run_script(
+    opts: &CliOptions,
+    ops: &OperatorTable,
+    src: &str,
+    out: &mut dyn Write,
+) -> Result<(), Vec<Diagnostic>> {
+    let mut stmts = Vec::new();
+    let mut diagnostics = Vec::new();
+    for (span, result) in ops.parse_script(src) {
+        match result {
+            Ok(ast) => stmts.push(ast),
+            Err(e) => diagnostics.extend(e.diagnostics(&src[..span.1])),
+        }
+    }
+    if !diagnostics.is_empty() {
+        return Err(diagnostics);
+    }
+    // 出力先に書けなくなったら(パイプの相手が終了したなど)黙って止める
+    let mut write = |line: &dyn fmt::Display| writeln!(out, "{}", line).is_ok();
+    match opts.emit {
+        Some(Emit::Ast) => {
+            for ast in &stmts {
+                if !write(&format_args!("{:?}", ast)) {
+                    break;
+                }
+            }
+        }
+        Some(emit @ Emit::Sexpr) | Some(emit @ Emit::Latex) | Some(emit @ Emit::Dot) => {
+            let mut emitter = emit.emitter(ops).unwrap();
+            for ast in &stmts {
+                if !write(&emitter.emit(ast)) {
+                    break;
+                }
+            }
+        }
+        Some(Emit::Rpn) => {
+            let mut compiler = RpnCompiler::new();
+            let mut optimizer = Optimizer::new();
+            for ast in &stmts {
+                if !write(&compiler.compile(&optimizer.optimize(ast))) {
+                    break;
+                }
+            }
+        }
+        Some(Emit::Value) => {
+            let mut checker = TypeChecker::new();
+            let mut interp = if opts.bigint {
+                Interpreter::with_bigint()
+            } else {
+                Interpreter::new()
+            };
+            let mut vm = if opts.bigint {
+                Vm::with_bigint()
+            } else {
+                Vm::new()
+            };
+            for ast in &stmts {
+                checker.check(ast).map_err(|errors| {
+                    errors.iter().map(TypeError::diagnostic).collect::<Vec<_>>()
+                })?;
+                let ret = if opts.vm {
+                    vm.exec(ast)
+                } else {
+                    interp.exec(ast)
+                };
+                match ret.map_err(|e| vec![e.diagnostic()])? {
+                    Some(n) if !write(&n) => break,
+                    _ => (),
+                }
+            }
+        }
+        None => (),
+    }
+    Ok(())
+}
+
+#[test]
+fn test_run_script() {
+    let ops = OperatorTable::default();
+    let run = |cmd, emit, src: &str| {
+        let opts = CliOptions {
+            cmd,
+            emit,
+            bigint: false,
+            vm: false,
+            format: ErrorFormat::Human,
+            file: None,
+        };
+        let mut out = Vec::new();
+        run_script(&opts, &ops, src, &mut out)
+            .map(|()| String::from_utf8(out).unwrap())
+            .map_err(|ds| ds.iter().map(|d| d.code).collect::<Vec<_>>())
+    };
+    let src = "let x = 2\nfn sq(a) = a * a\nsq(x) + 1; x\n";
+    assert_eq!(
+        run(Command::Run, Some(Emit::Value), src),
+        Ok("2\n5\n2\n".to_string())
+    );
+    assert_eq!(
+        run(Command::Rpn, Some(Emit::Rpn), "1 + 2 * x\n-(3)"),
+        Ok("1 2 x * +\n3 u-\n".to_string())
+    );
+    assert_eq!(run(Command::Check, None, src), Ok(String::new()));
+    assert_eq!(
+        run(Command::Run, Some(Emit::Sexpr), src),
+        Ok("(let x 2)\n(fn sq (a) (* a a))\n(+ (sq x) 1)\nx\n".to_string())
+    );
+    assert_eq!(
+        run(Command::Check, Some(Emit::Ast), "y"),
+        Ok(format!("{:?}\n", Ast::var("y", parser::Loc(0, 1))))
+    );
+    // 構文エラーはすべて、実行時エラーは最初の1つだけ報告する
+    assert_eq!(
+        run(Command::Run, Some(Emit::Value), "1 +\n)\n2"),
+        Err(vec!["E0106", "E0102", "E0105"])
+    );
+    assert_eq!(
+        run(Command::Run, Some(Emit::Value), "1\n1 / 0\ny"),
+        Err(vec!["E0301"])
+    );
+}
+
+/// サブコマンドを実行して終了ステータスを返す
+/// 0は成功、1はスクリプトのエラー、2は使い方か入出力のエラー
+fn run_command(opts: &CliOptions) -> i32 {
+    use std::io::Read;
+    let (name, src) = match opts.file.as_deref() {
+        None | Some("-") => {
+            let mut src = String::new();
+            if let Err(e) = io::stdin().read_to_string(&mut src) {
+                eprintln!("<stdin>: {}", e);
+                return 2;
+            }
+            ("<stdin>", src)
+        }
+        Some(path) => match std::fs::read_to_string(path) {
+            Ok(src) => (path, src),
+            Err(e) => {
+                eprintln!("{}: {}", path, e);
+                return 2;
+            }
+        },
+    };
+    let stdout = io::stdout();
+    let mut out = io::BufWriter::new(stdout.lock());
+    let result = run_script(opts, &OperatorTable::default(), &src, &mut out);
+    // 診断より先に、それまでの結果を出しておく
+    let _ = out.flush();
+    match result {
+        Ok(()) => 0,
+        Err(diagnostics) => {
+            opts.format.emit(&SourceMap::new(name, &src), &diagnostics);
+            1
+        }
+    }
+}
+
+/// コマンドラインで選んだ動作
+#[derive(Debug, Clone, PartialEq)]
+enum Cli {
+    /// check/rpn/runでスクリプトを処理する
+    Script(CliOptions),
+    /// --fmtでファイルを整形する
+    Fmt {
+        files: Vec<String>,
+        format: ErrorFormat,
+    },
+    /// REPLを起動する
+    Repl(ReplOptions),
+}
+
+impl Cli {
+    /// 知らないオプションや余計な引数は使い方の誤りとして返す
+    fn parse(args: &[String]) -> Result<Self, String> {
+        if let Some(opts) = CliOptions::parse(args)? {
+            return Ok(Cli::Script(opts));
+        }
+        let mut opts = ReplOptions {
+            mode: Mode::Eval,
+            bigint: false,
+            vm: false,
+            format: ErrorFormat::Human,
+        };
+        let mut fmt = false;
+        let mut files = Vec::new();
+        for arg in args {
+            match arg.as_str() {
+                // --bigintが指定されたらi64に収まらない結果も多倍長で正確に計算する
+                "--bigint" => opts.bigint = true,
+                // --vmが指定されたらバイトコードにコンパイルしてスタックマシンで実行する
+                "--vm" => opts.vm = true,
+                // --error-format=jsonが指定されたら診断を1行ずつJSONで出力する
+                "--error-format=json" => opts.format = ErrorFormat::Json,
+                "--error-format=human" => opts.format = ErrorFormat::Human,
+                // --rpn、--wat、--from-rpnは最初のモードを選ぶ。REPLの中では:modeで切り替える
+                "--rpn" => opts.mode = Mode::Rpn,
+                "--wat" => opts.mode = Mode::Wat,
+                "--from-rpn" => opts.mode = Mode::FromRpn,
+                // --fmtが指定されたら引数のファイルを整形し直して終わる
+                "--fmt" => fmt = true,
+                _ if arg.starts_with("--") => return Err(format!("unknown option `{}`", arg)),
+                _ => files.push(arg.clone()),
+            }
+        }
+        if fmt {
+            return Ok(Cli::Fmt {
+                files,
+                format: opts.format,
+            });
+        }
+        match files.first() {
+            Some(arg) => Err(format!("unexpected argument `{}`", arg)),
+            None => Ok(Cli::Repl(opts)),
+        }
+    }
+}
+
+#[test]
+fn test_cli() {
+    let parse = |args: &[&str]| {
+        let args: Vec<String> = args.iter().map(|s| s.to_string()).collect();
+        Cli::parse(&args)
+    };
+    assert_eq!(
+        parse(&["--rpn", "--bigint"]),
+        Ok(Cli::Repl(ReplOptions {
+            mode: Mode::Rpn,
+            bigint: true,
+            vm: false,
+            format: ErrorFormat::Human,
+        }))
+    );
+    assert_eq!(
+        parse(&["--fmt", "--error-format=json", "a.calc", "b.calc"]),
+        Ok(Cli::Fmt {
+            files: vec!["a.calc".to_string(), "b.calc".to_string()],
+            format: ErrorFormat::Json,
+        })
+    );
+    assert!(matches!(parse(&["run", "--vm"]), Ok(Cli::Script(_))));
+    // 綴りを誤ったオプションは黙って無視しない
+    assert!(parse(&["--bigit"]).is_err());
+    assert!(parse(&["--vm", "a.calc"]).is_err());
+}
+
+/// コマンドラインを解釈して実行し、終了ステータスを返す
+pub fn run(args: &[String]) -> i32 {
+    match Cli::parse(args) {
+        Ok(Cli::Script(opts)) => run_command(&opts),
+        Ok(Cli::Fmt { files, format }) => {
+            if run_fmt(&OperatorTable::default(), &files, format) {
+                0
+            } else {
+                1
+            }
+        }
+        Ok(Cli::Repl(opts)) => repl::run(&opts),
+        Err(msg) => {
+            eprintln!("error: {}", msg);
+            eprintln!("{}", USAGE);
+            2
+        }
+    }
+}
@@ -0,0 +1,433 @@
+use crate::ast::{ensure_stack, Ast, AstKind, BinOp, BinOpKind, UniOpKind};
+use crate::compile::Emitter;
+#[cfg(test)]
+use crate::compile::RpnCompiler;
+use crate::format::AstFormatter;
+use crate::parser::{Assoc, OperatorTable};
+
+/// デバッグ用にS式を書き出す。`1 + -x`は`(+ 1 (- x))`になる
+pub struct SexprEmitter;
+
+impl Default for SexprEmitter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SexprEmitter {
+    pub fn new() -> Self {
+        SexprEmitter
+    }
+
+    fn emit_inner(&mut self, expr: &Ast, buf: &mut String) {
+        ensure_stack(|| self.emit_node(expr, buf))
+    }
+
+    fn emit_node(&mut self, expr: &Ast, buf: &mut String) {
+        use self::AstKind::*;
+        let mut list = |head: &str, items: &[&Ast], buf: &mut String| {
+            buf.push('(');
+            buf.push_str(head);
+            for item in items {
+                buf.push(' ');
+                self.emit_inner(item, buf);
+            }
+            buf.push(')');
+        };
+        match expr.value {
+            Error => buf.push_str("<error>"),
+            Num(n) => buf.push_str(&n.to_string()),
+            Float(f) => buf.push_str(&format!("{:?}", f)),
+            Bool(b) => buf.push_str(&b.to_string()),
+            Var(ref name) => buf.push_str(name),
+            Let { ref var, ref e } => list(&format!("let {}", var.value), &[e], buf),
+            FnDef {
+                ref name,
+                ref params,
+                ref body,
+            } => {
+                let params: Vec<_> = params.iter().map(|p| p.value.as_str()).collect();
+                let head = format!("fn {} ({})", name.value, params.join(" "));
+                list(&head, &[body], buf)
+            }
+            Call { ref name, ref args } => {
+                let args: Vec<_> = args.iter().collect();
+                list(&name.value, &args, buf)
+            }
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => list("if", &[cond, then, els], buf),
+            // 単項と二項の-は要素の数で区別できる
+            UniOp { ref op, ref e } => list(op.value.symbol(), &[e], buf),
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => list(op.value.symbol(), &[l, r], buf),
+        }
+    }
+}
+
+impl Emitter for SexprEmitter {
+    fn emit(&mut self, stmt: &Ast) -> String {
+        let mut buf = String::new();
+        self.emit_inner(stmt, &mut buf);
+        buf
+    }
+}
+
+/// ドキュメント用にLaTeXの数式を書き出す
+/// 除算は`\frac`、べき乗は上付きにし、括弧は演算子表の結合力から必要なものだけを付ける
+pub struct LatexEmitter<'a> {
+    fmt: AstFormatter<'a>,
+}
+
+/// 書き出したLaTeXと、それを被演算子として置くときの結合力
+pub(crate) struct Latex {
+    text: String,
+    /// 括弧や`\frac`のようにそれだけでまとまる式はu8::MAX
+    bp: u8,
+}
+
+impl Latex {
+    fn atom(text: String) -> Self {
+        Latex { text, bp: u8::MAX }
+    }
+
+    fn paren(self) -> Self {
+        Self::atom(format!("\\left({}\\right)", self.text))
+    }
+}
+
+impl<'a> LatexEmitter<'a> {
+    pub fn new(ops: &'a OperatorTable) -> Self {
+        LatexEmitter {
+            fmt: AstFormatter::new(ops),
+        }
+    }
+
+    fn emit_expr(&mut self, expr: &Ast) -> Latex {
+        ensure_stack(|| self.emit_expr_inner(expr))
+    }
+
+    fn emit_expr_inner(&mut self, expr: &Ast) -> Latex {
+        use self::AstKind::*;
+        match expr.value {
+            Error => Latex::atom("\\langle\\mathrm{error}\\rangle".to_string()),
+            Num(n) => Latex::atom(n.to_string()),
+            Float(f) => Latex::atom(format!("{:?}", f)),
+            Bool(b) => Latex::atom(format!("\\mathrm{{{}}}", b)),
+            Var(ref name) => Latex::atom(latex_ident(name)),
+            Let { ref var, ref e } => Latex {
+                text: format!("{} = {}", latex_ident(&var.value), self.emit_expr(e).text),
+                bp: 0,
+            },
+            FnDef {
+                ref name,
+                ref params,
+                ref body,
+            } => {
+                let params: Vec<_> = params.iter().map(|p| latex_ident(&p.value)).collect();
+                Latex {
+                    text: format!(
+                        "\\operatorname{{{}}}\\left({}\\right) = {}",
+                        latex_escape(&name.value),
+                        params.join(", "),
+                        self.emit_expr(body).text
+                    ),
+                    bp: 0,
+                }
+            }
+            Call { ref name, ref args } => {
+                let args: Vec<_> = args.iter().map(|a| self.emit_expr(a).text).collect();
+                match (name.value.as_str(), &args[..]) {
+                    ("sqrt", [u]) => Latex::atom(format!("\\sqrt{{{}}}", u)),
+                    ("abs", [u]) => Latex::atom(format!("\\left|{}\\right|", u)),
+                    ("min", _) | ("max", _) => Latex::atom(format!(
+                        "\\{}\\left({}\\right)",
+                        name.value,
+                        args.join(", ")
+                    )),
+                    _ => Latex::atom(format!(
+                        "\\operatorname{{{}}}\\left({}\\right)",
+                        latex_escape(&name.value),
+                        args.join(", ")
+                    )),
+                }
+            }
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => Latex::atom(format!(
+                "\\begin{{cases}} {} & \\text{{if }} {} \\\\ {} & \\text{{otherwise}} \\end{{cases}}",
+                self.emit_expr(then).text,
+                self.emit_expr(cond).text,
+                self.emit_expr(els).text
+            )),
+            UniOp { ref op, ref e } => {
+                let bp = self.fmt.prefix_bp(&op.value);
+                let mut e = self.emit_expr(e);
+                if e.bp < bp {
+                    e = e.paren();
+                }
+                let symbol = match op.value {
+                    UniOpKind::Plus => "+",
+                    UniOpKind::Minus => "-",
+                    UniOpKind::Not => "\\lnot ",
+                };
+                Latex {
+                    text: format!("{}{}", symbol, e.text),
+                    bp,
+                }
+            }
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => self.emit_binop(op, l, r),
+        }
+    }
+
+    fn emit_binop(&mut self, op: &BinOp, l: &Ast, r: &Ast) -> Latex {
+        use self::BinOpKind::*;
+        let (bp, assoc) = self.fmt.infix_bp(&op.value);
+        match op.value {
+            // 分数の線が括弧の代わりになる
+            Div => {
+                let (l, r) = (self.emit_expr(l), self.emit_expr(r));
+                return Latex::atom(format!("\\frac{{{}}}{{{}}}", l.text, r.text));
+            }
+            // 指数は上付きになるので括弧は要らない。底はリテラルや変数でなければ囲む
+            Pow => {
+                let mut base = self.emit_expr(l);
+                let simple = matches!(
+                    l.value,
+                    AstKind::Num(_) | AstKind::Var(_) | AstKind::Call { .. }
+                );
+                if !simple {
+                    base = base.paren();
+                }
+                return Latex {
+                    text: format!("{}^{{{}}}", base.text, self.emit_expr(r).text),
+                    bp,
+                };
+            }
+            _ => (),
+        }
+        let symbol = match op.value {
+            Add => "+",
+            Sub => "-",
+            Mult => "\\cdot",
+            Mod => "\\bmod",
+            Eq => "=",
+            Ne => "\\neq",
+            Lt => "<",
+            Le => "\\leq",
+            Gt => ">",
+            Ge => "\\geq",
+            And => "\\land",
+            Or => "\\lor",
+            Div | Pow => unreachable!(),
+        };
+        let mut l = self.emit_expr(l);
+        if l.bp < bp || (l.bp == bp && assoc == Assoc::Right) {
+            l = l.paren();
+        }
+        let mut r = self.emit_expr(r);
+        if r.bp < bp || (r.bp == bp && assoc == Assoc::Left) {
+            r = r.paren();
+        }
+        Latex {
+            text: format!("{} {} {}", l.text, symbol, r.text),
+            bp,
+        }
+    }
+}
+
+impl<'a> Emitter for LatexEmitter<'a> {
+    fn emit(&mut self, stmt: &Ast) -> String {
+        self.emit_expr(stmt).text
+    }
+}
+
+/// LaTeXで特別な意味を持つ`_`をエスケープする。識別子は英数字と`_`だけでできている
+pub(crate) fn latex_escape(name: &str) -> String {
+    name.replace('_', "\\_")
+}
+
+/// 1文字の変数はそのまま、2文字以上は1つの名前として斜体にする
+pub(crate) fn latex_ident(name: &str) -> String {
+    if name.chars().count() == 1 {
+        name.to_string()
+    } else {
+        format!("\\mathit{{{}}}", latex_escape(name))
+    }
+}
+
+/// 木の形を見るためにGraphvizのDOT形式で書き出す。文ごとに1つのグラフになる
+pub struct DotEmitter {
+    /// 書き出しているグラフのノードの数
+    nodes: usize,
+}
+
+impl Default for DotEmitter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl DotEmitter {
+    pub fn new() -> Self {
+        DotEmitter { nodes: 0 }
+    }
+
+    /// exprのノードと子への辺をbufに書き、ノードの名前を返す
+    fn emit_node(&mut self, expr: &Ast, buf: &mut String) -> String {
+        ensure_stack(|| self.emit_node_inner(expr, buf))
+    }
+
+    fn emit_node_inner(&mut self, expr: &Ast, buf: &mut String) -> String {
+        use self::AstKind::*;
+        let id = format!("n{}", self.nodes);
+        self.nodes += 1;
+        let (label, children): (String, Vec<(&str, &Ast)>) = match expr.value {
+            Error => ("<error>".to_string(), vec![]),
+            Num(n) => (n.to_string(), vec![]),
+            Float(f) => (format!("{:?}", f), vec![]),
+            Bool(b) => (b.to_string(), vec![]),
+            Var(ref name) => (name.clone(), vec![]),
+            Let { ref var, ref e } => (format!("let {}", var.value), vec![("", e)]),
+            FnDef {
+                ref name,
+                ref params,
+                ref body,
+            } => {
+                let params: Vec<_> = params.iter().map(|p| p.value.as_str()).collect();
+                let label = format!("fn {}({})", name.value, params.join(", "));
+                (label, vec![("", body)])
+            }
+            Call { ref name, ref args } => (
+                format!("{}()", name.value),
+                args.iter().map(|a| ("", a)).collect(),
+            ),
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => (
+                "if".to_string(),
+                vec![("cond", cond), ("then", then), ("else", els)],
+            ),
+            UniOp { ref op, ref e } => (op.value.symbol().to_string(), vec![("", e)]),
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => (op.value.symbol().to_string(), vec![("", l), ("", r)]),
+        };
+        buf.push_str(&format!("  {} [label={}];\n", id, dot_string(&label)));
+        for (edge, child) in children {
+            let child = self.emit_node(child, buf);
+            if edge.is_empty() {
+                buf.push_str(&format!("  {} -> {};\n", id, child));
+            } else {
+                buf.push_str(&format!(
+                    "  {} -> {} [label={}];\n",
+                    id,
+                    child,
+                    dot_string(edge)
+                ));
+            }
+        }
+        id
+    }
+}
+
+impl Emitter for DotEmitter {
+    fn emit(&mut self, stmt: &Ast) -> String {
+        self.nodes = 0;
+        let mut buf = String::from("digraph {\n  node [shape=box];\n");
+        self.emit_node(stmt, &mut buf);
+        buf.push('}');
+        buf
+    }
+}
+
+/// DOTの引用符付き文字列にする
+pub(crate) fn dot_string(s: &str) -> String {
+    format!("\"{}\"", s.replace('\\', "\\\\").replace('"', "\\\""))
+}
+
+#[test]
+fn test_emitters() {
+    let ops = OperatorTable::default();
+    let emit = |e: &mut dyn Emitter, s: &str| e.emit(&ops.parse(s).unwrap());
+    let sexpr = |s| emit(&mut SexprEmitter::new(), s);
+    assert_eq!(sexpr("1 + 2 * -x"), "(+ 1 (* 2 (- x)))");
+    assert_eq!(sexpr("fn f(a, b) = max(a, b)"), "(fn f (a b) (max a b))");
+    assert_eq!(sexpr("if c then 1.5 else f()"), "(if c 1.5 (f))");
+
+    let latex = |s| emit(&mut LatexEmitter::new(&ops), s);
+    assert_eq!(latex("(1 + x) / 2 * 3"), "\\frac{1 + x}{2} \\cdot 3");
+    assert_eq!(
+        latex("(a - b) * -(c - d)"),
+        "\\left(a - b\\right) \\cdot -\\left(c - d\\right)"
+    );
+    assert_eq!(latex("1 - (2 - 3)"), "1 - \\left(2 - 3\\right)");
+    assert_eq!(
+        latex("(-x) ^ (1 + n) ^ 2"),
+        "\\left(-x\\right)^{\\left(1 + n\\right)^{2}}"
+    );
+    assert_eq!(
+        latex("sqrt(abs(x_1))"),
+        "\\sqrt{\\left|\\mathit{x\\_1}\\right|}"
+    );
+
+    let dot = |s| emit(&mut DotEmitter::new(), s);
+    assert_eq!(
+        dot("x"),
+        "digraph {\n  node [shape=box];\n  n0 [label=\"x\"];\n}"
+    );
+    assert_eq!(
+        dot("-a"),
+        "digraph {\n  node [shape=box];\n  n0 [label=\"-\"];\n  n1 [label=\"a\"];\n  n0 -> n1;\n}"
+    );
+    assert_eq!(dot_string("a\"b\\"), "\"a\\\"b\\\\\"");
+}
+
+/// 書き出し器の出力をtests/golden/のファイルと比べる
+/// UPDATE_GOLDEN=1を付けて実行すると、今の出力でファイルを書き直す
+#[test]
+fn test_emitters_golden() {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/golden");
+    let ops = OperatorTable::default();
+    let src = std::fs::read_to_string(dir.join("emit.calc")).unwrap();
+    let stmts: Vec<_> = ops
+        .parse_script(&src)
+        .into_iter()
+        .map(|(_, result)| result.unwrap())
+        .collect();
+    let emitters: Vec<(&str, Box<dyn Emitter>)> = vec![
+        ("sexpr", Box::new(SexprEmitter::new())),
+        ("tex", Box::new(LatexEmitter::new(&ops))),
+        ("dot", Box::new(DotEmitter::new())),
+        ("rpn", Box::new(RpnCompiler::new())),
+    ];
+    for (ext, mut emitter) in emitters {
+        let out: String = stmts.iter().map(|s| emitter.emit(s) + "\n").collect();
+        let path = dir.join(format!("emit.{}", ext));
+        if std::env::var_os("UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, &out).unwrap();
+        }
+        let expected = std::fs::read_to_string(&path).unwrap();
+        assert_eq!(
+            out,
+            expected,
+            "{} differs from the golden file",
+            path.display()
+        );
+    }
+}
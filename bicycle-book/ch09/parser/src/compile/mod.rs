@@ -0,0 +1,25 @@
+//! 構文木をほかの表現に変換するバックエンド
+
+mod emit;
+mod rpn;
+mod vm;
+mod wat;
+
+pub use self::emit::{DotEmitter, LatexEmitter, SexprEmitter};
+pub use self::rpn::{RpnCompiler, RpnError, RpnErrorKind, RpnReader};
+pub use self::vm::{BytecodeCompiler, CompiledFn, Instr, InstrLoc, Program, Vm};
+pub use self::wat::{WatCompiler, WatError, WatErrorKind};
+
+use crate::ast::Ast;
+
+/// 構文木を別の表現に書き出すバックエンドが実装するトレイト
+pub trait Emitter {
+    /// 文を1つ書き出す。複数行になってもよいが、末尾に改行は付けない
+    fn emit(&mut self, stmt: &Ast) -> String;
+}
+
+impl Emitter for RpnCompiler {
+    fn emit(&mut self, stmt: &Ast) -> String {
+        self.compile(stmt)
+    }
+}
@@ -0,0 +1,397 @@
+#[cfg(test)]
+use crate::ast::arb_ast;
+use crate::ast::{ensure_stack, Ast, AstKind, BinOp, BinOpKind, UniOp, UniOpKind};
+use crate::diagnostics::Diagnostic;
+#[cfg(test)]
+use crate::format::{parseable, without_loc};
+use crate::lexer::{lex, Annot, Loc, Token, TokenKind};
+use std::error::Error as StdError;
+use std::fmt;
+
+/// 逆ポーランド記法へのコンパイラを表すデータ型
+pub struct RpnCompiler;
+
+impl Default for RpnCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RpnCompiler {
+    pub fn new() -> Self {
+        RpnCompiler
+    }
+
+    pub fn compile(&mut self, expr: &Ast) -> String {
+        let mut buf = String::new();
+        self.compile_inner(expr, &mut buf);
+        buf
+    }
+
+    pub fn compile_inner(&mut self, expr: &Ast, buf: &mut String) {
+        ensure_stack(|| self.compile_node(expr, buf))
+    }
+
+    fn compile_node(&mut self, expr: &Ast, buf: &mut String) {
+        use self::AstKind::*;
+
+        match expr.value {
+            // 構文エラーの箇所は整形器と同じく<error>と書く
+            Error => buf.push_str("<error>"),
+            Num(n) => buf.push_str(&n.to_string()),
+            Float(f) => buf.push_str(&format!("{:?}", f)),
+            Bool(b) => buf.push_str(&b.to_string()),
+            Var(ref name) => buf.push_str(name),
+            Let { ref var, ref e } => {
+                buf.push_str(&var.value);
+                buf.push(' ');
+                self.compile_inner(e, buf);
+                buf.push_str(" =")
+            }
+            FnDef {
+                ref name,
+                ref params,
+                ref body,
+            } => {
+                // 本体の後に fn:名前(引数,...) を置く
+                self.compile_inner(body, buf);
+                let params: Vec<_> = params.iter().map(|p| p.value.as_str()).collect();
+                buf.push_str(&format!(" fn:{}({})", name.value, params.join(",")));
+            }
+            Call { ref name, ref args } => {
+                // 引数の後に 名前@引数の数 を置く
+                for arg in args {
+                    self.compile_inner(arg, buf);
+                    buf.push(' ');
+                }
+                buf.push_str(&format!("{}@{}", name.value, args.len()));
+            }
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => {
+                // 条件、真の場合、偽の場合の後にifを置く
+                for e in &[cond, then, els] {
+                    self.compile_inner(e, buf);
+                    buf.push(' ');
+                }
+                buf.push_str("if");
+            }
+            UniOp { ref op, ref e } => {
+                self.compile_inner(e, buf);
+                buf.push(' ');
+                self.compile_uniop(op, buf)
+            }
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => {
+                self.compile_inner(l, buf);
+                buf.push(' ');
+                self.compile_inner(r, buf);
+                buf.push(' ');
+                self.compile_binop(op, buf)
+            }
+        }
+    }
+
+    fn compile_uniop(&mut self, op: &UniOp, buf: &mut String) {
+        buf.push_str(rpn_uniop(&op.value))
+    }
+
+    fn compile_binop(&mut self, op: &BinOp, buf: &mut String) {
+        buf.push_str(op.value.symbol())
+    }
+}
+
+/// 逆ポーランド記法での単項演算子の語
+/// 二項演算子の-や+と区別するため、識別子には使えない記号を付ける
+pub(crate) fn rpn_uniop(op: &UniOpKind) -> &'static str {
+    use self::UniOpKind::*;
+    match op {
+        Plus => "u+",
+        Minus => "u-",
+        Not => "!",
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum RpnErrorKind {
+    /// 解釈できない語
+    InvalidWord(String),
+    /// 演算子や関数の被演算子が足りない
+    MissingOperand(String),
+    /// =の左辺が変数でない
+    NotAVariable,
+    /// 式が1つにまとまらない。値は残った式の数
+    Leftover(usize),
+}
+
+pub type RpnError = Annot<RpnErrorKind>;
+
+/// RpnCompilerが出力した逆ポーランド記法からASTを組み立て直すデータ型
+/// ASTの位置は逆ポーランド記法の文字列の上の位置になる
+pub struct RpnReader;
+
+impl Default for RpnReader {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl RpnReader {
+    pub fn new() -> Self {
+        RpnReader
+    }
+
+    pub fn read(&mut self, input: &str) -> Result<Ast, RpnError> {
+        let mut stack = Vec::new();
+        let mut pos = 0;
+        for word in input.split_whitespace() {
+            // split_whitespaceは元の文字列の部分を返すので、そのアドレスから位置がわかる
+            let start = word.as_ptr() as usize - input.as_ptr() as usize;
+            pos = start + word.len();
+            let ast = self.read_word(word, Loc(start, pos), &mut stack)?;
+            stack.push(ast);
+        }
+        match stack.len() {
+            1 => Ok(stack.pop().unwrap()),
+            0 => Err(RpnError::new(RpnErrorKind::Leftover(0), Loc(pos, pos))),
+            n => {
+                let loc = stack[0].loc.merge(&stack[n - 1].loc);
+                Err(RpnError::new(RpnErrorKind::Leftover(n), loc))
+            }
+        }
+    }
+
+    fn read_word(&mut self, word: &str, loc: Loc, stack: &mut Vec<Ast>) -> Result<Ast, RpnError> {
+        use self::BinOpKind::*;
+        use self::UniOpKind::*;
+        const UNIOPS: &[UniOpKind] = &[Plus, Minus, Not];
+        const BINOPS: &[BinOpKind] = &[
+            Add, Sub, Mult, Div, Mod, Pow, Eq, Ne, Lt, Le, Gt, Ge, And, Or,
+        ];
+        let invalid = || RpnError::new(RpnErrorKind::InvalidWord(word.to_string()), loc.clone());
+
+        if let Some(op) = UNIOPS.iter().find(|op| rpn_uniop(op) == word) {
+            let e = pop_operands(stack, 1, word, &loc)?.remove(0);
+            let all = e.loc.merge(&loc);
+            return Ok(Ast::uniop(UniOp::new(op.clone(), loc), e, all));
+        }
+        if let Some(op) = BINOPS.iter().find(|op| op.symbol() == word) {
+            let mut operands = pop_operands(stack, 2, word, &loc)?;
+            let r = operands.pop().unwrap();
+            let l = operands.pop().unwrap();
+            let all = l.loc.merge(&loc);
+            return Ok(Ast::binop(BinOp::new(op.clone(), loc), l, r, all));
+        }
+        match word {
+            "if" => {
+                let mut operands = pop_operands(stack, 3, word, &loc)?;
+                let els = operands.pop().unwrap();
+                let then = operands.pop().unwrap();
+                let cond = operands.pop().unwrap();
+                let all = cond.loc.merge(&loc);
+                return Ok(Ast::if_(cond, then, els, all));
+            }
+            // 変数名 式 = の順に並ぶ
+            "=" => {
+                let mut operands = pop_operands(stack, 2, word, &loc)?;
+                let e = operands.pop().unwrap();
+                let var = operands.pop().unwrap();
+                let all = var.loc.merge(&loc);
+                return match var.value {
+                    AstKind::Var(name) => Ok(Ast::let_(Annot::new(name, var.loc), e, all)),
+                    _ => Err(RpnError::new(RpnErrorKind::NotAVariable, var.loc)),
+                };
+            }
+            _ => (),
+        }
+        // 本体 fn:名前(引数,...) の順に並ぶ
+        if let Some(sig) = word.strip_prefix("fn:") {
+            let (name, params) = sig
+                .strip_suffix(')')
+                .and_then(|sig| sig.split_once('('))
+                .ok_or_else(invalid)?;
+            let name = rpn_ident(name, &loc).ok_or_else(invalid)?;
+            let params = params
+                .split(',')
+                .filter(|p| !p.is_empty())
+                .map(|p| rpn_ident(p, &loc).ok_or_else(invalid))
+                .collect::<Result<Vec<_>, _>>()?;
+            let body = pop_operands(stack, 1, word, &loc)?.remove(0);
+            let all = body.loc.merge(&loc);
+            return Ok(Ast::fn_def(name, params, body, all));
+        }
+        // 引数 ... 名前@引数の数 の順に並ぶ
+        if let Some((name, arity)) = word.split_once('@') {
+            let name = rpn_ident(name, &loc).ok_or_else(invalid)?;
+            let arity = arity.parse().map_err(|_| invalid())?;
+            let args = pop_operands(stack, arity, word, &loc)?;
+            let all = args.first().map_or(loc.clone(), |a| a.loc.merge(&loc));
+            return Ok(Ast::call(name, args, all));
+        }
+        // リテラルと変数は字句解析器と同じ規則で読む
+        match lex(word).map(|mut tokens| tokens.pop().filter(|_| tokens.is_empty())) {
+            Ok(Some(tok)) => match tok.value {
+                TokenKind::Number(n) => Ok(Ast::num(n, loc)),
+                TokenKind::Float(f) => Ok(Ast::float(f, loc)),
+                TokenKind::True => Ok(Ast::bool(true, loc)),
+                TokenKind::False => Ok(Ast::bool(false, loc)),
+                TokenKind::Ident(name) => Ok(Ast::var(&name, loc)),
+                _ => Err(invalid()),
+            },
+            _ => Err(invalid()),
+        }
+    }
+}
+
+/// スタックの上からn個の式を取り出す。順序は積んだ順のまま
+pub(crate) fn pop_operands(
+    stack: &mut Vec<Ast>,
+    n: usize,
+    word: &str,
+    loc: &Loc,
+) -> Result<Vec<Ast>, RpnError> {
+    if stack.len() < n {
+        let kind = RpnErrorKind::MissingOperand(word.to_string());
+        return Err(RpnError::new(kind, loc.clone()));
+    }
+    Ok(stack.split_off(stack.len() - n))
+}
+
+/// 識別子として読める名前なら、位置を付けて返す
+pub(crate) fn rpn_ident(name: &str, loc: &Loc) -> Option<Annot<String>> {
+    match lex(name).ok()?.as_slice() {
+        [Token {
+            value: TokenKind::Ident(name),
+            ..
+        }] => Some(Annot::new(name.to_string(), loc.clone())),
+        _ => None,
+    }
+}
+
+impl fmt::Display for RpnError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::RpnErrorKind::*;
+        match self.value {
+            InvalidWord(ref w) => write!(f, "{}: invalid word '{}'", self.loc, w),
+            MissingOperand(ref w) => write!(f, "{}: '{}' is missing operands", self.loc, w),
+            NotAVariable => write!(f, "{}: the left side of '=' is not a variable", self.loc),
+            Leftover(n) => write!(f, "{}: expected one expression, found {}", self.loc, n),
+        }
+    }
+}
+
+impl StdError for RpnError {
+    fn description(&self) -> &str {
+        use self::RpnErrorKind::*;
+        match self.value {
+            InvalidWord(_) => "the word is neither an operator nor an operand",
+            MissingOperand(_) => "the stack has fewer operands than the word takes",
+            NotAVariable => "only a variable can be bound",
+            Leftover(_) => "the words do not form a single expression",
+        }
+    }
+}
+
+impl RpnError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        use self::RpnErrorKind::*;
+        let loc = self.loc.clone();
+        match self.value {
+            InvalidWord(ref w) => Diagnostic::new(
+                "E0501",
+                format!("invalid word '{}'", w),
+                loc,
+                "neither an operator nor an operand",
+            ),
+            MissingOperand(ref w) => Diagnostic::new(
+                "E0502",
+                format!("'{}' is missing operands", w),
+                loc,
+                "not enough operands on the stack",
+            ),
+            NotAVariable => Diagnostic::new(
+                "E0503",
+                "the left side of '=' is not a variable",
+                loc,
+                "expected a variable",
+            ),
+            Leftover(n) => Diagnostic::new(
+                "E0504",
+                format!("expected one expression, found {}", n),
+                loc,
+                "these do not form a single expression",
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_rpn_reader() {
+    let read = |s: &str| RpnReader::new().read(s);
+    // 単項と二項の-を区別できる
+    assert_eq!(
+        read("1 u- 2 -"),
+        Ok(Ast::binop(
+            BinOp::sub(Loc(7, 8)),
+            Ast::uniop(UniOp::minus(Loc(2, 4)), Ast::num(1, Loc(0, 1)), Loc(0, 4)),
+            Ast::num(2, Loc(5, 6)),
+            Loc(0, 8)
+        ))
+    );
+    assert_eq!(
+        read("x  2.5 ="),
+        Ok(Ast::let_(
+            Annot::new("x".to_string(), Loc(0, 1)),
+            Ast::float(2.5, Loc(3, 6)),
+            Loc(0, 8)
+        ))
+    );
+    let error = |kind, loc| Err(RpnError::new(kind, loc));
+    assert_eq!(
+        read("1 +"),
+        error(RpnErrorKind::MissingOperand("+".to_string()), Loc(2, 3))
+    );
+    assert_eq!(
+        read("1 2 $"),
+        error(RpnErrorKind::InvalidWord("$".to_string()), Loc(4, 5))
+    );
+    assert_eq!(
+        read("1 then"),
+        error(RpnErrorKind::InvalidWord("then".to_string()), Loc(2, 6))
+    );
+    assert_eq!(read("1 2 ="), error(RpnErrorKind::NotAVariable, Loc(0, 1)));
+    assert_eq!(read("1 2 3 +"), error(RpnErrorKind::Leftover(2), Loc(0, 7)));
+    assert_eq!(read(" "), error(RpnErrorKind::Leftover(0), Loc(0, 0)));
+
+    // 中置記法 -> 逆ポーランド記法 -> AST -> 中置記法で元の式に戻る
+    for src in &[
+        "1 + 2 * 3 - -4",
+        "-(1 - 2) ^ -x",
+        "!(a && b) || c != 1.5e-7",
+        "if x < 0 then -x else +x",
+        "let y = max(1, abs(-2), f())",
+        "fn f(a, b) = a % b",
+        "fn g() = 1",
+    ] {
+        let ast: Ast = src.parse().unwrap();
+        let rpn = RpnCompiler::new().compile(&ast);
+        assert_eq!(read(&rpn).unwrap().to_string(), *src, "{}", rpn);
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_rpn_round_trip(ast in arb_ast()) {
+        let ast = parseable(&ast, true);
+        let rpn = RpnCompiler::new().compile(&ast);
+        let read = RpnReader::new().read(&rpn).unwrap();
+        proptest::prop_assert_eq!(without_loc(&read), without_loc(&ast), "{}", rpn);
+    }
+}
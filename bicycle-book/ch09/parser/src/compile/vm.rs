@@ -0,0 +1,600 @@
+//! 構文木をバイトコードにコンパイルし、スタックマシンで実行する
+
+#[cfg(test)]
+use crate::ast::arb_ast;
+use crate::ast::{ensure_stack, Ast, AstKind, BinOp, BinOpKind, UniOp, UniOpKind};
+use crate::interp::{
+    check_arity, compare_numbers, type_mismatch, values_equal, Interpreter, InterpreterError,
+    InterpreterErrorKind, Number, Value, MAX_CALL_DEPTH,
+};
+use crate::lexer::Loc;
+use crate::typeck::Type;
+use std::collections::HashMap;
+use std::rc::Rc;
+
+/// バイトコードの命令
+#[derive(Debug, Clone, PartialEq)]
+pub enum Instr {
+    /// 整数リテラルを積む
+    Push(u64),
+    /// 浮動小数点数リテラルを積む
+    PushFloat(f64),
+    /// 真偽値リテラルを積む
+    PushBool(bool),
+    /// 変数の値を積む
+    Load(String),
+    /// 先頭の値を変数に束縛する。値はスタックに残す
+    Store(String),
+    /// 先頭の値の型を確かめる。Type::NumberかType::Boolのみ使う
+    Expect(Type),
+    /// 符号の反転
+    Neg,
+    /// 論理否定
+    Not,
+    Add,
+    Sub,
+    Mul,
+    Div,
+    Mod,
+    Pow,
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+    /// 指定した位置の命令へ飛ぶ
+    Jump(usize),
+    /// 先頭の真偽値を取り出し、偽なら指定した位置の命令へ飛ぶ
+    JumpIfFalse(usize),
+    /// 引数を指定した数だけ取り出して関数を呼ぶ
+    Call(String, usize),
+    /// 必ず失敗する。式の中に置かれた関数定義など
+    Fail(InterpreterErrorKind),
+}
+
+/// 命令に対応するソース上の位置。実行時エラーはここで報告する
+#[derive(Debug, Clone, PartialEq)]
+pub struct InstrLoc {
+    /// 命令を生成した式の位置
+    expr: Loc,
+    /// 演算子の位置。オーバーフローはここで報告する
+    op: Loc,
+    /// 被演算子や引数の位置
+    operands: Vec<Loc>,
+}
+
+impl InstrLoc {
+    fn new(loc: &Loc) -> Self {
+        InstrLoc {
+            expr: loc.clone(),
+            op: loc.clone(),
+            operands: Vec::new(),
+        }
+    }
+}
+
+/// コンパイル済みのプログラム。命令列と同じ長さの位置の表を持つ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Program {
+    code: Vec<Instr>,
+    locs: Vec<InstrLoc>,
+}
+
+impl Program {
+    /// 命令を追加して、その位置を返す
+    fn emit(&mut self, instr: Instr, loc: InstrLoc) -> usize {
+        self.code.push(instr);
+        self.locs.push(loc);
+        self.code.len() - 1
+    }
+
+    /// ジャンプ命令の飛び先を次に追加する命令に合わせる
+    fn patch(&mut self, at: usize) {
+        let target = self.code.len();
+        match self.code[at] {
+            Instr::Jump(ref mut t) | Instr::JumpIfFalse(ref mut t) => *t = target,
+            _ => unreachable!(),
+        }
+    }
+}
+
+/// バイトコードへのコンパイラを表すデータ型
+pub struct BytecodeCompiler;
+
+impl Default for BytecodeCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl BytecodeCompiler {
+    pub fn new() -> Self {
+        BytecodeCompiler
+    }
+
+    pub fn compile(&mut self, expr: &Ast) -> Program {
+        let mut program = Program {
+            code: Vec::new(),
+            locs: Vec::new(),
+        };
+        self.compile_inner(expr, &mut program);
+        program
+    }
+
+    fn compile_inner(&mut self, expr: &Ast, program: &mut Program) {
+        ensure_stack(|| self.compile_node(expr, program))
+    }
+
+    fn compile_node(&mut self, expr: &Ast, program: &mut Program) {
+        use self::AstKind::*;
+        let here = InstrLoc::new(&expr.loc);
+        match expr.value {
+            Error => {
+                program.emit(Instr::Fail(InterpreterErrorKind::SyntaxError), here);
+            }
+            Num(n) => {
+                program.emit(Instr::Push(n), here);
+            }
+            Float(f) => {
+                program.emit(Instr::PushFloat(f), here);
+            }
+            Bool(b) => {
+                program.emit(Instr::PushBool(b), here);
+            }
+            Var(ref name) => {
+                program.emit(Instr::Load(name.clone()), here);
+            }
+            Let { ref var, ref e } => {
+                self.compile_inner(e, program);
+                program.emit(Instr::Store(var.value.clone()), here);
+            }
+            // 関数定義はVm::execが処理する。式の中にあれば評価と同じくエラーにする
+            FnDef { .. } => {
+                program.emit(Instr::Fail(InterpreterErrorKind::NotAnExpression), here);
+            }
+            Call { ref name, ref args } => {
+                for arg in args {
+                    self.compile_inner(arg, program);
+                }
+                let loc = InstrLoc {
+                    operands: args.iter().map(|arg| arg.loc.clone()).collect(),
+                    ..here
+                };
+                program.emit(Instr::Call(name.value.clone(), args.len()), loc);
+            }
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => {
+                self.compile_inner(cond, program);
+                let to_else = program.emit(Instr::JumpIfFalse(0), InstrLoc::new(&cond.loc));
+                self.compile_inner(then, program);
+                let to_end = program.emit(Instr::Jump(0), here);
+                program.patch(to_else);
+                self.compile_inner(els, program);
+                program.patch(to_end);
+            }
+            UniOp { ref op, ref e } => {
+                self.compile_inner(e, program);
+                let ty = match op.value {
+                    UniOpKind::Not => Type::Bool,
+                    _ => Type::Number,
+                };
+                program.emit(Instr::Expect(ty), InstrLoc::new(&e.loc));
+                let loc = InstrLoc {
+                    op: op.loc.clone(),
+                    ..here
+                };
+                match op.value {
+                    // 単項の+は型を確かめるだけ
+                    UniOpKind::Plus => (),
+                    UniOpKind::Minus => {
+                        program.emit(Instr::Neg, loc);
+                    }
+                    UniOpKind::Not => {
+                        program.emit(Instr::Not, loc);
+                    }
+                }
+            }
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => self.compile_binop(op, l, r, here, program),
+        }
+    }
+
+    fn compile_binop(
+        &mut self,
+        op: &BinOp,
+        l: &Ast,
+        r: &Ast,
+        here: InstrLoc,
+        program: &mut Program,
+    ) {
+        use self::BinOpKind::*;
+        match op.value {
+            // 短絡評価。左辺だけで結果が決まれば右辺は実行しない
+            And => {
+                self.compile_inner(l, program);
+                let to_false = program.emit(Instr::JumpIfFalse(0), InstrLoc::new(&l.loc));
+                self.compile_inner(r, program);
+                program.emit(Instr::Expect(Type::Bool), InstrLoc::new(&r.loc));
+                let to_end = program.emit(Instr::Jump(0), here.clone());
+                program.patch(to_false);
+                program.emit(Instr::PushBool(false), here);
+                program.patch(to_end);
+            }
+            Or => {
+                self.compile_inner(l, program);
+                let to_right = program.emit(Instr::JumpIfFalse(0), InstrLoc::new(&l.loc));
+                program.emit(Instr::PushBool(true), here.clone());
+                let to_end = program.emit(Instr::Jump(0), here);
+                program.patch(to_right);
+                self.compile_inner(r, program);
+                program.emit(Instr::Expect(Type::Bool), InstrLoc::new(&r.loc));
+                program.patch(to_end);
+            }
+            // 等値比較はどちらの型でもよいが、両辺がそろっている必要がある
+            Eq | Ne => {
+                self.compile_inner(l, program);
+                self.compile_inner(r, program);
+                let loc = InstrLoc {
+                    op: op.loc.clone(),
+                    operands: vec![l.loc.clone(), r.loc.clone()],
+                    ..here
+                };
+                let instr = if op.value == Eq { Instr::Eq } else { Instr::Ne };
+                program.emit(instr, loc);
+            }
+            // 残りは数値をとる演算。被演算子はそれぞれ評価した直後に型を確かめる
+            _ => {
+                self.compile_inner(l, program);
+                program.emit(Instr::Expect(Type::Number), InstrLoc::new(&l.loc));
+                self.compile_inner(r, program);
+                program.emit(Instr::Expect(Type::Number), InstrLoc::new(&r.loc));
+                let instr = match op.value {
+                    Add => Instr::Add,
+                    Sub => Instr::Sub,
+                    Mult => Instr::Mul,
+                    Div => Instr::Div,
+                    Mod => Instr::Mod,
+                    Pow => Instr::Pow,
+                    Lt => Instr::Lt,
+                    Le => Instr::Le,
+                    Gt => Instr::Gt,
+                    Ge => Instr::Ge,
+                    Eq | Ne | And | Or => unreachable!(),
+                };
+                let loc = InstrLoc {
+                    op: op.loc.clone(),
+                    ..here
+                };
+                program.emit(instr, loc);
+            }
+        }
+    }
+}
+
+/// コンパイル済みのユーザ定義関数
+pub struct CompiledFn {
+    params: Vec<String>,
+    body: Program,
+}
+
+/// バイトコードを実行するスタックマシン
+pub struct Vm {
+    /// 値のスタック
+    stack: Vec<Value>,
+    /// 変数の環境。REPLの行をまたいで保持される
+    env: HashMap<String, Value>,
+    /// ユーザ定義の関数
+    funcs: HashMap<String, Rc<CompiledFn>>,
+    /// 呼び出し中の関数の引数。末尾が現在の関数
+    frames: Vec<HashMap<String, Value>>,
+    /// 数値の演算はインタプリタと同じ規則で行う
+    arith: Interpreter,
+}
+
+impl Default for Vm {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Vm {
+    pub fn new() -> Self {
+        Vm::with_interpreter(Interpreter::new())
+    }
+
+    /// 定義済みの変数と値。順序は決まっていない
+    pub fn variables(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.env.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// 定義済みの関数と引数の名前。順序は決まっていない
+    pub fn functions(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.funcs
+            .iter()
+            .map(|(name, f)| (name.as_str(), &f.params[..]))
+    }
+
+    /// 多倍長モードのVMを作る
+    pub fn with_bigint() -> Self {
+        Vm::with_interpreter(Interpreter::with_bigint())
+    }
+
+    fn with_interpreter(arith: Interpreter) -> Self {
+        Vm {
+            stack: Vec::new(),
+            env: HashMap::new(),
+            funcs: HashMap::new(),
+            frames: Vec::new(),
+            arith,
+        }
+    }
+
+    /// 文をコンパイルして実行する。関数定義は値を持たないのでNoneを返す
+    pub fn exec(&mut self, stmt: &Ast) -> Result<Option<Value>, InterpreterError> {
+        let mut compiler = BytecodeCompiler::new();
+        match stmt.value {
+            AstKind::FnDef {
+                ref name,
+                ref params,
+                ref body,
+            } => {
+                let func = CompiledFn {
+                    params: params.iter().map(|p| p.value.clone()).collect(),
+                    body: compiler.compile(body),
+                };
+                self.funcs.insert(name.value.clone(), Rc::new(func));
+                Ok(None)
+            }
+            _ => self.run(&compiler.compile(stmt)).map(Some),
+        }
+    }
+
+    /// プログラムを実行して、スタックに残った値を返す
+    pub fn run(&mut self, program: &Program) -> Result<Value, InterpreterError> {
+        let base = self.stack.len();
+        let ret = self.run_inner(program);
+        let ret = ret.map(|()| self.stack.pop().unwrap());
+        // エラーで中断したときは途中の値を捨てる
+        self.stack.truncate(base);
+        ret
+    }
+
+    fn run_inner(&mut self, program: &Program) -> Result<(), InterpreterError> {
+        use self::Instr::*;
+        let mut pc = 0;
+        while let Some(instr) = program.code.get(pc) {
+            let loc = &program.locs[pc];
+            let at_expr = |e| InterpreterError::new(e, loc.expr.clone());
+            pc += 1;
+            let v = match *instr {
+                Push(n) => Value::Num(self.arith.eval_num(n).map_err(at_expr)?),
+                PushFloat(f) => Value::Num(Number::Float(f)),
+                PushBool(b) => Value::Bool(b),
+                // 関数の中なら引数を先に探す
+                Load(ref name) => self
+                    .frames
+                    .last()
+                    .and_then(|frame| frame.get(name))
+                    .or_else(|| self.env.get(name))
+                    .cloned()
+                    .ok_or_else(|| at_expr(InterpreterErrorKind::UnknownVariable(name.clone())))?,
+                Store(ref name) => {
+                    let v = self.stack.last().unwrap().clone();
+                    self.env.insert(name.clone(), v);
+                    continue;
+                }
+                Expect(ty) => {
+                    match (ty, self.stack.last().unwrap()) {
+                        (Type::Number, Value::Num(_)) | (Type::Bool, Value::Bool(_)) => (),
+                        (ty, v) => return Err(type_mismatch(ty.name(), v, &loc.expr)),
+                    }
+                    continue;
+                }
+                Neg => {
+                    let n = self.pop_number();
+                    let n = self
+                        .arith
+                        .eval_uniop(&UniOp::minus(loc.op.clone()), n)
+                        .map_err(|e| InterpreterError::new(e, loc.op.clone()))?;
+                    Value::Num(n)
+                }
+                Not => Value::Bool(!self.pop_bool()),
+                Add | Sub | Mul | Div | Mod | Pow => {
+                    let kind = match *instr {
+                        Add => BinOpKind::Add,
+                        Sub => BinOpKind::Sub,
+                        Mul => BinOpKind::Mult,
+                        Div => BinOpKind::Div,
+                        Mod => BinOpKind::Mod,
+                        _ => BinOpKind::Pow,
+                    };
+                    let b = self.pop_number();
+                    let a = self.pop_number();
+                    let n = self
+                        .arith
+                        .eval_binop(&BinOp::new(kind, loc.op.clone()), a, b)
+                        .map_err(|e| {
+                            // オーバーフローは演算子の位置、それ以外は式全体の位置を指す
+                            let loc = match e {
+                                InterpreterErrorKind::Overflow => loc.op.clone(),
+                                _ => loc.expr.clone(),
+                            };
+                            InterpreterError::new(e, loc)
+                        })?;
+                    Value::Num(n)
+                }
+                Eq | Ne => {
+                    let r = self.stack.pop().unwrap();
+                    let l = self.stack.pop().unwrap();
+                    let eq = values_equal(&l, &r, &loc.operands[1])?;
+                    Value::Bool(eq == (*instr == Eq))
+                }
+                Lt | Le | Gt | Ge => {
+                    let kind = match *instr {
+                        Lt => BinOpKind::Lt,
+                        Le => BinOpKind::Le,
+                        Gt => BinOpKind::Gt,
+                        _ => BinOpKind::Ge,
+                    };
+                    let b = self.pop_number();
+                    let a = self.pop_number();
+                    Value::Bool(compare_numbers(&kind, &a, &b))
+                }
+                Jump(target) => {
+                    pc = target;
+                    continue;
+                }
+                JumpIfFalse(target) => {
+                    if !self.stack.pop().unwrap().into_bool(&loc.expr)? {
+                        pc = target;
+                    }
+                    continue;
+                }
+                Call(ref name, argc) => {
+                    let args = self.stack.split_off(self.stack.len() - argc);
+                    let ret = match self.funcs.get(name) {
+                        Some(func) => {
+                            let func = func.clone();
+                            self.call(name, &func, args)
+                        }
+                        // ユーザ定義の関数がなければ組み込み関数を探す
+                        None => {
+                            // 組み込み関数の引数はすべて数値
+                            let nums = args
+                                .into_iter()
+                                .zip(&loc.operands)
+                                .map(|(v, loc)| v.into_number(loc))
+                                .collect::<Result<Vec<_>, _>>()?;
+                            self.arith.eval_builtin(name, nums).map(Value::Num)
+                        }
+                    };
+                    // 関数本体で起きたエラーも呼び出し位置で報告する
+                    ret.map_err(at_expr)?
+                }
+                Fail(ref e) => return Err(at_expr(e.clone())),
+            };
+            self.stack.push(v);
+        }
+        Ok(())
+    }
+
+    fn call(
+        &mut self,
+        name: &str,
+        func: &CompiledFn,
+        args: Vec<Value>,
+    ) -> Result<Value, InterpreterErrorKind> {
+        check_arity(name, func.params.len(), args.len())?;
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return Err(InterpreterErrorKind::RecursionLimit);
+        }
+        let frame = func.params.iter().cloned().zip(args).collect();
+        self.frames.push(frame);
+        let ret = self.run(&func.body);
+        self.frames.pop();
+        ret.map_err(|e| e.value)
+    }
+
+    // 被演算子の型は直前のExpect命令で確かめてある
+    fn pop_number(&mut self) -> Number {
+        match self.stack.pop() {
+            Some(Value::Num(n)) => n,
+            _ => unreachable!(),
+        }
+    }
+
+    fn pop_bool(&mut self) -> bool {
+        match self.stack.pop() {
+            Some(Value::Bool(b)) => b,
+            _ => unreachable!(),
+        }
+    }
+}
+
+#[test]
+fn test_vm() {
+    // 単項と二項のマイナスは別の命令になる
+    let ast = "-1 - 2".parse::<Ast>().unwrap();
+    let program = BytecodeCompiler::new().compile(&ast);
+    assert_eq!(
+        program.code,
+        vec![
+            Instr::Push(1),
+            Instr::Expect(Type::Number),
+            Instr::Neg,
+            Instr::Expect(Type::Number),
+            Instr::Push(2),
+            Instr::Expect(Type::Number),
+            Instr::Sub,
+        ]
+    );
+    assert_eq!(program.code.len(), program.locs.len());
+
+    let mut vm = Vm::new();
+    let mut exec = |s: &str| vm.exec(&s.parse().unwrap());
+    assert_eq!(exec("-1 - 2"), Ok(Some(Value::Num(Number::Int(-3)))));
+    assert_eq!(
+        exec("fn fact(n) = if n <= 1 then 1 else n * fact(n - 1)"),
+        Ok(None)
+    );
+    assert_eq!(
+        exec("let x = fact(5)"),
+        Ok(Some(Value::Num(Number::Int(120))))
+    );
+    assert_eq!(
+        exec("x > 100 && !false || 1 / 0 == 0"),
+        Ok(Some(Value::Bool(true)))
+    );
+    // 実行時エラーは位置の表を使ってソース上の位置へ戻す
+    assert_eq!(
+        exec("1 + 2 / (3 - 3)"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::DivisionByZero,
+            Loc(4, 14)
+        ))
+    );
+    assert_eq!(
+        exec("abs(1, true)"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::TypeMismatch {
+                expected: "number",
+                found: "bool"
+            },
+            Loc(7, 11)
+        ))
+    );
+    assert_eq!(
+        exec("fact(100)"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::Overflow,
+            Loc(0, 9)
+        ))
+    );
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_vm_agrees_with_interpreter(ast in arb_ast(), bigint in proptest::bool::ANY) {
+        let (mut interp, mut vm) = if bigint {
+            (Interpreter::with_bigint(), Vm::with_bigint())
+        } else {
+            (Interpreter::new(), Vm::new())
+        };
+        for stmt in &["let x = 3", "let y = true", "let m = -9223372036854775807 - 1"] {
+            let stmt = stmt.parse().unwrap();
+            interp.exec(&stmt).unwrap();
+            vm.exec(&stmt).unwrap();
+        }
+        proptest::prop_assert_eq!(interp.exec(&ast), vm.exec(&ast));
+        // letで束縛した変数もそろっている
+        proptest::prop_assert_eq!(interp.env, vm.env);
+    }
+}
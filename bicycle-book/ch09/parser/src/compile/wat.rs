@@ -0,0 +1,493 @@
+#[cfg(test)]
+use crate::ast::arb_arith_ast;
+use crate::ast::{ensure_stack, Ast, AstKind, BinOp, BinOpKind, UniOpKind};
+use crate::diagnostics::Diagnostic;
+#[cfg(test)]
+use crate::interp::{reference_eval, Interpreter, InterpreterErrorKind, Number, Value};
+use crate::lexer::Annot;
+#[cfg(test)]
+use crate::lexer::Loc;
+#[cfg(test)]
+use crate::typeck::Type;
+use crate::typeck::{TypeChecker, TypeError, TypeErrorKind};
+use num_traits::ToPrimitive;
+#[cfg(test)]
+use num_traits::Zero;
+use std::error::Error as StdError;
+use std::fmt;
+
+/// WATのeval関数が失敗したときにerrorグローバルに入れる値
+pub(crate) const WAT_DIVISION_BY_ZERO: i32 = 1;
+pub(crate) const WAT_OVERFLOW: i32 = 2;
+/// 結果が整数にならない。i64では有理数を表せない
+pub(crate) const WAT_NOT_INTEGER: i32 = 3;
+
+/// WATモジュールの前半。i64の演算をインタプリタと同じ規則で検査する補助関数を定義する
+/// 失敗したらerrorに理由を入れてトラップする
+pub(crate) fn wat_prelude() -> String {
+    format!(
+        r#"(module
+  (global $error (export "error") (mut i32) (i32.const 0))
+  (func $fail (param $code i32) (result i64)
+    (global.set $error (local.get $code))
+    unreachable)
+  (func $add (param $l i64) (param $r i64) (result i64)
+    (local $x i64)
+    (local.set $x (i64.add (local.get $l) (local.get $r)))
+    ;; 結果の符号が両辺の符号と違えば溢れている
+    (if (i64.lt_s (i64.and (i64.xor (local.get $l) (local.get $x))
+                           (i64.xor (local.get $r) (local.get $x)))
+                  (i64.const 0))
+      (then (drop (call $fail (i32.const {overflow})))))
+    (local.get $x))
+  (func $sub (param $l i64) (param $r i64) (result i64)
+    (local $x i64)
+    (local.set $x (i64.sub (local.get $l) (local.get $r)))
+    ;; 符号の違う数を引いて結果の符号が左辺と違えば溢れている
+    (if (i64.lt_s (i64.and (i64.xor (local.get $l) (local.get $r))
+                           (i64.xor (local.get $l) (local.get $x)))
+                  (i64.const 0))
+      (then (drop (call $fail (i32.const {overflow})))))
+    (local.get $x))
+  (func $mul (param $l i64) (param $r i64) (result i64)
+    (local $x i64)
+    (local.set $x (i64.mul (local.get $l) (local.get $r)))
+    ;; 割り戻して元に戻らなければ溢れている。-1 * i64::MINは割り戻すとトラップするので先に調べる
+    (if (i64.eq (local.get $l) (i64.const -1))
+      (then
+        (if (i64.eq (local.get $r) (i64.const -9223372036854775808))
+          (then (drop (call $fail (i32.const {overflow}))))))
+      (else
+        (if (i64.ne (local.get $l) (i64.const 0))
+          (then
+            (if (i64.ne (i64.div_s (local.get $x) (local.get $l)) (local.get $r))
+              (then (drop (call $fail (i32.const {overflow})))))))))
+    (local.get $x))
+  (func $check_div (param $l i64) (param $r i64)
+    (if (i64.eqz (local.get $r))
+      (then (drop (call $fail (i32.const {div_zero})))))
+    (if (i32.and (i64.eq (local.get $l) (i64.const -9223372036854775808))
+                 (i64.eq (local.get $r) (i64.const -1)))
+      (then (drop (call $fail (i32.const {overflow}))))))
+  (func $div (param $l i64) (param $r i64) (result i64)
+    (call $check_div (local.get $l) (local.get $r))
+    (if (i64.ne (i64.rem_s (local.get $l) (local.get $r)) (i64.const 0))
+      (then (drop (call $fail (i32.const {not_integer})))))
+    (i64.div_s (local.get $l) (local.get $r)))
+  (func $rem (param $l i64) (param $r i64) (result i64)
+    (call $check_div (local.get $l) (local.get $r))
+    (i64.rem_s (local.get $l) (local.get $r)))
+  (func $neg (param $n i64) (result i64)
+    (call $sub (i64.const 0) (local.get $n)))
+  (func $abs (param $n i64) (result i64)
+    (if (result i64) (i64.lt_s (local.get $n) (i64.const 0))
+      (then (call $neg (local.get $n)))
+      (else (local.get $n))))
+  (func $min (param $l i64) (param $r i64) (result i64)
+    (select (local.get $l) (local.get $r) (i64.lt_s (local.get $l) (local.get $r))))
+  (func $max (param $l i64) (param $r i64) (result i64)
+    (select (local.get $r) (local.get $l) (i64.lt_s (local.get $l) (local.get $r))))
+  ;; expは符号なしとして扱う。num_traits::checked_powと同じ手順で二乗していく
+  (func $upow (param $base i64) (param $exp i64) (result i64)
+    (local $acc i64)
+    (if (i64.eqz (local.get $exp))
+      (then (return (i64.const 1))))
+    (block $odd
+      (loop $even
+        (br_if $odd (i32.wrap_i64 (i64.and (local.get $exp) (i64.const 1))))
+        (local.set $base (call $mul (local.get $base) (local.get $base)))
+        (local.set $exp (i64.shr_u (local.get $exp) (i64.const 1)))
+        (br $even)))
+    (local.set $acc (local.get $base))
+    (block $done
+      (loop $next
+        (br_if $done (i64.le_u (local.get $exp) (i64.const 1)))
+        (local.set $exp (i64.shr_u (local.get $exp) (i64.const 1)))
+        (local.set $base (call $mul (local.get $base) (local.get $base)))
+        (if (i32.wrap_i64 (i64.and (local.get $exp) (i64.const 1)))
+          (then (local.set $acc (call $mul (local.get $acc) (local.get $base)))))
+        (br $next)))
+    (local.get $acc))
+  (func $pow (param $base i64) (param $exp i64) (result i64)
+    (if (i64.ge_s (local.get $exp) (i64.const 0))
+      (then (return (call $upow (local.get $base) (local.get $exp)))))
+    ;; 負のべき乗は逆数になる。整数のままなのは底が1か-1のときだけ
+    (if (i64.eqz (local.get $base))
+      (then (drop (call $fail (i32.const {div_zero})))))
+    (if (i64.eq (local.get $base) (i64.const 1))
+      (then (return (i64.const 1))))
+    (if (i64.eq (local.get $base) (i64.const -1))
+      (then (return (select (i64.const -1) (i64.const 1)
+                            (i32.wrap_i64 (i64.and (local.get $exp) (i64.const 1)))))))
+    ;; 分母が溢れるならオーバーフロー、そうでなければ整数にならない
+    (drop (call $upow (local.get $base) (i64.sub (i64.const 0) (local.get $exp))))
+    (call $fail (i32.const {not_integer})))
+"#,
+        div_zero = WAT_DIVISION_BY_ZERO,
+        overflow = WAT_OVERFLOW,
+        not_integer = WAT_NOT_INTEGER
+    )
+}
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum WatErrorKind {
+    /// i64だけのWATでは表せない構文
+    Unsupported(&'static str),
+    /// 組み込み関数の引数の数が合わない
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// 型検査で見つかったエラー
+    Type(TypeErrorKind),
+}
+
+pub type WatError = Annot<WatErrorKind>;
+
+/// WebAssemblyのテキスト形式(WAT)へのコンパイラを表すデータ型
+/// 整数と真偽値の式だけを扱い、真偽値は1と0で表す
+/// i64では有理数を表せないので、`/`や負のべき乗の結果が整数でなければその場でトラップする。
+/// インタプリタは有理数のまま計算を続けるので、`7 / 2 * 2`のように途中だけ整数にならない式は
+/// インタプリタでは7になり、WATではWAT_NOT_INTEGERで失敗する
+pub struct WatCompiler;
+
+impl Default for WatCompiler {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl WatCompiler {
+    pub fn new() -> Self {
+        WatCompiler
+    }
+
+    /// eval() -> i64 をエクスポートするモジュールを作る
+    /// 失敗したときはerrorグローバルにWAT_DIVISION_BY_ZEROなどを入れてトラップする
+    pub fn compile(&mut self, expr: &Ast) -> Result<String, WatError> {
+        // 型の合わない式はi64に落とすと意味が変わるので先に弾く
+        if let Err(mut errors) = TypeChecker::new().type_of(expr) {
+            let e = errors.remove(0);
+            return Err(WatError::new(WatErrorKind::Type(e.value), e.loc));
+        }
+        let mut buf = wat_prelude();
+        buf.push_str("  (func (export \"eval\") (result i64)\n    ");
+        self.compile_inner(expr, &mut buf)?;
+        buf.push_str("))\n");
+        Ok(buf)
+    }
+
+    fn compile_inner(&mut self, expr: &Ast, buf: &mut String) -> Result<(), WatError> {
+        ensure_stack(|| self.compile_node(expr, buf))
+    }
+
+    fn compile_node(&mut self, expr: &Ast, buf: &mut String) -> Result<(), WatError> {
+        use self::AstKind::*;
+        let unsupported = |what| {
+            Err(WatError::new(
+                WatErrorKind::Unsupported(what),
+                expr.loc.clone(),
+            ))
+        };
+        match expr.value {
+            Error => return unsupported("a syntax error"),
+            Num(n) => match n.to_i64() {
+                Some(n) => buf.push_str(&format!("(i64.const {})", n)),
+                // インタプリタと同じく評価したときに溢れる
+                None => buf.push_str(&format!("(call $fail (i32.const {}))", WAT_OVERFLOW)),
+            },
+            Bool(b) => buf.push_str(&format!("(i64.const {})", b as i64)),
+            Float(_) => return unsupported("floating point numbers"),
+            Var(_) => return unsupported("variables"),
+            // モジュールは一度しか評価しないので束縛は残らない
+            Let { ref e, .. } => self.compile_inner(e, buf)?,
+            FnDef { .. } => return unsupported("function definitions"),
+            Call { ref name, ref args } => {
+                let arity = match name.value.as_str() {
+                    "abs" => 1,
+                    "min" | "max" | "pow" => 2,
+                    _ => return unsupported("calls other than abs, min, max and pow"),
+                };
+                if args.len() != arity {
+                    let kind = WatErrorKind::ArityMismatch {
+                        name: name.value.clone(),
+                        expected: arity,
+                        found: args.len(),
+                    };
+                    return Err(WatError::new(kind, expr.loc.clone()));
+                }
+                buf.push_str(&format!("(call ${}", name.value));
+                for arg in args {
+                    buf.push(' ');
+                    self.compile_inner(arg, buf)?;
+                }
+                buf.push(')');
+            }
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => {
+                buf.push_str("(if (result i64) (i32.wrap_i64 ");
+                self.compile_inner(cond, buf)?;
+                buf.push_str(") (then ");
+                self.compile_inner(then, buf)?;
+                buf.push_str(") (else ");
+                self.compile_inner(els, buf)?;
+                buf.push_str("))");
+            }
+            UniOp { ref op, ref e } => {
+                match op.value {
+                    UniOpKind::Plus => return self.compile_inner(e, buf),
+                    UniOpKind::Minus => buf.push_str("(call $neg "),
+                    UniOpKind::Not => buf.push_str("(i64.extend_i32_u (i64.eqz "),
+                }
+                self.compile_inner(e, buf)?;
+                buf.push_str(if op.value == UniOpKind::Not {
+                    "))"
+                } else {
+                    ")"
+                });
+            }
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => self.compile_binop(op, l, r, buf)?,
+        }
+        Ok(())
+    }
+
+    fn compile_binop(
+        &mut self,
+        op: &BinOp,
+        l: &Ast,
+        r: &Ast,
+        buf: &mut String,
+    ) -> Result<(), WatError> {
+        use self::BinOpKind::*;
+        let (open, mid, close) = match op.value {
+            Add => ("(call $add ", " ", ")"),
+            Sub => ("(call $sub ", " ", ")"),
+            Mult => ("(call $mul ", " ", ")"),
+            Div => ("(call $div ", " ", ")"),
+            Mod => ("(call $rem ", " ", ")"),
+            Pow => ("(call $pow ", " ", ")"),
+            Eq => ("(i64.extend_i32_u (i64.eq ", " ", "))"),
+            Ne => ("(i64.extend_i32_u (i64.ne ", " ", "))"),
+            Lt => ("(i64.extend_i32_u (i64.lt_s ", " ", "))"),
+            Le => ("(i64.extend_i32_u (i64.le_s ", " ", "))"),
+            Gt => ("(i64.extend_i32_u (i64.gt_s ", " ", "))"),
+            Ge => ("(i64.extend_i32_u (i64.ge_s ", " ", "))"),
+            // 短絡評価。左辺だけで結果が決まれば右辺は評価しない
+            And => (
+                "(if (result i64) (i32.wrap_i64 ",
+                ") (then ",
+                ") (else (i64.const 0)))",
+            ),
+            Or => (
+                "(if (result i64) (i32.wrap_i64 ",
+                ") (then (i64.const 1)) (else ",
+                "))",
+            ),
+        };
+        buf.push_str(open);
+        self.compile_inner(l, buf)?;
+        buf.push_str(mid);
+        self.compile_inner(r, buf)?;
+        buf.push_str(close);
+        Ok(())
+    }
+}
+
+impl fmt::Display for WatError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::WatErrorKind::*;
+        match self.value {
+            Unsupported(what) => write!(f, "{} cannot be compiled to wasm", what),
+            ArityMismatch {
+                ref name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "function '{}' takes {} argument(s) but {} were given",
+                name, expected, found
+            ),
+            Type(ref e) => TypeError::new(e.clone(), self.loc.clone()).fmt(f),
+        }
+    }
+}
+
+impl StdError for WatError {
+    fn description(&self) -> &str {
+        use self::WatErrorKind::*;
+        match self.value {
+            Unsupported(_) => "the wasm backend only handles integer and boolean expressions",
+            ArityMismatch { .. } => "the number of arguments does not match the builtin",
+            Type(_) => "the expression has a different type than the operation requires",
+        }
+    }
+}
+
+impl WatError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        use self::WatErrorKind::*;
+        let loc = self.loc.clone();
+        match self.value {
+            Unsupported(_) => Diagnostic::new("E0401", self.to_string(), loc, "not supported")
+                .with_note("the wasm backend only handles integer and boolean expressions"),
+            ArityMismatch { found, .. } => Diagnostic::new(
+                "E0402",
+                self.to_string(),
+                loc,
+                format!("called with {} argument(s)", found),
+            ),
+            Type(ref e) => TypeError::new(e.clone(), loc).diagnostic(),
+        }
+    }
+}
+
+/// WATをwasmに変換して埋め込みのランタイムで実行する。失敗したらerrorの値を返す
+#[cfg(test)]
+fn run_wat(wat: &str) -> Result<i64, i32> {
+    use wasmi::{Engine, Linker, Module, Store};
+    let wasm = wat::parse_str(wat).expect("invalid wat");
+    let engine = Engine::default();
+    let module = Module::new(&engine, &wasm[..]).expect("invalid wasm");
+    let mut store = Store::new(&engine, ());
+    let instance = Linker::<()>::new(&engine)
+        .instantiate(&mut store, &module)
+        .and_then(|pre| pre.start(&mut store))
+        .unwrap();
+    let eval = instance.get_typed_func::<(), i64>(&store, "eval").unwrap();
+    eval.call(&mut store, ()).map_err(|_| {
+        match instance.get_global(&store, "error").unwrap().get(&store) {
+            wasmi::Value::I32(code) => code,
+            v => panic!("unexpected error value {:?}", v),
+        }
+    })
+}
+
+/// インタプリタの結果をWATの結果の形にする
+#[cfg(test)]
+fn expected_wat_result(ast: &Ast) -> Result<i64, i32> {
+    match Interpreter::new().eval(ast) {
+        Ok(Value::Num(Number::Int(n))) => Ok(n),
+        Ok(Value::Bool(b)) => Ok(b as i64),
+        Ok(Value::Num(_)) => Err(WAT_NOT_INTEGER),
+        Err(e) => Err(match e.value {
+            InterpreterErrorKind::DivisionByZero => WAT_DIVISION_BY_ZERO,
+            InterpreterErrorKind::Overflow => WAT_OVERFLOW,
+            e => panic!("unexpected error {:?}", e),
+        }),
+    }
+}
+
+#[test]
+fn test_wat_compiler() {
+    let inputs = [
+        "1 + 2 * 3 - 4",
+        "-(2 ^ 62) * 2",
+        "2 ^ 63",
+        "(-2) ^ 63",
+        "3 ^ -2",
+        "(-1) ^ -3",
+        "0 ^ -1",
+        "1 ^ 9223372036854775807",
+        "2 ^ -100",
+        "8 / 2 + 7 % -3",
+        "(-7) % 3",
+        "7 / 2",
+        "1 / 0",
+        "5 % (2 - 2)",
+        "9223372036854775807 + 1",
+        "9223372036854775808",
+        "(-9223372036854775807 - 1) / -1",
+        "(-9223372036854775807 - 1) % -1",
+        "-(-9223372036854775807 - 1)",
+        "abs(-9223372036854775807) + min(3, -4) * max(2, 5)",
+        "pow(10, 18) * 10",
+        "if 1 < 2 && !(3 >= 4) then 10 else 1 / 0",
+        "false && 1 / 0 == 0 || 2 != 2",
+        "let x = 6 * 7",
+    ];
+    for input in inputs.iter() {
+        let ast = input.parse::<Ast>().unwrap();
+        let wat = WatCompiler::new().compile(&ast).unwrap();
+        assert_eq!(run_wat(&wat), expected_wat_result(&ast), "{}", input);
+    }
+
+    let compile = |s: &str| WatCompiler::new().compile(&s.parse().unwrap());
+    // 途中の値が整数にならないとインタプリタと結果が変わる
+    assert_eq!(expected_wat_result(&"7 / 2 * 2".parse().unwrap()), Ok(7));
+    assert_eq!(
+        run_wat(&compile("7 / 2 * 2").unwrap()),
+        Err(WAT_NOT_INTEGER)
+    );
+
+    // i64で表せない式はコンパイルしない
+    assert_eq!(
+        compile("1 + 0.5"),
+        Err(WatError::new(
+            WatErrorKind::Unsupported("floating point numbers"),
+            Loc(4, 7)
+        ))
+    );
+    assert_eq!(
+        compile("1 + true"),
+        Err(WatError::new(
+            WatErrorKind::Type(TypeErrorKind::Mismatch {
+                expected: Type::Number,
+                found: Type::Bool
+            }),
+            Loc(4, 8)
+        ))
+    );
+}
+
+/// 変数をリテラルに置き換える。WATは変数を扱えない
+#[cfg(test)]
+fn subst_var(ast: &Ast, n: u64) -> Ast {
+    let loc = ast.loc.clone();
+    match ast.value {
+        AstKind::Var(_) => Ast::num(n, loc),
+        AstKind::UniOp { ref op, ref e } => Ast::uniop(op.clone(), subst_var(e, n), loc),
+        AstKind::BinOp {
+            ref op,
+            ref l,
+            ref r,
+        } => Ast::binop(op.clone(), subst_var(l, n), subst_var(r, n), loc),
+        _ => ast.clone(),
+    }
+}
+
+/// 途中の演算の結果に整数でない値があるかどうか
+#[cfg(test)]
+fn has_fraction(ast: &Ast) -> bool {
+    match ast.value {
+        AstKind::UniOp { ref e, .. } => has_fraction(e),
+        AstKind::BinOp { ref l, ref r, .. } => {
+            let value = reference_eval(ast, &Zero::zero(), &mut true);
+            has_fraction(l) || has_fraction(r) || value.is_some_and(|v| !v.is_integer())
+        }
+        _ => false,
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_wat_agrees_with_interpreter(ast in arb_arith_ast(), x in 0u64..5) {
+        let ast = subst_var(&ast, x);
+        let wat = WatCompiler::new().compile(&ast).unwrap();
+        let (expected, found) = (expected_wat_result(&ast), run_wat(&wat));
+        // 結果が違ってよいのは、途中で整数にならない値が出てWATがトラップしたときだけ
+        if found != expected {
+            proptest::prop_assert_eq!(found, Err(WAT_NOT_INTEGER), "expected {:?}", expected);
+            proptest::prop_assert!(has_fraction(&ast));
+        }
+    }
+}
@@ -0,0 +1,419 @@
+//! エラーの位置を示す診断メッセージとその表示
+
+#[cfg(test)]
+use crate::ast::Ast;
+use crate::lexer::{LexError, LexErrorKind, Loc};
+use crate::parser::{Error, ParseError, MAX_DEPTH};
+
+/// ソースコードのバイト位置を行と列に変換する
+pub struct SourceMap<'a> {
+    /// 診断に表示するファイル名
+    name: &'a str,
+    src: &'a str,
+    /// 各行の先頭のバイト位置
+    line_starts: Vec<usize>,
+}
+
+impl<'a> SourceMap<'a> {
+    pub fn new(name: &'a str, src: &'a str) -> Self {
+        let line_starts = std::iter::once(0)
+            .chain(src.match_indices('\n').map(|(i, _)| i + 1))
+            .collect();
+        SourceMap {
+            name,
+            src,
+            line_starts,
+        }
+    }
+
+    /// 位置を含む行の番号(0始まり)。入力の終わりより後ろは最後の行とみなす
+    fn line_index(&self, pos: usize) -> usize {
+        match self.line_starts.binary_search(&pos) {
+            Ok(i) => i,
+            Err(i) => i - 1,
+        }
+    }
+
+    /// 改行を除いた行の内容
+    fn line(&self, line: usize) -> &'a str {
+        let end = self
+            .line_starts
+            .get(line + 1)
+            .map_or(self.src.len(), |&next| next - 1);
+        let text = &self.src[self.line_starts[line]..end];
+        text.strip_suffix('\r').unwrap_or(text)
+    }
+
+    /// 行の先頭からposまでの部分。posは行の中の文字の境界に丸める
+    fn prefix(&self, line: usize, pos: usize) -> &'a str {
+        let text = self.line(line);
+        let mut len = pos.saturating_sub(self.line_starts[line]).min(text.len());
+        while !text.is_char_boundary(len) {
+            len -= 1;
+        }
+        &text[..len]
+    }
+
+    /// 1始まりの行と列。列は文字単位で数える
+    pub fn line_col(&self, pos: usize) -> (usize, usize) {
+        let line = self.line_index(pos);
+        let col = self.prefix(line, pos).chars().count();
+        // 行末より後ろを指す位置は行末からの距離をそのまま足す
+        let past_end = pos.saturating_sub(self.line_starts[line] + self.line(line).len());
+        (line + 1, col + past_end + 1)
+    }
+
+    /// 行の先頭からposまでの表示幅
+    fn display_col(&self, pos: usize) -> usize {
+        let line = self.line_index(pos);
+        text_width(self.prefix(line, pos))
+    }
+}
+
+/// 端末に表示したときの幅。全角文字は2、結合文字は0、タブは4と数える
+pub(crate) fn text_width(s: &str) -> usize {
+    use unicode_width::UnicodeWidthChar;
+    s.chars()
+        .map(|c| if c == '\t' { 4 } else { c.width().unwrap_or(0) })
+        .sum()
+}
+
+/// 診断の中で位置に付ける説明
+#[derive(Debug, Clone, PartialEq)]
+pub struct Label {
+    pub loc: Loc,
+    pub message: String,
+}
+
+/// rustc風の診断。エラーコードと、エラーの位置を示す主ラベル、関連する位置を示す補助ラベル、注記を持つ
+#[derive(Debug, Clone, PartialEq)]
+pub struct Diagnostic {
+    pub code: &'static str,
+    pub message: String,
+    pub primary: Label,
+    pub secondary: Vec<Label>,
+    pub notes: Vec<String>,
+}
+
+impl Diagnostic {
+    pub fn new(
+        code: &'static str,
+        message: impl Into<String>,
+        loc: Loc,
+        label: impl Into<String>,
+    ) -> Self {
+        Diagnostic {
+            code,
+            message: message.into(),
+            primary: Label {
+                loc,
+                message: label.into(),
+            },
+            secondary: Vec::new(),
+            notes: Vec::new(),
+        }
+    }
+
+    pub fn with_secondary(mut self, loc: Loc, label: impl Into<String>) -> Self {
+        self.secondary.push(Label {
+            loc,
+            message: label.into(),
+        });
+        self
+    }
+
+    pub fn with_note(mut self, note: impl Into<String>) -> Self {
+        self.notes.push(note.into());
+        self
+    }
+
+    /// すべての位置をずらす。行ごとに解析した結果をファイル全体の位置に直すのに使う
+    pub fn offset(mut self, by: usize) -> Self {
+        for label in std::iter::once(&mut self.primary).chain(&mut self.secondary) {
+            label.loc = Loc(label.loc.0 + by, label.loc.1 + by);
+        }
+        self
+    }
+
+    /// 端末向けにrustcと同じ形で描く
+    ///
+    /// ```text
+    /// error[E0104]: unclosed '('
+    ///  --> <stdin>:1:7
+    ///   |
+    /// 1 | f(1, 2
+    ///   |       ^ expected ')'
+    ///   |  - '(' opened here
+    /// ```
+    pub fn render(&self, sm: &SourceMap) -> String {
+        use std::fmt::Write;
+        let mut out = String::new();
+        let (line, col) = sm.line_col(self.primary.loc.0);
+        // ラベルを行ごとにまとめる。同じ行では主ラベルを先に描く
+        let mut labels: Vec<(usize, bool, &Label)> = std::iter::once((true, &self.primary))
+            .chain(self.secondary.iter().map(|l| (false, l)))
+            .map(|(primary, l)| (sm.line_index(l.loc.0), !primary, l))
+            .collect();
+        labels.sort_by_key(|&(line, secondary, _)| (line, secondary));
+        let gutter = labels
+            .last()
+            .map_or(1, |&(line, _, _)| (line + 1).to_string().len());
+        let pad = " ".repeat(gutter);
+
+        writeln!(out, "error[{}]: {}", self.code, self.message).unwrap();
+        writeln!(out, "{}--> {}:{}:{}", pad, sm.name, line, col).unwrap();
+        writeln!(out, "{} |", pad).unwrap();
+        let mut last_line = None;
+        for (line, secondary, label) in labels {
+            if last_line != Some(line) {
+                let text = sm.line(line).replace('\t', "    ");
+                writeln!(out, "{:>w$} | {}", line + 1, text, w = gutter).unwrap();
+                last_line = Some(line);
+            }
+            // 複数行にまたがるときは最初の行の終わりまで下線を引く
+            let start = sm.display_col(label.loc.0);
+            let end = if sm.line_index(label.loc.1) == line {
+                sm.display_col(label.loc.1) + label.loc.1.saturating_sub(sm.src.len())
+            } else {
+                text_width(sm.line(line))
+            };
+            let mark = if secondary { "-" } else { "^" };
+            let underline = mark.repeat((end.saturating_sub(start)).max(1));
+            let text = format!("{}{} {}", " ".repeat(start), underline, label.message);
+            writeln!(out, "{} | {}", pad, text.trim_end()).unwrap();
+        }
+        for note in &self.notes {
+            writeln!(out, "{} = note: {}", pad, note).unwrap();
+        }
+        out
+    }
+
+    /// ツール向けに1行のJSONにする。位置は行と列(1始まり)とバイト位置の両方で表す
+    pub fn to_json(&self, sm: &SourceMap) -> String {
+        let span = |label: &Label, primary: bool| {
+            let (line_start, column_start) = sm.line_col(label.loc.0);
+            let (line_end, column_end) = sm.line_col(label.loc.1);
+            serde_json::json!({
+                "file_name": sm.name,
+                "byte_start": label.loc.0,
+                "byte_end": label.loc.1,
+                "line_start": line_start,
+                "column_start": column_start,
+                "line_end": line_end,
+                "column_end": column_end,
+                "is_primary": primary,
+                "label": label.message,
+            })
+        };
+        let spans: Vec<_> = std::iter::once(span(&self.primary, true))
+            .chain(self.secondary.iter().map(|l| span(l, false)))
+            .collect();
+        serde_json::json!({
+            "code": self.code,
+            "level": "error",
+            "message": self.message,
+            "spans": spans,
+            "notes": self.notes,
+            "rendered": self.render(sm),
+        })
+        .to_string()
+    }
+}
+
+/// 診断の出力形式
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorFormat {
+    /// 端末向けのrustc風の表示
+    Human,
+    /// 1つの診断を1行のJSONにする
+    Json,
+}
+
+impl ErrorFormat {
+    /// 診断を標準エラー出力に書く
+    pub fn emit(self, sm: &SourceMap, diagnostics: &[Diagnostic]) {
+        for d in diagnostics {
+            match self {
+                ErrorFormat::Human => eprint!("{}", d.render(sm)),
+                ErrorFormat::Json => eprintln!("{}", d.to_json(sm)),
+            }
+        }
+    }
+}
+
+impl LexError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        use self::LexErrorKind::*;
+        match self.value {
+            InvalidChar(c) => Diagnostic::new(
+                "E0001",
+                format!("invalid character '{}'", c),
+                self.loc.clone(),
+                "not allowed in an expression",
+            ),
+            Eof => Diagnostic::new(
+                "E0002",
+                "unexpected end of input",
+                self.loc.clone(),
+                "the token is incomplete",
+            ),
+            InvalidDigitForRadix { digit, radix } => {
+                let digits = match radix {
+                    2 => "0 and 1",
+                    8 => "0 to 7",
+                    _ => "0 to 9 and a to f",
+                };
+                Diagnostic::new(
+                    "E0003",
+                    format!("invalid digit '{}' in a base {} literal", digit, radix),
+                    self.loc.clone(),
+                    format!("not a base {} digit", radix),
+                )
+                .with_note(format!("base {} literals use the digits {}", radix, digits))
+            }
+            MissingDigits { radix } => Diagnostic::new(
+                "E0004",
+                format!("missing digits in a base {} literal", radix),
+                self.loc.clone(),
+                "expected digits after the prefix",
+            ),
+            NumberTooLarge => Diagnostic::new(
+                "E0005",
+                "number is too large",
+                self.loc.clone(),
+                "out of range",
+            )
+            .with_note("integers must fit in 64 bits and decimals must be finite"),
+            UnterminatedComment => Diagnostic::new(
+                "E0006",
+                "unterminated block comment",
+                self.loc.clone(),
+                "the comment starts here",
+            )
+            .with_note("close it with `*/`"),
+        }
+    }
+}
+
+impl ParseError {
+    /// 入力の終わりを指す診断のために入力も受け取る
+    pub fn diagnostic(&self, input: &str) -> Diagnostic {
+        use self::ParseError::*;
+        let eof = Loc(input.len(), input.len() + 1);
+        match self {
+            UnexpectedToken(tok) => Diagnostic::new(
+                "E0101",
+                format!("unexpected '{}'", tok.value),
+                tok.loc.clone(),
+                "unexpected token",
+            ),
+            NotExpression(tok) => Diagnostic::new(
+                "E0102",
+                format!("expected an expression, found '{}'", tok.value),
+                tok.loc.clone(),
+                "expected an expression",
+            ),
+            NotOperator(tok) => Diagnostic::new(
+                "E0103",
+                format!("'{}' is not an infix operator", tok.value),
+                tok.loc.clone(),
+                "only allowed before an operand",
+            ),
+            UnclosedOpenParen(tok) => Diagnostic::new("E0104", "unclosed '('", eof, "expected ')'")
+                .with_secondary(tok.loc.clone(), "'(' opened here"),
+            // トークン以降行末までが余り
+            RedundantExpression(tok) => Diagnostic::new(
+                "E0105",
+                "unexpected input after the expression",
+                Loc(tok.loc.0, input.len()),
+                "redundant",
+            ),
+            NestingTooDeep(tok) => Diagnostic::new(
+                "E0107",
+                "expression is nested too deeply",
+                tok.loc.clone(),
+                "nesting limit reached here",
+            )
+            .with_note(format!(
+                "at most {} levels of parentheses and operators are allowed",
+                MAX_DEPTH
+            )),
+            Eof => Diagnostic::new(
+                "E0106",
+                "unexpected end of input",
+                eof,
+                "expected more input",
+            ),
+        }
+    }
+}
+
+impl Error {
+    /// 字句解析エラーは1つ、構文エラーは見つかったものすべての診断を返す
+    pub fn diagnostics(&self, input: &str) -> Vec<Diagnostic> {
+        match self {
+            Error::Lexer(e) => vec![e.diagnostic()],
+            Error::Parser(errors) => errors.iter().map(|e| e.diagnostic(input)).collect(),
+        }
+    }
+}
+
+#[test]
+fn test_source_map() {
+    let sm = SourceMap::new("t", "ab\nあいx\r\n\tz");
+    assert_eq!(sm.line(1), "あいx");
+    assert_eq!(sm.line_col(0), (1, 1));
+    assert_eq!(sm.line_col(3), (2, 1));
+    // 列は文字単位、表示幅は全角文字を2と数える
+    assert_eq!(sm.line_col(9), (2, 3));
+    assert_eq!(sm.display_col(9), 4);
+    assert_eq!(sm.line_col(13), (3, 2));
+    assert_eq!(sm.display_col(13), 4);
+    // 入力の終わりより後ろは最後の行の続き
+    assert_eq!(sm.line_col(15), (3, 4));
+}
+
+#[test]
+fn test_diagnostic() {
+    // 複数行の入力では行ごとにラベルを描き、補助ラベルは開き括弧を指す
+    let input = "(1 +\n2";
+    let sm = SourceMap::new("<stdin>", input);
+    let diagnostics = input.parse::<Ast>().unwrap_err().diagnostics(input);
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0].render(&sm),
+        "error[E0104]: unclosed '('\n \
+         --> <stdin>:2:2\n  \
+         |\n\
+         1 | (1 +\n  \
+         | - '(' opened here\n\
+         2 | 2\n  \
+         |  ^ expected ')'\n"
+    );
+    let json: serde_json::Value = serde_json::from_str(&diagnostics[0].to_json(&sm)).unwrap();
+    assert_eq!(json["code"], "E0104");
+    assert_eq!(json["spans"][0]["is_primary"], true);
+    assert_eq!(json["spans"][0]["line_start"], 2);
+    assert_eq!(json["spans"][0]["column_start"], 2);
+    assert_eq!(json["spans"][1]["label"], "'(' opened here");
+    assert_eq!(json["spans"][1]["byte_start"], 0);
+
+    // 下線は表示幅で位置と長さを決める
+    let input = "let x = 1\nf(あ, 2";
+    let sm = SourceMap::new("t", input);
+    let d = Diagnostic::new("E0101", "unexpected 'あ'", Loc(12, 15), "here")
+        .with_secondary(Loc(10, 11), "called")
+        .with_note("a note");
+    assert_eq!(
+        d.render(&sm),
+        "error[E0101]: unexpected 'あ'\n \
+         --> t:2:3\n  \
+         |\n\
+         2 | f(あ, 2\n  \
+         |   ^^ here\n  \
+         | - called\n  \
+         = note: a note\n"
+    );
+    // 行ごとに解析した結果はずらしてファイル全体の位置に直せる
+    assert_eq!(d.offset(1).primary.loc, Loc(13, 16));
+}
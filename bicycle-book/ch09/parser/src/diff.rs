@@ -0,0 +1,354 @@
+//! 式の記号微分
+
+use crate::ast::{ensure_stack, Ast, AstKind, BinOp, BinOpKind, UniOp, UniOpKind};
+use crate::diagnostics::Diagnostic;
+#[cfg(test)]
+use crate::interp::{Interpreter, Number, Value};
+use crate::lexer::{Annot, Loc};
+use crate::optimize::{is_int, Optimizer};
+use std::error::Error as StdError;
+use std::fmt;
+
+/// 微分できない式のエラー
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum DiffErrorKind {
+    /// 真偽値や比較、剰余など、数値の微分可能な関数ではない式
+    NotDifferentiable(&'static str),
+    /// 変数を含む指数。対数がないので扱えない
+    VariableExponent,
+    /// 導関数がわからない関数。ユーザ定義の関数の本体は見えない
+    UnknownFunction(String),
+    /// letやfnの定義
+    NotAnExpression,
+}
+
+pub type DiffError = Annot<DiffErrorKind>;
+
+impl fmt::Display for DiffError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::DiffErrorKind::*;
+        match &self.value {
+            NotDifferentiable(what) => write!(f, "{}: cannot differentiate {}", self.loc, what),
+            VariableExponent => write!(f, "{}: exponent depends on the variable", self.loc),
+            UnknownFunction(name) => {
+                write!(f, "{}: derivative of '{}' is unknown", self.loc, name)
+            }
+            NotAnExpression => write!(f, "{}: only expressions can be differentiated", self.loc),
+        }
+    }
+}
+
+impl StdError for DiffError {}
+
+impl DiffError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        use self::DiffErrorKind::*;
+        let loc = self.loc.clone();
+        match &self.value {
+            NotDifferentiable(what) => Diagnostic::new(
+                "E0601",
+                format!("cannot differentiate {}", what),
+                loc,
+                "not a differentiable number",
+            ),
+            VariableExponent => Diagnostic::new(
+                "E0602",
+                "exponent depends on the variable",
+                loc,
+                "variable exponent",
+            )
+            .with_note("only constant exponents are supported"),
+            UnknownFunction(name) => Diagnostic::new(
+                "E0603",
+                format!("derivative of '{}' is unknown", name),
+                loc,
+                "cannot differentiate this call",
+            )
+            .with_note("builtin functions except pow, sqrt, abs, min and max are not supported"),
+            NotAnExpression => Diagnostic::new(
+                "E0604",
+                "only expressions can be differentiated",
+                loc,
+                "definition",
+            ),
+        }
+    }
+}
+
+/// 式を変数で微分した式を作る
+/// 和・積・商・べき乗の規則と、組み込み関数の連鎖律を使う。結果は簡約しない
+pub(crate) struct Differentiator<'a> {
+    /// 微分する変数
+    var: &'a str,
+}
+
+impl<'a> Differentiator<'a> {
+    pub fn new(var: &'a str) -> Self {
+        Differentiator { var }
+    }
+
+    /// 生成する式には微分した元の式の位置を持たせる
+    pub fn diff(&mut self, expr: &Ast) -> Result<Ast, DiffError> {
+        ensure_stack(|| self.diff_inner(expr))
+    }
+
+    fn diff_inner(&mut self, expr: &Ast) -> Result<Ast, DiffError> {
+        use self::AstKind::*;
+        let loc = expr.loc.clone();
+        let err = |kind| Err(DiffError::new(kind, expr.loc.clone()));
+        let num = |n| Ast::num(n, loc.clone());
+        match expr.value {
+            Num(_) | Float(_) => Ok(num(0)),
+            Var(ref name) => Ok(num(if name == self.var { 1 } else { 0 })),
+            Bool(_) => err(DiffErrorKind::NotDifferentiable("a boolean")),
+            Let { .. } | FnDef { .. } => err(DiffErrorKind::NotAnExpression),
+            Error => err(DiffErrorKind::NotDifferentiable("a syntax error")),
+            // 区分ごとに微分する。条件の境目では正しくないことがある
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => Ok(Ast::if_(
+                (**cond).clone(),
+                self.diff(then)?,
+                self.diff(els)?,
+                loc,
+            )),
+            UniOp { ref op, ref e } => match op.value {
+                UniOpKind::Plus => self.diff(e),
+                UniOpKind::Minus => Ok(Ast::uniop(op.clone(), self.diff(e)?, loc)),
+                UniOpKind::Not => err(DiffErrorKind::NotDifferentiable("a boolean")),
+            },
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => self.diff_binop(op, l, r, loc),
+            Call { ref name, ref args } => self.diff_call(name, args, loc),
+        }
+    }
+
+    fn diff_binop(&mut self, op: &BinOp, l: &Ast, r: &Ast, loc: Loc) -> Result<Ast, DiffError> {
+        use self::BinOpKind::*;
+        let bin = |op: fn(Loc) -> self::BinOp, l, r| Ast::binop(op(loc.clone()), l, r, loc.clone());
+        match op.value {
+            // (u ± v)' = u' ± v'
+            Add | Sub => Ok(Ast::binop(op.clone(), self.diff(l)?, self.diff(r)?, loc)),
+            // 定数倍は(cu)' = cu'とし、0を掛けた項を作らない
+            // 最適化器は評価が失敗しうる式に0を掛けても消さないため
+            Mult if !self.depends_on(l) => Ok(bin(self::BinOp::mult, l.clone(), self.diff(r)?)),
+            Mult if !self.depends_on(r) => Ok(bin(self::BinOp::mult, self.diff(l)?, r.clone())),
+            // (uv)' = u'v + uv'
+            Mult => Ok(bin(
+                self::BinOp::add,
+                bin(self::BinOp::mult, self.diff(l)?, r.clone()),
+                bin(self::BinOp::mult, l.clone(), self.diff(r)?),
+            )),
+            Div if !self.depends_on(r) => Ok(bin(self::BinOp::div, self.diff(l)?, r.clone())),
+            // (c/v)' = -cv' / v^2。u'vの項は0になるので作らない
+            Div if !self.depends_on(l) => Ok(bin(
+                self::BinOp::div,
+                Ast::uniop(
+                    UniOp::minus(loc.clone()),
+                    bin(self::BinOp::mult, l.clone(), self.diff(r)?),
+                    loc.clone(),
+                ),
+                bin(self::BinOp::pow, r.clone(), Ast::num(2, loc.clone())),
+            )),
+            // (u/v)' = (u'v - uv') / v^2
+            Div => Ok(bin(
+                self::BinOp::div,
+                bin(
+                    self::BinOp::sub,
+                    bin(self::BinOp::mult, self.diff(l)?, r.clone()),
+                    bin(self::BinOp::mult, l.clone(), self.diff(r)?),
+                ),
+                bin(self::BinOp::pow, r.clone(), Ast::num(2, loc.clone())),
+            )),
+            Pow => self.diff_pow(l, r, loc),
+            Mod => Err(DiffError::new(
+                DiffErrorKind::NotDifferentiable("a remainder"),
+                op.loc.clone(),
+            )),
+            Eq | Ne | Lt | Le | Gt | Ge => Err(DiffError::new(
+                DiffErrorKind::NotDifferentiable("a comparison"),
+                loc,
+            )),
+            And | Or => Err(DiffError::new(
+                DiffErrorKind::NotDifferentiable("a boolean"),
+                loc,
+            )),
+        }
+    }
+
+    /// (u^c)' = c * u^(c - 1) * u'。指数は変数を含まないこと
+    fn diff_pow(&mut self, base: &Ast, exp: &Ast, loc: Loc) -> Result<Ast, DiffError> {
+        if self.depends_on(exp) {
+            return Err(DiffError::new(
+                DiffErrorKind::VariableExponent,
+                exp.loc.clone(),
+            ));
+        }
+        // (u^0)' = 0, (u^1)' = u'。一般の式だとu^-1やu^0が残り、u = 0で0除算になる
+        if is_int(exp, 0) {
+            return Ok(Ast::num(0, loc));
+        }
+        if is_int(exp, 1) {
+            return self.diff(base);
+        }
+        let bin = |op: fn(Loc) -> BinOp, l, r| Ast::binop(op(loc.clone()), l, r, loc.clone());
+        let lowered = bin(BinOp::sub, exp.clone(), Ast::num(1, loc.clone()));
+        Ok(bin(
+            BinOp::mult,
+            bin(
+                BinOp::mult,
+                exp.clone(),
+                bin(BinOp::pow, base.clone(), lowered),
+            ),
+            self.diff(base)?,
+        ))
+    }
+
+    /// 組み込み関数に連鎖律を使う
+    fn diff_call(
+        &mut self,
+        name: &Annot<String>,
+        args: &[Ast],
+        loc: Loc,
+    ) -> Result<Ast, DiffError> {
+        let bin = |op: fn(Loc) -> BinOp, l, r| Ast::binop(op(loc.clone()), l, r, loc.clone());
+        let call = |args| Ast::call(name.clone(), args, loc.clone());
+        match (name.value.as_str(), args) {
+            ("pow", [base, exp]) => self.diff_pow(base, exp, loc),
+            // sqrt(u)' = u' / (2 * sqrt(u))
+            ("sqrt", [u]) => Ok(bin(
+                BinOp::div,
+                self.diff(u)?,
+                bin(BinOp::mult, Ast::num(2, loc.clone()), call(vec![u.clone()])),
+            )),
+            // abs(u)' = u' * u / abs(u)
+            ("abs", [u]) => Ok(bin(
+                BinOp::div,
+                bin(BinOp::mult, self.diff(u)?, u.clone()),
+                call(vec![u.clone()]),
+            )),
+            // 小さい(大きい)ほうの導関数を選ぶ
+            ("min", [u, v]) | ("max", [u, v]) => {
+                let cmp = if name.value == "min" {
+                    BinOp::lt
+                } else {
+                    BinOp::gt
+                };
+                Ok(Ast::if_(
+                    bin(cmp, u.clone(), v.clone()),
+                    self.diff(u)?,
+                    self.diff(v)?,
+                    loc,
+                ))
+            }
+            // 変数を含まない呼び出しは定数
+            _ if !args.iter().any(|arg| self.depends_on(arg)) => Ok(Ast::num(0, loc)),
+            _ => Err(DiffError::new(
+                DiffErrorKind::UnknownFunction(name.value.clone()),
+                loc,
+            )),
+        }
+    }
+
+    /// 式が微分する変数を含むかどうか
+    fn depends_on(&self, expr: &Ast) -> bool {
+        use self::AstKind::*;
+        ensure_stack(|| match expr.value {
+            Var(ref name) => name == self.var,
+            Num(_) | Float(_) | Bool(_) | Error => false,
+            Let { ref e, .. } => self.depends_on(e),
+            FnDef { ref body, .. } => self.depends_on(body),
+            Call { ref args, .. } => args.iter().any(|arg| self.depends_on(arg)),
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => self.depends_on(cond) || self.depends_on(then) || self.depends_on(els),
+            UniOp { ref e, .. } => self.depends_on(e),
+            BinOp { ref l, ref r, .. } => self.depends_on(l) || self.depends_on(r),
+        })
+    }
+}
+
+/// exprをvarで微分し、最適化器で簡約した式を返す
+pub fn derivative(expr: &Ast, var: &str) -> Result<Ast, DiffError> {
+    let d = Differentiator::new(var).diff(expr)?;
+    Ok(Optimizer::new().optimize(&d))
+}
+
+#[test]
+fn test_derivative() {
+    let diff = |s: &str| derivative(&s.parse().unwrap(), "x").map(|d| d.to_string());
+    assert_eq!(diff("x ^ 3 + 2 * x + y"), Ok("3 * x ^ 2 + 2".to_string()));
+    assert_eq!(diff("x * y"), Ok("y".to_string()));
+    assert_eq!(diff("1 / x"), Ok("-1 / x ^ 2".to_string()));
+    assert_eq!(diff("y / x"), Ok("-y / x ^ 2".to_string()));
+    assert_eq!(
+        diff("x / (x + 1)"),
+        Ok("(x + 1 - x) / (x + 1) ^ 2".to_string())
+    );
+    assert_eq!(diff("-(x - 5)"), Ok("-1".to_string()));
+    assert_eq!(diff("5 - x * x"), Ok("-(x + x)".to_string()));
+    // 連鎖律
+    assert_eq!(
+        diff("sqrt(x ^ 2 + 1)"),
+        Ok("2 * x / (2 * sqrt(x ^ 2 + 1))".to_string())
+    );
+    assert_eq!(diff("pow(2 * x, 3)"), Ok("3 * (2 * x) ^ 2 * 2".to_string()));
+    // 指数が0や1なら0乗や-1乗を残さない
+    assert_eq!(diff("x ^ 0"), Ok("0".to_string()));
+    assert_eq!(diff("x ^ 1"), Ok("1".to_string()));
+    assert_eq!(diff("(3 * x) ^ 1"), Ok("3".to_string()));
+    assert_eq!(
+        diff("if x < 0 then -x else x"),
+        Ok("if x < 0 then -1 else 1".to_string())
+    );
+    assert_eq!(diff("max(x, 1)"), Ok("if x > 1 then 1 else 0".to_string()));
+    assert_eq!(diff("f(y) * x"), Ok("f(y)".to_string()));
+    // 微分できない式
+    let kind = |s: &str| diff(s).map_err(|e| (e.value, e.loc));
+    assert_eq!(
+        kind("2 ^ x"),
+        Err((DiffErrorKind::VariableExponent, Loc(4, 5)))
+    );
+    assert_eq!(
+        kind("1 + f(x)"),
+        Err((DiffErrorKind::UnknownFunction("f".to_string()), Loc(4, 8)))
+    );
+    assert_eq!(
+        kind("x % 2"),
+        Err((DiffErrorKind::NotDifferentiable("a remainder"), Loc(2, 3)))
+    );
+    assert_eq!(
+        kind("let y = x"),
+        Err((DiffErrorKind::NotAnExpression, Loc(0, 9)))
+    );
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_derivative_matches_difference(a in -20i64..20, b in -20i64..20, n in 0u64..4, at in -10i64..10) {
+        // 多項式の導関数を差分と比べる。整数の多項式なら中心差分は3次まで正確
+        let src = format!("({}) * x ^ {} + ({}) * x", a, n, b);
+        let ast: Ast = src.parse().unwrap();
+        let d = derivative(&ast, "x").unwrap();
+        let eval = |e: &Ast, x: i64| {
+            let mut interp = Interpreter::new();
+            interp.exec(&format!("let x = {}", x).parse().unwrap()).unwrap();
+            interp.eval(e).unwrap()
+        };
+        let slope = match (eval(&ast, at + 1), eval(&ast, at - 1)) {
+            (Value::Num(Number::Int(hi)), Value::Num(Number::Int(lo))) => (hi - lo) / 2,
+            other => panic!("not an integer: {:?}", other),
+        };
+        let expected = if n == 3 { slope - a } else { slope };
+        proptest::prop_assert_eq!(eval(&d, at), Value::Num(Number::Int(expected)));
+    }
+}
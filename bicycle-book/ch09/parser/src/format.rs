@@ -0,0 +1,442 @@
+//! 構文木をソースコードに戻す整形器
+
+#[cfg(test)]
+use crate::ast::arb_ast;
+#[cfg(test)]
+use crate::ast::UniOp;
+use crate::ast::{ensure_stack, Ast, AstKind, BinOpKind, UniOpKind};
+use crate::diagnostics::Diagnostic;
+#[cfg(test)]
+use crate::lexer::Annot;
+use crate::lexer::{comments, Loc};
+use crate::parser::{Assoc, OperatorTable, PostOp};
+use std::fmt;
+
+/// ASTをソースコードに戻す整形器を表すデータ型
+/// 演算子表の結合力と結合性から、パースし直して同じ木になる最小限の括弧だけを付ける
+pub struct AstFormatter<'a> {
+    ops: &'a OperatorTable,
+}
+
+/// 整形した式と、それを被演算子として置くときに括弧が要るかを判断するための情報
+pub(crate) struct Formatted {
+    text: String,
+    /// 式の結合力。これより強い演算子の被演算子にするときは括弧で囲む
+    bp: u8,
+    /// 前置演算子やifで始まる式。演算子の右側にはそのまま置ける
+    prefix: bool,
+    /// 右端が前置演算子やifの被演算子で終わっているとき、後ろに続く演算子を取り込んでしまう結合力の下限
+    open: Option<u8>,
+}
+
+impl Formatted {
+    fn atom(text: String) -> Self {
+        Formatted {
+            text,
+            bp: u8::MAX,
+            prefix: false,
+            open: None,
+        }
+    }
+
+    /// 括弧で囲む。囲んだ式は原子式として扱える
+    fn paren(self) -> Self {
+        Self::atom(format!("({})", self.text))
+    }
+}
+
+impl<'a> AstFormatter<'a> {
+    pub fn new(ops: &'a OperatorTable) -> Self {
+        AstFormatter { ops }
+    }
+
+    pub fn format(&self, stmt: &Ast) -> String {
+        use self::AstKind::*;
+        match stmt.value {
+            Let { ref var, ref e } => format!("let {} = {}", var.value, self.format_expr(e).text),
+            FnDef {
+                ref name,
+                ref params,
+                ref body,
+            } => {
+                let params: Vec<_> = params.iter().map(|p| p.value.as_str()).collect();
+                format!(
+                    "fn {}({}) = {}",
+                    name.value,
+                    params.join(", "),
+                    self.format_expr(body).text
+                )
+            }
+            _ => self.format_expr(stmt).text,
+        }
+    }
+
+    fn format_expr(&self, expr: &Ast) -> Formatted {
+        ensure_stack(|| self.format_expr_inner(expr))
+    }
+
+    fn format_expr_inner(&self, expr: &Ast) -> Formatted {
+        use self::AstKind::*;
+        match expr.value {
+            Num(n) => Formatted::atom(n.to_string()),
+            Float(f) => Formatted::atom(format!("{:?}", f)),
+            Bool(b) => Formatted::atom(b.to_string()),
+            Var(ref name) => Formatted::atom(name.clone()),
+            Error => Formatted::atom("<error>".to_string()),
+            // 文は式の中に書けないので、パーサが作らない木は括弧で囲んでおく
+            Let { .. } | FnDef { .. } => Formatted::atom(self.format(expr)).paren(),
+            Call { ref name, ref args } => {
+                // 引数はカンマや括弧で区切られるので括弧は要らない
+                let args: Vec<_> = args.iter().map(|a| self.format_expr(a).text).collect();
+                Formatted::atom(format!("{}({})", name.value, args.join(", ")))
+            }
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => {
+                // elseの後ろはできるだけ長く読まれるので、後ろに何も続かない位置にだけ括弧なしで置ける
+                Formatted {
+                    text: format!(
+                        "if {} then {} else {}",
+                        self.format_expr(cond).text,
+                        self.format_expr(then).text,
+                        self.format_expr(els).text
+                    ),
+                    bp: 0,
+                    prefix: true,
+                    open: Some(0),
+                }
+            }
+            UniOp { ref op, ref e } => {
+                let bp = self.prefix_bp(&op.value);
+                let mut e = self.format_expr(e);
+                if !e.prefix && e.bp < bp {
+                    e = e.paren();
+                }
+                // --xのように記号がつながっても別々のトークンとして読まれる
+                Formatted {
+                    text: format!("{}{}", op.value.symbol(), e.text),
+                    bp,
+                    prefix: true,
+                    open: Some(e.open.map_or(bp, |o| o.min(bp))),
+                }
+            }
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => {
+                let (bp, assoc) = self.infix_bp(&op.value);
+                let mut l = self.format_expr(l);
+                let absorbs = l.open.is_some_and(|o| o <= bp);
+                if l.bp < bp || (l.bp == bp && assoc == Assoc::Right) || absorbs {
+                    l = l.paren();
+                }
+                let mut r = self.format_expr(r);
+                if !r.prefix && (r.bp < bp || (r.bp == bp && assoc == Assoc::Left)) {
+                    r = r.paren();
+                }
+                Formatted {
+                    text: format!("{} {} {}", l.text, op.value.symbol(), r.text),
+                    bp,
+                    prefix: false,
+                    open: r.open,
+                }
+            }
+        }
+    }
+
+    pub(crate) fn prefix_bp(&self, op: &UniOpKind) -> u8 {
+        match self.ops.prefix.get(op.symbol()) {
+            Some(prefix) => prefix.bp,
+            None => unreachable!("builtin prefix operator {} is not registered", op.symbol()),
+        }
+    }
+
+    pub(crate) fn infix_bp(&self, op: &BinOpKind) -> (u8, Assoc) {
+        match self.ops.post.get(op.symbol()) {
+            Some(PostOp::Infix { bp, assoc, .. }) => (*bp, *assoc),
+            _ => unreachable!("builtin infix operator {} is not registered", op.symbol()),
+        }
+    }
+}
+
+impl fmt::Display for Ast {
+    /// 組み込みの演算子表で整形する
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(&AstFormatter::new(&OperatorTable::default()).format(self))
+    }
+}
+
+#[test]
+fn test_formatter() {
+    let format = |s: &str| s.parse::<Ast>().unwrap().to_string();
+    assert_eq!(format("(1 + 2) * 3"), "(1 + 2) * 3");
+    assert_eq!(format("((1 * 2)) + (3)"), "1 * 2 + 3");
+    // 結合性
+    assert_eq!(format("(1 - 2) - 3"), "1 - 2 - 3");
+    assert_eq!(format("1 - (2 - 3)"), "1 - (2 - 3)");
+    assert_eq!(format("2 ^ (3 ^ 4)"), "2 ^ 3 ^ 4");
+    assert_eq!(format("(2 ^ 3) ^ 4"), "(2 ^ 3) ^ 4");
+    // 前置演算子は^より弱い
+    assert_eq!(format("-(2 ^ 2)"), "-2 ^ 2");
+    assert_eq!(format("(-2) ^ 2"), "(-2) ^ 2");
+    assert_eq!(format("2 ^ (-2)"), "2 ^ -2");
+    assert_eq!(format("-(1 + x)"), "-(1 + x)");
+    assert_eq!(format("- - !x"), "--!x");
+    // elseは後ろの演算子まで取り込むので、左辺に来るときだけ囲む
+    assert_eq!(format("1 + (if c then 2 else 3)"), "1 + if c then 2 else 3");
+    assert_eq!(
+        format("(if c then 2 else 3) + 1"),
+        "(if c then 2 else 3) + 1"
+    );
+    assert_eq!(
+        format("(1 * -(if c then 2 else 3)) + 1"),
+        "(1 * -if c then 2 else 3) + 1"
+    );
+    assert_eq!(format("(1 * -x) + 1"), "1 * -x + 1");
+    assert_eq!(
+        format("if (a && b) then (f(1, (2))) else (1 < 2)"),
+        "if a && b then f(1, 2) else 1 < 2"
+    );
+    // 文
+    assert_eq!(format("let  x=(1+2)"), "let x = 1 + 2");
+    assert_eq!(format("fn f(a,b)=a*(b)"), "fn f(a, b) = a * b");
+    assert_eq!(format("1.5e-7 + 2.0"), "1.5e-7 + 2.0");
+}
+
+/// 位置をすべて同じにした木を返す。位置以外が同じかを比べるのに使う
+#[cfg(test)]
+pub(crate) fn without_loc(ast: &Ast) -> Ast {
+    use self::AstKind::*;
+    let name = |n: &Annot<String>| Annot::new(n.value.clone(), Loc(0, 0));
+    let value = match ast.value {
+        Num(_) | Float(_) | Bool(_) | Var(_) | Error => ast.value.clone(),
+        Let { ref var, ref e } => Let {
+            var: name(var),
+            e: Box::new(without_loc(e)),
+        },
+        FnDef {
+            ref name,
+            ref params,
+            ref body,
+        } => FnDef {
+            name: Annot::new(name.value.clone(), Loc(0, 0)),
+            params: params
+                .iter()
+                .map(|p| Annot::new(p.value.clone(), Loc(0, 0)))
+                .collect(),
+            body: Box::new(without_loc(body)),
+        },
+        Call { ref name, ref args } => Call {
+            name: Annot::new(name.value.clone(), Loc(0, 0)),
+            args: args.iter().map(without_loc).collect(),
+        },
+        If {
+            ref cond,
+            ref then,
+            ref els,
+        } => If {
+            cond: Box::new(without_loc(cond)),
+            then: Box::new(without_loc(then)),
+            els: Box::new(without_loc(els)),
+        },
+        UniOp { ref op, ref e } => UniOp {
+            op: Annot::new(op.value.clone(), Loc(0, 0)),
+            e: Box::new(without_loc(e)),
+        },
+        BinOp {
+            ref op,
+            ref l,
+            ref r,
+        } => BinOp {
+            op: Annot::new(op.value.clone(), Loc(0, 0)),
+            l: Box::new(without_loc(l)),
+            r: Box::new(without_loc(r)),
+        },
+    };
+    Ast::new(value, Loc(0, 0))
+}
+
+/// パーサが作りうる木に直す。式の中のletは外し、負のリテラルは負号を付けた形にする
+#[cfg(test)]
+pub(crate) fn parseable(ast: &Ast, top: bool) -> Ast {
+    use self::AstKind::*;
+    let loc = ast.loc.clone();
+    match ast.value {
+        Let { ref var, ref e } if top => Ast::let_(var.clone(), parseable(e, false), loc),
+        Let { ref e, .. } => parseable(e, false),
+        Float(f) if f.is_sign_negative() => Ast::uniop(
+            self::UniOp::minus(loc.clone()),
+            Ast::float(-f, loc.clone()),
+            loc,
+        ),
+        Call { ref name, ref args } => Ast::call(
+            name.clone(),
+            args.iter().map(|a| parseable(a, false)).collect(),
+            loc,
+        ),
+        If {
+            ref cond,
+            ref then,
+            ref els,
+        } => Ast::if_(
+            parseable(cond, false),
+            parseable(then, false),
+            parseable(els, false),
+            loc,
+        ),
+        UniOp { ref op, ref e } => Ast::uniop(op.clone(), parseable(e, false), loc),
+        BinOp {
+            ref op,
+            ref l,
+            ref r,
+        } => Ast::binop(op.clone(), parseable(l, false), parseable(r, false), loc),
+        _ => ast.clone(),
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_format_round_trip(ast in arb_ast()) {
+        let ast = parseable(&ast, true);
+        let formatted = ast.to_string();
+        let parsed: Ast = formatted.parse().unwrap();
+        proptest::prop_assert_eq!(without_loc(&parsed), without_loc(&ast), "{}", formatted);
+    }
+}
+
+/// ソースコードを1行に1文ずつ整形し直す。文の間の空行は1行にまとめて残す
+/// 文の間のコメントはそれぞれ1行に書く。文の前後のコメントは整形した文に付け、
+/// 文の途中にコメントがあればその文は元のまま残す
+/// パースできない文があれば、すべての文の診断を返す
+pub fn format_source(ops: &OperatorTable, src: &str) -> Result<String, Vec<Diagnostic>> {
+    let fmt = AstFormatter::new(ops);
+    let mut out = String::new();
+    let mut diagnostics = Vec::new();
+    let mut prev_end = None;
+    let mut comments = comments(src).into_iter().peekable();
+    // locにあった文かコメントを1行に書く。前の行との間に空行があれば1行だけ残す
+    let push_line =
+        |out: &mut String, prev_end: &mut Option<usize>, loc: &Loc, text: &str, comment| {
+            let Loc(start, end) = *loc;
+            if let Some(prev) = *prev_end {
+                match src[prev..start].matches('\n').count() {
+                    // 前の文と同じ行にあるコメントは行末に付ける
+                    0 if comment && out.ends_with('\n') => {
+                        out.pop();
+                        out.push(' ');
+                    }
+                    0 | 1 => {}
+                    _ => out.push('\n'),
+                }
+            }
+            out.push_str(text);
+            out.push('\n');
+            *prev_end = Some(end);
+        };
+    for (span, result) in ops.parse_script(src) {
+        while let Some(c) = comments.next_if(|c| c.1 <= span.0) {
+            push_line(&mut out, &mut prev_end, &c, &src[c.0..c.1], true);
+        }
+        let mut inner = Vec::new();
+        while let Some(c) = comments.next_if(|c| c.0 < span.1) {
+            inner.push(c);
+        }
+        match result {
+            Ok(ast) => {
+                let text = with_comments(ops, src, &span, &inner, fmt.format(&ast));
+                push_line(&mut out, &mut prev_end, &span, &text, false);
+            }
+            Err(e) => {
+                diagnostics.extend(e.diagnostics(&src[..span.1]));
+                prev_end = Some(span.1);
+            }
+        }
+    }
+    for c in comments {
+        push_line(&mut out, &mut prev_end, &c, &src[c.0..c.1], true);
+    }
+    if diagnostics.is_empty() {
+        Ok(out)
+    } else {
+        Err(diagnostics)
+    }
+}
+
+/// 整形した文に、文の区間spanの中のコメントを付ける
+/// コメントがすべて最初のトークンより前か最後のトークンより後にあればその位置に付け、
+/// そうでなければ元の文をそのまま使う
+fn with_comments(
+    ops: &OperatorTable,
+    src: &str,
+    span: &Loc,
+    comments: &[Loc],
+    formatted: String,
+) -> String {
+    if comments.is_empty() {
+        return formatted;
+    }
+    let tokens = match ops.lex(&src[span.0..span.1]) {
+        Ok(tokens) if !tokens.is_empty() => tokens,
+        _ => return src[span.0..span.1].trim().to_string(),
+    };
+    let first = span.0 + tokens[0].loc.0;
+    let last = span.0 + tokens[tokens.len() - 1].loc.1;
+    if comments.iter().any(|c| first < c.0 && c.0 < last) {
+        return src[span.0..span.1].trim().to_string();
+    }
+    let mut parts: Vec<&str> = comments
+        .iter()
+        .filter(|c| c.0 < first)
+        .map(|c| &src[c.0..c.1])
+        .collect();
+    parts.push(&formatted);
+    parts.extend(
+        comments
+            .iter()
+            .filter(|c| last <= c.0)
+            .map(|c| &src[c.0..c.1]),
+    );
+    parts.join(" ")
+}
+
+#[test]
+fn test_format_source() {
+    let ops = OperatorTable::default();
+    assert_eq!(
+        format_source(&ops, "let x = (1+2)\n\n\n  fn f(a)=(a*\na); f(1)\n"),
+        Ok("let x = 1 + 2\n\nfn f(a) = a * a\nf(1)\n".to_string())
+    );
+    let errors = format_source(&ops, "1 +\n2\n(3").unwrap_err();
+    let found: Vec<_> = errors
+        .iter()
+        .map(|d| (d.code, d.primary.loc.clone()))
+        .collect();
+    assert_eq!(found, vec![("E0106", Loc(3, 4)), ("E0104", Loc(8, 9))]);
+}
+
+#[test]
+fn test_format_source_comments() {
+    let ops = OperatorTable::default();
+    let src = "# head\n\n/* a */ let x=(1+2) # x\nx*2; # same line\n\n1 + /* mid */ 2\n# tail";
+    assert_eq!(
+        format_source(&ops, src),
+        Ok(
+            "# head\n\n/* a */ let x = 1 + 2 # x\nx * 2 # same line\n\n1 + /* mid */ 2\n# tail\n"
+                .to_string()
+        )
+    );
+    // コメントの中の;や括弧は文を区切らない
+    assert_eq!(
+        format_source(&ops, "1+2 /* ; ( */\n3"),
+        Ok("1 + 2 /* ; ( */\n3\n".to_string())
+    );
+    // ;の後に同じ行で続く文は別の行にする
+    assert_eq!(
+        format_source(&ops, "1;/* a */ 2"),
+        Ok("1\n/* a */ 2\n".to_string())
+    );
+}
@@ -0,0 +1,1128 @@
+//! 構文木を直接評価するインタプリタ
+
+#[cfg(test)]
+use crate::ast::arb_arith_ast;
+use crate::ast::{ensure_stack, Ast, AstKind, BinOp, BinOpKind, UniOp, UniOpKind};
+use crate::diagnostics::Diagnostic;
+use crate::lexer::{Annot, Loc};
+use num_bigint::BigInt;
+use num_rational::{BigRational, Rational64};
+use num_traits::{CheckedAdd, CheckedDiv, CheckedMul, CheckedSub, Signed, ToPrimitive, Zero};
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::rc::Rc;
+
+/// 評価結果の数値を表すデータ型
+/// 演算では整数 -> 有理数 -> 多倍長 -> 浮動小数点数の順に精度の低い方へ昇格する
+#[derive(Debug, Clone, PartialEq)]
+pub enum Number {
+    /// 整数
+    Int(i64),
+    /// 既約分数で表した有理数。分母が1になることはない
+    Ratio(Rational64),
+    /// 多倍長の有理数。多倍長モードでi64に収まらなくなったときだけ使う
+    Big(BigRational),
+    /// 浮動小数点数
+    Float(f64),
+}
+
+impl Number {
+    /// 有理数から数値を作る。分母が1なら整数にする
+    fn from_ratio(r: Rational64) -> Self {
+        if r.is_integer() {
+            Number::Int(r.to_integer())
+        } else {
+            Number::Ratio(r)
+        }
+    }
+
+    /// 多倍長の有理数から数値を作る。i64に収まるなら小さい表現に戻す
+    fn from_big(r: BigRational) -> Self {
+        match (r.numer().to_i64(), r.denom().to_i64()) {
+            (Some(n), Some(d)) => Number::from_ratio(Rational64::new_raw(n, d)),
+            _ => Number::Big(r),
+        }
+    }
+
+    fn to_ratio(&self) -> Option<Rational64> {
+        match *self {
+            Number::Int(n) => Some(Rational64::from_integer(n)),
+            Number::Ratio(r) => Some(r),
+            Number::Big(_) | Number::Float(_) => None,
+        }
+    }
+
+    fn to_big(&self) -> Option<BigRational> {
+        match self {
+            Number::Int(n) => Some(BigRational::from_integer(BigInt::from(*n))),
+            Number::Ratio(r) => Some(BigRational::new_raw(
+                BigInt::from(*r.numer()),
+                BigInt::from(*r.denom()),
+            )),
+            Number::Big(r) => Some(r.clone()),
+            Number::Float(_) => None,
+        }
+    }
+
+    fn to_f64(&self) -> f64 {
+        match self {
+            Number::Int(n) => *n as f64,
+            Number::Ratio(r) => *r.numer() as f64 / *r.denom() as f64,
+            Number::Big(r) => r.to_f64().unwrap_or(f64::NAN),
+            Number::Float(f) => *f,
+        }
+    }
+
+    /// 2つの数値を比較する。NaNを含むときはNone
+    pub(crate) fn compare(&self, other: &Number) -> Option<std::cmp::Ordering> {
+        match (self, other) {
+            (Number::Float(_), _) | (_, Number::Float(_)) => {
+                self.to_f64().partial_cmp(&other.to_f64())
+            }
+            _ => match (self.to_ratio(), other.to_ratio()) {
+                (Some(l), Some(r)) => Some(l.cmp(&r)),
+                // どちらもFloatではないのでto_bigは成功する
+                _ => Some(self.to_big().unwrap().cmp(&other.to_big().unwrap())),
+            },
+        }
+    }
+
+    fn is_zero(&self) -> bool {
+        match self {
+            Number::Int(n) => *n == 0,
+            // 有理数は既約で分母が1でないので0になることはない
+            Number::Ratio(_) => false,
+            Number::Big(r) => r.is_zero(),
+            Number::Float(f) => *f == 0.0,
+        }
+    }
+}
+
+impl fmt::Display for Number {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Number::Int(n) => n.fmt(f),
+            Number::Ratio(r) => r.fmt(f),
+            Number::Big(r) => r.fmt(f),
+            // 整数と区別できるよう3.0のように小数点を残して表示する
+            Number::Float(n) => write!(f, "{:?}", n),
+        }
+    }
+}
+
+/// 評価結果の値を表すデータ型
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    /// 数値
+    Num(Number),
+    /// 真偽値
+    Bool(bool),
+}
+
+impl Value {
+    /// エラーメッセージに使う型の名前
+    fn type_name(&self) -> &'static str {
+        match self {
+            Value::Num(_) => "number",
+            Value::Bool(_) => "bool",
+        }
+    }
+
+    /// 数値を取り出す。数値でなければlocを位置とする型エラーにする
+    pub(crate) fn into_number(self, loc: &Loc) -> Result<Number, InterpreterError> {
+        match self {
+            Value::Num(n) => Ok(n),
+            v => Err(type_mismatch("number", &v, loc)),
+        }
+    }
+
+    /// 真偽値を取り出す。真偽値でなければlocを位置とする型エラーにする
+    pub(crate) fn into_bool(self, loc: &Loc) -> Result<bool, InterpreterError> {
+        match self {
+            Value::Bool(b) => Ok(b),
+            v => Err(type_mismatch("bool", &v, loc)),
+        }
+    }
+}
+
+pub(crate) fn type_mismatch(expected: &'static str, found: &Value, loc: &Loc) -> InterpreterError {
+    InterpreterError::new(
+        InterpreterErrorKind::TypeMismatch {
+            expected,
+            found: found.type_name(),
+        },
+        loc.clone(),
+    )
+}
+
+/// 二つの値が等しいか調べる。型が違えば右辺の位置で型エラーにする
+pub(crate) fn values_equal(l: &Value, r: &Value, rloc: &Loc) -> Result<bool, InterpreterError> {
+    match (l, r) {
+        (Value::Num(a), Value::Num(b)) => Ok(a.compare(b) == Some(std::cmp::Ordering::Equal)),
+        (Value::Bool(a), Value::Bool(b)) => Ok(a == b),
+        // 右辺の型を左辺にそろえることを求める
+        _ => Err(type_mismatch(l.type_name(), r, rloc)),
+    }
+}
+
+/// 大小比較の演算子で数値を比べる。NaNとの比較はすべて偽
+pub(crate) fn compare_numbers(op: &BinOpKind, a: &Number, b: &Number) -> bool {
+    use std::cmp::Ordering::*;
+    match (op, a.compare(b)) {
+        (_, None) => false,
+        (BinOpKind::Lt, Some(ord)) => ord == Less,
+        (BinOpKind::Le, Some(ord)) => ord != Greater,
+        (BinOpKind::Gt, Some(ord)) => ord == Greater,
+        (_, Some(ord)) => ord != Less,
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Value::Num(n) => n.fmt(f),
+            Value::Bool(b) => b.fmt(f),
+        }
+    }
+}
+
+/// ユーザ定義の関数
+pub(crate) struct Function {
+    params: Vec<String>,
+    body: Ast,
+}
+
+/// 組み込み関数の名前と引数の数
+pub(crate) const BUILTINS: &[(&str, usize)] =
+    &[("abs", 1), ("min", 2), ("max", 2), ("sqrt", 1), ("pow", 2)];
+
+/// 関数呼び出しの深さの上限
+pub(crate) const MAX_CALL_DEPTH: usize = 256;
+
+/// 多倍長モードのべき乗で許す結果の大きさ(ビット数)。巨大な指数で計算が終わらなくなるのを防ぐ
+pub(crate) const MAX_POW_BITS: u64 = 1 << 20;
+
+/// 評価器を表すデータ型
+pub struct Interpreter {
+    /// 変数の環境。REPLの行をまたいで保持される
+    pub(crate) env: HashMap<String, Value>,
+    /// ユーザ定義の関数
+    funcs: HashMap<String, Rc<Function>>,
+    /// 呼び出し中の関数の引数。末尾が現在の関数
+    frames: Vec<HashMap<String, Value>>,
+    /// 多倍長モード。trueならi64に収まらない結果をオーバーフローにせず正確に計算する
+    bigint: bool,
+}
+
+impl Default for Interpreter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Interpreter {
+    pub fn new() -> Self {
+        Interpreter {
+            env: HashMap::new(),
+            funcs: HashMap::new(),
+            frames: Vec::new(),
+            bigint: false,
+        }
+    }
+
+    /// 定義済みの変数と値。順序は決まっていない
+    pub fn variables(&self) -> impl Iterator<Item = (&str, &Value)> {
+        self.env.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    /// 定義済みの関数と引数の名前。順序は決まっていない
+    pub fn functions(&self) -> impl Iterator<Item = (&str, &[String])> {
+        self.funcs
+            .iter()
+            .map(|(name, f)| (name.as_str(), &f.params[..]))
+    }
+
+    /// 多倍長モードの評価器を作る
+    pub fn with_bigint() -> Self {
+        Interpreter {
+            bigint: true,
+            ..Interpreter::new()
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum InterpreterErrorKind {
+    DivisionByZero,
+    /// 束縛されていない変数を参照した
+    UnknownVariable(String),
+    /// 演算結果が数値の表現範囲に収まらない
+    Overflow,
+    /// 定義されていない関数を呼び出した
+    UnknownFunction(String),
+    /// 関数の引数の数が合わない
+    ArityMismatch {
+        name: String,
+        expected: usize,
+        found: usize,
+    },
+    /// 関数呼び出しが深くなりすぎた
+    RecursionLimit,
+    /// 関数定義を式として評価しようとした
+    NotAnExpression,
+    /// 値の型が期待と異なる
+    TypeMismatch {
+        expected: &'static str,
+        found: &'static str,
+    },
+    /// 構文エラーの箇所を評価しようとした
+    SyntaxError,
+}
+
+pub type InterpreterError = Annot<InterpreterErrorKind>;
+
+impl Interpreter {
+    /// 文を実行する。関数定義は値を持たないのでNoneを返す
+    pub fn exec(&mut self, stmt: &Ast) -> Result<Option<Value>, InterpreterError> {
+        match stmt.value {
+            AstKind::FnDef {
+                ref name,
+                ref params,
+                ref body,
+            } => {
+                let func = Function {
+                    params: params.iter().map(|p| p.value.clone()).collect(),
+                    body: (**body).clone(),
+                };
+                self.funcs.insert(name.value.clone(), Rc::new(func));
+                Ok(None)
+            }
+            _ => self.eval(stmt).map(Some),
+        }
+    }
+
+    pub fn eval(&mut self, expr: &Ast) -> Result<Value, InterpreterError> {
+        ensure_stack(|| self.eval_inner(expr))
+    }
+
+    fn eval_inner(&mut self, expr: &Ast) -> Result<Value, InterpreterError> {
+        use self::AstKind::*;
+        match expr.value {
+            // parse_partialの木には構文エラーの箇所が残っている
+            Error => Err(InterpreterError::new(
+                InterpreterErrorKind::SyntaxError,
+                expr.loc.clone(),
+            )),
+            Num(n) => self
+                .eval_num(n)
+                .map(Value::Num)
+                .map_err(|e| InterpreterError::new(e, expr.loc.clone())),
+            Float(f) => Ok(Value::Num(Number::Float(f))),
+            Bool(b) => Ok(Value::Bool(b)),
+            // 関数の中なら引数を先に探す
+            Var(ref name) => self
+                .frames
+                .last()
+                .and_then(|frame| frame.get(name))
+                .or_else(|| self.env.get(name))
+                .cloned()
+                .ok_or_else(|| {
+                    InterpreterError::new(
+                        InterpreterErrorKind::UnknownVariable(name.clone()),
+                        expr.loc.clone(),
+                    )
+                }),
+            Let { ref var, ref e } => {
+                let n = self.eval(e)?;
+                self.env.insert(var.value.clone(), n.clone());
+                Ok(n)
+            }
+            FnDef { .. } => Err(InterpreterError::new(
+                InterpreterErrorKind::NotAnExpression,
+                expr.loc.clone(),
+            )),
+            Call { ref name, ref args } => {
+                let vals = args
+                    .iter()
+                    .map(|arg| self.eval(arg))
+                    .collect::<Result<Vec<_>, _>>()?;
+                let ret = match self.funcs.get(&name.value) {
+                    Some(func) => {
+                        let func = func.clone();
+                        self.eval_call(&name.value, &func, vals)
+                    }
+                    // ユーザ定義の関数がなければ組み込み関数を探す
+                    None => {
+                        // 組み込み関数の引数はすべて数値
+                        let nums = vals
+                            .into_iter()
+                            .zip(args)
+                            .map(|(v, arg)| v.into_number(&arg.loc))
+                            .collect::<Result<Vec<_>, _>>()?;
+                        self.eval_builtin(&name.value, nums).map(Value::Num)
+                    }
+                };
+                // 関数本体で起きたエラーも呼び出し位置で報告する
+                ret.map_err(|e| InterpreterError::new(e, expr.loc.clone()))
+            }
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => {
+                // 選ばれなかった側は評価しない
+                if self.eval(cond)?.into_bool(&cond.loc)? {
+                    self.eval(then)
+                } else {
+                    self.eval(els)
+                }
+            }
+            UniOp {
+                op:
+                    Annot {
+                        value: UniOpKind::Not,
+                        ..
+                    },
+                ref e,
+            } => {
+                let b = self.eval(e)?.into_bool(&e.loc)?;
+                Ok(Value::Bool(!b))
+            }
+            UniOp { ref op, ref e } => {
+                let n = self.eval(e)?.into_number(&e.loc)?;
+                self.eval_uniop(op, n)
+                    .map(Value::Num)
+                    .map_err(|e| InterpreterError::new(e, op.loc.clone()))
+            }
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => match op.value {
+                BinOpKind::And
+                | BinOpKind::Or
+                | BinOpKind::Eq
+                | BinOpKind::Ne
+                | BinOpKind::Lt
+                | BinOpKind::Le
+                | BinOpKind::Gt
+                | BinOpKind::Ge => self.eval_logical(op, l, r).map(Value::Bool),
+                _ => {
+                    let a = self.eval(l)?.into_number(&l.loc)?;
+                    let b = self.eval(r)?.into_number(&r.loc)?;
+                    self.eval_binop(op, a, b).map(Value::Num).map_err(|e| {
+                        // オーバーフローは演算子の位置、それ以外は式全体の位置を指す
+                        let loc = match e {
+                            InterpreterErrorKind::Overflow => op.loc.clone(),
+                            _ => expr.loc.clone(),
+                        };
+                        InterpreterError::new(e, loc)
+                    })
+                }
+            },
+        }
+    }
+
+    // 論理演算と比較演算。結果は常に真偽値になる
+    fn eval_logical(&mut self, op: &BinOp, l: &Ast, r: &Ast) -> Result<bool, InterpreterError> {
+        match op.value {
+            BinOpKind::And | BinOpKind::Or => {
+                // 短絡評価。左辺だけで結果が決まれば右辺は評価しない
+                let is_and = op.value == BinOpKind::And;
+                if self.eval(l)?.into_bool(&l.loc)? != is_and {
+                    return Ok(!is_and);
+                }
+                self.eval(r)?.into_bool(&r.loc)
+            }
+            BinOpKind::Eq | BinOpKind::Ne => {
+                let lv = self.eval(l)?;
+                let rv = self.eval(r)?;
+                let eq = values_equal(&lv, &rv, &r.loc)?;
+                Ok(eq == (op.value == BinOpKind::Eq))
+            }
+            BinOpKind::Lt | BinOpKind::Le | BinOpKind::Gt | BinOpKind::Ge => {
+                let a = self.eval(l)?.into_number(&l.loc)?;
+                let b = self.eval(r)?.into_number(&r.loc)?;
+                Ok(compare_numbers(&op.value, &a, &b))
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn eval_call(
+        &mut self,
+        name: &str,
+        func: &Function,
+        args: Vec<Value>,
+    ) -> Result<Value, InterpreterErrorKind> {
+        check_arity(name, func.params.len(), args.len())?;
+        if self.frames.len() >= MAX_CALL_DEPTH {
+            return Err(InterpreterErrorKind::RecursionLimit);
+        }
+        let frame = func.params.iter().cloned().zip(args).collect();
+        self.frames.push(frame);
+        let ret = self.eval(&func.body);
+        self.frames.pop();
+        ret.map_err(|e| e.value)
+    }
+
+    pub(crate) fn eval_builtin(
+        &mut self,
+        name: &str,
+        mut args: Vec<Number>,
+    ) -> Result<Number, InterpreterErrorKind> {
+        let arity = match BUILTINS.iter().find(|(n, _)| *n == name) {
+            Some((_, arity)) => *arity,
+            None => return Err(InterpreterErrorKind::UnknownFunction(name.to_string())),
+        };
+        check_arity(name, arity, args.len())?;
+        // 引数の数は確認済みなので先頭から取り出せる
+        let mut arg = || args.remove(0);
+        match name {
+            "abs" => match arg() {
+                Number::Int(n) => match n.checked_abs() {
+                    Some(n) => Ok(Number::Int(n)),
+                    None if self.bigint => {
+                        Ok(Number::Big(BigRational::from_integer(-BigInt::from(n))))
+                    }
+                    None => Err(InterpreterErrorKind::Overflow),
+                },
+                Number::Ratio(r) => Ok(Number::Ratio(r.abs())),
+                Number::Big(r) => Ok(Number::Big(r.abs())),
+                Number::Float(f) => Ok(Number::Float(f.abs())),
+            },
+            "min" | "max" => {
+                let (l, r) = (arg(), arg());
+                let less = l.compare(&r) == Some(std::cmp::Ordering::Less);
+                Ok(if less == (name == "min") { l } else { r })
+            }
+            "sqrt" => Ok(sqrt(arg())),
+            "pow" => {
+                let (l, r) = (arg(), arg());
+                self.eval_pow(l, r)
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    pub(crate) fn eval_num(&mut self, n: u64) -> Result<Number, InterpreterErrorKind> {
+        match n.to_i64() {
+            Some(n) => Ok(Number::Int(n)),
+            None if self.bigint => Ok(Number::Big(BigRational::from_integer(n.into()))),
+            None => Err(InterpreterErrorKind::Overflow),
+        }
+    }
+
+    pub(crate) fn eval_uniop(
+        &mut self,
+        op: &UniOp,
+        n: Number,
+    ) -> Result<Number, InterpreterErrorKind> {
+        use self::UniOpKind::*;
+        let ret = match (&op.value, n) {
+            (Plus, n) => Some(n),
+            (Minus, Number::Int(n)) => n.checked_neg().map(Number::Int),
+            (Minus, Number::Ratio(r)) => r
+                .numer()
+                .checked_neg()
+                .map(|n| Number::Ratio(Rational64::new_raw(n, *r.denom()))),
+            (Minus, Number::Big(r)) => Some(Number::from_big(-r)),
+            (Minus, Number::Float(f)) => Some(Number::Float(-f)),
+            // 論理否定はevalで処理済み
+            (Not, _) => unreachable!(),
+        };
+        match ret {
+            Some(n) => Ok(n),
+            // 符号を反転して溢れるのは-2^63だけ
+            None if self.bigint => Ok(Number::Big(BigRational::from_integer(-BigInt::from(
+                i64::MIN,
+            )))),
+            None => Err(InterpreterErrorKind::Overflow),
+        }
+    }
+
+    pub(crate) fn eval_binop(
+        &mut self,
+        op: &BinOp,
+        l: Number,
+        r: Number,
+    ) -> Result<Number, InterpreterErrorKind> {
+        use self::BinOpKind::*;
+        if (op.value == Div || op.value == Mod) && r.is_zero() {
+            return Err(InterpreterErrorKind::DivisionByZero);
+        }
+        if op.value == Pow {
+            return self.eval_pow(l, r);
+        }
+        // ここに来るのは四則演算と剰余だけ。比較と論理演算はevalで処理済み
+        // 両辺を同じ種類の数値にそろえてから計算する
+        let ret = match (&l, &r) {
+            (Number::Float(_), _) | (_, Number::Float(_)) => {
+                let (l, r) = (l.to_f64(), r.to_f64());
+                let ret = match op.value {
+                    Add => l + r,
+                    Sub => l - r,
+                    Mult => l * r,
+                    Div => l / r,
+                    Mod => l % r,
+                    _ => unreachable!(),
+                };
+                return float_result(ret, l, r);
+            }
+            (&Number::Int(l), &Number::Int(r)) => match op.value {
+                Add => l.checked_add(r).map(Number::Int),
+                Sub => l.checked_sub(r).map(Number::Int),
+                Mult => l.checked_mul(r).map(Number::Int),
+                // 割り切れないときは有理数になる
+                // i64::MIN % -1は溢れるのでchecked_remで調べる
+                Div if l.checked_rem(r) == Some(0) => l.checked_div(r).map(Number::Int),
+                // i64::MINは符号を正規化するときに溢れるので多倍長に任せる
+                Div if l == i64::MIN || r == i64::MIN => None,
+                Div => Some(Number::Ratio(Rational64::new(l, r))),
+                // 剰余の符号は左辺にそろえる
+                Mod => l.checked_rem(r).map(Number::Int),
+                _ => unreachable!(),
+            },
+            (Number::Big(_), _) | (_, Number::Big(_)) => None,
+            (l, r) => {
+                // どちらもFloatでもBigでもないのでto_ratioは成功する
+                let (l, r) = (l.to_ratio().unwrap(), r.to_ratio().unwrap());
+                match op.value {
+                    Add => l.checked_add(&r),
+                    Sub => l.checked_sub(&r),
+                    Mult => l.checked_mul(&r),
+                    Div => l.checked_div(&r),
+                    // l - trunc(l / r) * r
+                    Mod => l
+                        .checked_div(&r)
+                        .and_then(|q| q.trunc().checked_mul(&r))
+                        .and_then(|m| l.checked_sub(&m)),
+                    _ => unreachable!(),
+                }
+                .map(Number::from_ratio)
+            }
+        };
+        match ret {
+            Some(n) => Ok(n),
+            None if self.bigint => {
+                // 多倍長の有理数で計算し直す。Floatはすでに処理済みなのでto_bigは成功する
+                let (l, r) = (l.to_big().unwrap(), r.to_big().unwrap());
+                Ok(Number::from_big(match op.value {
+                    Add => l + r,
+                    Sub => l - r,
+                    Mult => l * r,
+                    Div => l / r,
+                    Mod => l % r,
+                    _ => unreachable!(),
+                }))
+            }
+            None => Err(InterpreterErrorKind::Overflow),
+        }
+    }
+
+    /// べき乗を計算する。指数が整数なら正確に、そうでなければ浮動小数点数で計算する
+    fn eval_pow(&mut self, l: Number, r: Number) -> Result<Number, InterpreterErrorKind> {
+        let exp = match r {
+            Number::Int(exp) if !matches!(l, Number::Float(_)) => exp,
+            _ => {
+                let (l, r) = (l.to_f64(), r.to_f64());
+                return float_result(l.powf(r), l, r);
+            }
+        };
+        // 0の負のべき乗は0除算になる
+        if exp < 0 && l.is_zero() {
+            return Err(InterpreterErrorKind::DivisionByZero);
+        }
+        let pow = |b: Rational64| {
+            // 既約分数の分子と分母を別々にべき乗しても既約のまま
+            let e = exp.unsigned_abs() as usize;
+            let p = Rational64::new_raw(
+                num_traits::checked_pow(*b.numer(), e)?,
+                num_traits::checked_pow(*b.denom(), e)?,
+            );
+            if exp < 0 {
+                Rational64::from_integer(1).checked_div(&p)
+            } else {
+                Some(p)
+            }
+        };
+        match l.to_ratio().and_then(pow) {
+            Some(r) => Ok(Number::from_ratio(r)),
+            None if self.bigint => {
+                // Floatは処理済みなのでto_bigは成功する
+                let base = l.to_big().unwrap();
+                // 結果の大きさを分子と分母のビット数から見積もり、大きすぎれば計算しない
+                let bits = base.numer().bits().max(base.denom().bits());
+                match exp.to_i32() {
+                    Some(e) if bits.saturating_mul(e.unsigned_abs().into()) <= MAX_POW_BITS => {
+                        Ok(Number::from_big(base.pow(e)))
+                    }
+                    _ => Err(InterpreterErrorKind::Overflow),
+                }
+            }
+            None => Err(InterpreterErrorKind::Overflow),
+        }
+    }
+}
+
+pub(crate) fn check_arity(
+    name: &str,
+    expected: usize,
+    found: usize,
+) -> Result<(), InterpreterErrorKind> {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(InterpreterErrorKind::ArityMismatch {
+            name: name.to_string(),
+            expected,
+            found,
+        })
+    }
+}
+
+/// 平方根を求める。有理数の平方根が有理数になるときは正確に計算する
+pub(crate) fn sqrt(n: Number) -> Number {
+    // 平方数ならその平方根を返す
+    fn exact(n: i64) -> Option<i64> {
+        let r = (n as f64).sqrt() as i64;
+        (r.saturating_sub(1)..=r.saturating_add(1)).find(|&x| x >= 0 && x.checked_mul(x) == Some(n))
+    }
+    if let Some(r) = n.to_ratio() {
+        if let (Some(numer), Some(denom)) = (exact(*r.numer()), exact(*r.denom())) {
+            return Number::from_ratio(Rational64::new_raw(numer, denom));
+        }
+    }
+    Number::Float(n.to_f64().sqrt())
+}
+
+/// 浮動小数点数の演算結果を検査する
+pub(crate) fn float_result(ret: f64, l: f64, r: f64) -> Result<Number, InterpreterErrorKind> {
+    // 有限の値どうしの演算で無限大になったらオーバーフロー
+    if !ret.is_finite() && l.is_finite() && r.is_finite() {
+        Err(InterpreterErrorKind::Overflow)
+    } else {
+        Ok(Number::Float(ret))
+    }
+}
+
+impl fmt::Display for InterpreterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::InterpreterErrorKind::*;
+        match self.value {
+            DivisionByZero => write!(f, "division by zero"),
+            UnknownVariable(ref name) => write!(f, "unknown variable '{}'", name),
+            Overflow => write!(f, "arithmetic overflow"),
+            UnknownFunction(ref name) => write!(f, "unknown function '{}'", name),
+            ArityMismatch {
+                ref name,
+                expected,
+                found,
+            } => write!(
+                f,
+                "function '{}' takes {} argument(s) but {} were given",
+                name, expected, found
+            ),
+            RecursionLimit => write!(f, "recursion limit exceeded"),
+            NotAnExpression => write!(f, "function definition is not an expression"),
+            TypeMismatch { expected, found } => {
+                write!(f, "expected {} but found {}", expected, found)
+            }
+            SyntaxError => write!(f, "expression contains a syntax error"),
+        }
+    }
+}
+
+impl StdError for InterpreterError {
+    fn description(&self) -> &str {
+        use self::InterpreterErrorKind::*;
+        match self.value {
+            DivisionByZero => "the right hand expression of the division evaluates to zero",
+            UnknownVariable(_) => "the variable is not bound by let",
+            Overflow => "the result does not fit in a 64-bit number",
+            UnknownFunction(_) => "the function is neither defined by fn nor built in",
+            ArityMismatch { .. } => "the number of arguments does not match the definition",
+            RecursionLimit => "function calls are nested too deeply",
+            NotAnExpression => "a function definition has no value",
+            TypeMismatch { .. } => "the value has a different type than the operation requires",
+            SyntaxError => "the expression could not be parsed",
+        }
+    }
+}
+
+impl InterpreterError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        use self::InterpreterErrorKind::*;
+        let loc = self.loc.clone();
+        match self.value {
+            DivisionByZero => Diagnostic::new("E0301", self.to_string(), loc, "divided by zero"),
+            UnknownVariable(_) => {
+                Diagnostic::new("E0302", self.to_string(), loc, "not bound by let")
+            }
+            Overflow => Diagnostic::new("E0303", self.to_string(), loc, "does not fit in 64 bits")
+                .with_note("integers are computed exactly with --bigint"),
+            UnknownFunction(_) => Diagnostic::new(
+                "E0304",
+                self.to_string(),
+                loc,
+                "neither defined by fn nor built in",
+            ),
+            ArityMismatch { found, .. } => Diagnostic::new(
+                "E0305",
+                self.to_string(),
+                loc,
+                format!("called with {} argument(s)", found),
+            ),
+            RecursionLimit => Diagnostic::new(
+                "E0306",
+                self.to_string(),
+                loc,
+                format!("nested more than {} calls deep", MAX_CALL_DEPTH),
+            ),
+            NotAnExpression => Diagnostic::new("E0307", self.to_string(), loc, "has no value"),
+            TypeMismatch { expected, found } => Diagnostic::new(
+                "E0308",
+                "mismatched types",
+                loc,
+                format!("expected {}, found {}", expected, found),
+            ),
+            SyntaxError => Diagnostic::new("E0309", self.to_string(), loc, "not parsed"),
+        }
+    }
+}
+
+#[test]
+fn test_number() {
+    let mut interp = Interpreter::new();
+    let mut eval = |s: &str| interp.eval(&s.parse().unwrap()).unwrap().to_string();
+    assert_eq!(eval("6 / 3"), "2");
+    assert_eq!(eval("7 / 2"), "7/2");
+    assert_eq!(eval("7 / 2 + 1 / 2"), "4");
+    assert_eq!(eval("7 / 2 * 1.0"), "3.5");
+    assert_eq!(eval("-1 / 4 + 0.25"), "0.0");
+}
+
+#[test]
+fn test_overflow() {
+    let mut interp = Interpreter::new();
+    let mut eval = |s: &str| interp.eval(&s.parse().unwrap());
+    // 演算子の位置が報告される
+    assert_eq!(
+        eval("1 + 9223372036854775807 * 2"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::Overflow,
+            Loc(24, 25)
+        ))
+    );
+    // i64に収まらないリテラルはリテラルの位置
+    assert_eq!(
+        eval("9223372036854775808"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::Overflow,
+            Loc(0, 19)
+        ))
+    );
+    assert_eq!(
+        eval("-(-9223372036854775807 - 1)"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::Overflow,
+            Loc(0, 1)
+        ))
+    );
+    assert!(eval("1 / 4611686018427387904 + 1 / 4611686018427387905").is_err());
+    assert!(eval("1e308 * 10.0").is_err());
+    // i64::MIN / -1 と i64::MIN % -1 もパニックせずにオーバーフローになる
+    assert!(eval("(-9223372036854775807 - 1) / -1").is_err());
+    assert!(eval("(-9223372036854775807 - 1) % -1").is_err());
+
+    // 多倍長モードでは正確に計算し、収まれば元の表現に戻る
+    let mut interp = Interpreter::with_bigint();
+    let mut eval = |s: &str| interp.eval(&s.parse().unwrap()).unwrap().to_string();
+    assert_eq!(eval("9223372036854775807 * 4"), "36893488147419103228");
+    assert_eq!(eval("-(-9223372036854775807 - 1)"), "9223372036854775808");
+    assert_eq!(eval("9223372036854775808 - 1"), "9223372036854775807");
+    assert_eq!(
+        eval("1 / 4611686018427387904 + 1 / 4611686018427387905"),
+        "9223372036854775809/21267647932558653971072598982912901120"
+    );
+}
+
+#[test]
+fn test_pow() {
+    let mut interp = Interpreter::new();
+    let mut eval = |s: &str| interp.eval(&s.parse().unwrap()).map(|n| n.to_string());
+    assert_eq!(eval("-2^2"), Ok("-4".to_string()));
+    assert_eq!(eval("2^3^2"), Ok("512".to_string()));
+    assert_eq!(eval("(2/3)^-2"), Ok("9/4".to_string()));
+    assert_eq!(eval("4^0.5"), Ok("2.0".to_string()));
+    assert_eq!(eval("-7 % 3"), Ok("-1".to_string()));
+    assert_eq!(eval("7/2 % 1"), Ok("1/2".to_string()));
+    assert_eq!(
+        eval("0^-1"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::DivisionByZero,
+            Loc(0, 4)
+        ))
+    );
+    assert_eq!(
+        eval("2^63"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::Overflow,
+            Loc(1, 2)
+        ))
+    );
+}
+
+#[test]
+fn test_let() {
+    let ast = "let x = 1 + y".parse::<Ast>();
+    // 環境は評価をまたいで保持される
+    let mut interp = Interpreter::new();
+    assert_eq!(
+        interp.eval(&ast.clone().unwrap()),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::UnknownVariable("y".to_string()),
+            Loc(12, 13)
+        ))
+    );
+    interp.eval(&"let y = 2".parse().unwrap()).unwrap();
+    interp.eval(&ast.unwrap()).unwrap();
+    assert_eq!(
+        interp.eval(&"x * y".parse().unwrap()),
+        Ok(Value::Num(Number::Int(6)))
+    );
+}
+
+#[test]
+fn test_function() {
+    let mut interp = Interpreter::new();
+    let mut exec = |s: &str| interp.exec(&s.parse().unwrap());
+    assert_eq!(exec("fn sq(x) = x * x"), Ok(None));
+    assert_eq!(exec("let x = 10"), Ok(Some(Value::Num(Number::Int(10)))));
+    // 引数は同名の変数より優先される
+    assert_eq!(
+        exec("sq(3) + max(1, 2) + x"),
+        Ok(Some(Value::Num(Number::Int(21))))
+    );
+    assert_eq!(
+        exec("abs(-3/4) + min(0.5, 1) + sqrt(9/4) + pow(2, 10)"),
+        Ok(Some(Value::Num(Number::Float(1026.75))))
+    );
+    assert_eq!(
+        exec("1 + sq(1, 2)"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::ArityMismatch {
+                name: "sq".to_string(),
+                expected: 1,
+                found: 2,
+            },
+            Loc(4, 12)
+        ))
+    );
+    assert_eq!(
+        exec("cube(2)"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::UnknownFunction("cube".to_string()),
+            Loc(0, 7)
+        ))
+    );
+    exec("fn loop(n) = loop(n + 1)").unwrap();
+    assert_eq!(
+        exec("2 * loop(0)"),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::RecursionLimit,
+            Loc(4, 11)
+        ))
+    );
+}
+
+#[test]
+fn test_conditional() {
+    let mut interp = Interpreter::new();
+    interp
+        .exec(
+            &"fn fact(n) = if n <= 1 then 1 else n * fact(n - 1)"
+                .parse()
+                .unwrap(),
+        )
+        .unwrap();
+    assert_eq!(
+        interp.eval(&"fact(5)".parse().unwrap()),
+        Ok(Value::Num(Number::Int(120)))
+    );
+    assert_eq!(
+        interp.eval(&"1 + 2 < 4 && !false".parse().unwrap()),
+        Ok(Value::Bool(true))
+    );
+    assert_eq!(
+        interp.eval(&"0.5 == 1 / 2".parse().unwrap()),
+        Ok(Value::Bool(true))
+    );
+    // &&は短絡評価されるので右辺のゼロ除算は起きない
+    assert_eq!(
+        interp.eval(&"false && 1 / 0 == 0".parse().unwrap()),
+        Ok(Value::Bool(false))
+    );
+    // 型の誤りはその値の位置で報告される
+    assert_eq!(
+        interp.eval(&"1 + true".parse().unwrap()),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::TypeMismatch {
+                expected: "number",
+                found: "bool"
+            },
+            Loc(4, 8)
+        ))
+    );
+    assert_eq!(
+        interp.eval(&"if 1 then 2 else 3".parse().unwrap()),
+        Err(InterpreterError::new(
+            InterpreterErrorKind::TypeMismatch {
+                expected: "bool",
+                found: "number"
+            },
+            Loc(3, 4)
+        ))
+    );
+}
+
+/// 評価器と比べるための参照実装。整数と有理数の式を多倍長の有理数でそのまま計算する
+/// 0除算ならNoneを返す。途中の値がi64の分数で表せなければfitsをfalseにする
+#[cfg(test)]
+pub(crate) fn reference_eval(ast: &Ast, x: &BigRational, fits: &mut bool) -> Option<BigRational> {
+    use self::BinOpKind::*;
+    let ret = match ast.value {
+        AstKind::Num(n) => BigRational::from_integer(n.into()),
+        AstKind::Var(_) => x.clone(),
+        AstKind::UniOp { ref op, ref e } if op.value == UniOpKind::Minus => {
+            -reference_eval(e, x, fits)?
+        }
+        AstKind::BinOp {
+            ref op,
+            ref l,
+            ref r,
+        } => {
+            let l = reference_eval(l, x, fits)?;
+            let r = reference_eval(r, x, fits)?;
+            match op.value {
+                Add => {
+                    *fits &= fits_sum(&l, &r, false);
+                    l + r
+                }
+                Sub => {
+                    *fits &= fits_sum(&l, &r, true);
+                    l - r
+                }
+                Mult => l * r,
+                Div | Mod if r.is_zero() => return None,
+                Div => l / r,
+                // 剰余の符号は左辺にそろえる。評価器と同じく商と積も途中の値になる
+                Mod => {
+                    let q = &l / &r;
+                    let m = q.trunc() * &r;
+                    *fits &= fits_i64(&q) && fits_i64(&m) && fits_sum(&l, &m, true);
+                    &l - m
+                }
+                Pow => {
+                    let exp = r.to_integer().to_i32()?;
+                    if exp < 0 && l.is_zero() {
+                        return None;
+                    }
+                    num_traits::Pow::pow(l, exp)
+                }
+                _ => unreachable!("arb_arith_astは算術演算だけを生成する"),
+            }
+        }
+        _ => unreachable!("arb_arith_astは算術演算だけを生成する"),
+    };
+    *fits &= fits_i64(&ret);
+    Some(ret)
+}
+
+/// 既定のモードの数値、つまりi64の分数で表せるかどうか
+/// i64::MINは符号を反転すると溢れ、評価器が多倍長に回すことがあるので含めない
+#[cfg(test)]
+fn fits_i64(r: &BigRational) -> bool {
+    [r.numer(), r.denom()]
+        .iter()
+        .all(|n| n.abs().to_i64().is_some())
+}
+
+/// 既定のモードで分数を足し引きするときの途中の値、つまり通分した分母と分子がi64に収まるかどうか
+#[cfg(test)]
+fn fits_sum(l: &BigRational, r: &BigRational, sub: bool) -> bool {
+    // 分母どうしの比を約分すると、それぞれの分数に掛ける数が分かる
+    let k = BigRational::new(l.denom().clone(), r.denom().clone());
+    let lcm = l.denom() * k.denom();
+    let (a, b) = (l.numer() * k.denom(), r.numer() * k.numer());
+    let sum = if sub { &a - &b } else { &a + &b };
+    [lcm, a, b, sum].iter().all(|n| n.abs().to_i64().is_some())
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_interpreter_agrees_with_reference(
+        ast in arb_arith_ast(),
+        x in -5i64..5,
+        bigint in proptest::bool::ANY,
+    ) {
+        // 多倍長モードなら整数と有理数の演算は常に正確になる
+        // 既定のモードでは、途中の値がi64に収まらないときだけオーバーフローしてよい
+        let mut interp = if bigint {
+            Interpreter::with_bigint()
+        } else {
+            Interpreter::new()
+        };
+        interp.env.insert("x".to_string(), Value::Num(Number::Int(x)));
+        let mut fits = true;
+        let expected = reference_eval(&ast, &BigRational::from_integer(x.into()), &mut fits);
+        match interp.eval(&ast) {
+            Ok(Value::Num(n)) => {
+                proptest::prop_assert_eq!(n.to_big(), expected.clone());
+                // 表現は値に合った最小のものになっている
+                if let Some(r) = expected {
+                    proptest::prop_assert_eq!(n, Number::from_big(r));
+                }
+            }
+            Ok(v) => proptest::prop_assert!(false, "not a number: {:?}", v),
+            Err(e) if e.value == InterpreterErrorKind::Overflow => {
+                proptest::prop_assert!(!bigint && !fits, "unexpected overflow");
+            }
+            Err(e) => {
+                proptest::prop_assert_eq!(e.value, InterpreterErrorKind::DivisionByZero);
+                proptest::prop_assert_eq!(expected, None);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_pow_limit() {
+    let mut interp = Interpreter::with_bigint();
+    let mut eval = |s: &str| interp.eval(&s.parse().unwrap()).map(|v| v.to_string());
+    assert_eq!(
+        eval("2^100"),
+        Ok("1267650600228229401496703205376".to_string())
+    );
+    assert_eq!(eval("(2^500000) % 7"), Ok("4".to_string()));
+    // 結果が大きすぎるべき乗は計算せずにオーバーフローにする
+    let overflow = |r: Result<String, InterpreterError>| r.map_err(|e| e.value);
+    assert_eq!(
+        overflow(eval("2^2000000000")),
+        Err(InterpreterErrorKind::Overflow)
+    );
+    assert_eq!(
+        overflow(eval("(1/3)^-2000000")),
+        Err(InterpreterErrorKind::Overflow)
+    );
+    assert_eq!(
+        overflow(eval("(2^1000)^2000")),
+        Err(InterpreterErrorKind::Overflow)
+    );
+    // 絶対値が1以下の底は大きさが変わらないので、どんな指数でも計算できる
+    assert_eq!(eval("(-1)^2000000001"), Ok("-1".to_string()));
+    assert_eq!(eval("0^2000000000"), Ok("0".to_string()));
+}
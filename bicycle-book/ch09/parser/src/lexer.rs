@@ -0,0 +1,854 @@
+//! 字句解析器。入力文字列を位置情報付きのトークン列にする
+
+use std::borrow::Cow;
+use std::error::Error as StdError;
+use std::fmt;
+use std::iter::FusedIterator;
+
+// 位置情報。.0から.1までの区間を表す
+// たとえばLoc(4, 6)なら入力文字の5文字目から7文字目までの区間を表す(0始まり)
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Loc(pub usize, pub usize);
+
+// loc に便利メソッドを実装しておく
+impl Loc {
+    pub fn merge(&self, other: &Loc) -> Loc {
+        use std::cmp::{max, min};
+        Loc(min(self.0, other.0), max(self.1, other.1))
+    }
+}
+
+// アノテーション。値にさまざまなデータをもたせたもの。ここではLocをもたせている
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Annot<T> {
+    pub value: T,
+    pub loc: Loc,
+}
+
+impl<T> Annot<T> {
+    pub fn new(value: T, loc: Loc) -> Self {
+        Self { value, loc }
+    }
+}
+
+/// トークンの種類。識別子と演算子の名前は入力や演算子表の文字列を借用し、トークンごとに文字列を確保しない
+#[derive(Debug, Clone, PartialEq)]
+pub enum TokenKind<'a> {
+    /// [0-9][0-9_]* | 0x[0-9a-fA-F_]+ | 0o[0-7_]+ | 0b[01_]+
+    /// _は区切りで値には含めない。0x、0o、0bの後には数字が1つ以上必要
+    Number(u64),
+    /// [0-9][0-9_]*(.[0-9][0-9_]*)?([eE][+-]?[0-9][0-9_]*)?
+    Float(f64),
+    /// [a-zA-Z_][a-zA-Z0-9_]*
+    Ident(Cow<'a, str>),
+    /// let
+    Let,
+    /// fn
+    Fn,
+    /// if
+    If,
+    /// then
+    Then,
+    /// else
+    Else,
+    /// true
+    True,
+    /// false
+    False,
+    /// =
+    Equal,
+    /// ,
+    Comma,
+    Plus,
+    Minus,
+    Asterisk,
+    Slash,
+    Percent,
+    Caret,
+    /// ==
+    EqEq,
+    /// !=
+    NotEq,
+    /// <
+    Lt,
+    /// <=
+    Le,
+    /// >
+    Gt,
+    /// >=
+    Ge,
+    /// &&
+    AndAnd,
+    /// ||
+    OrOr,
+    /// !
+    Bang,
+    /// (
+    LParen,
+    /// )
+    RParen,
+    /// 演算子表に登録されたユーザ定義の演算子
+    Op(Cow<'a, str>),
+}
+
+pub type Token<'a> = Annot<TokenKind<'a>>;
+
+impl TokenKind<'_> {
+    /// 入力を借用しない種類にする。エラーに入れて入力より長く持つときに使う
+    pub fn into_owned(self) -> TokenKind<'static> {
+        use self::TokenKind::*;
+        match self {
+            Number(n) => Number(n),
+            Float(f) => Float(f),
+            Ident(name) => Ident(Cow::Owned(name.into_owned())),
+            Let => Let,
+            Fn => Fn,
+            If => If,
+            Then => Then,
+            Else => Else,
+            True => True,
+            False => False,
+            Equal => Equal,
+            Comma => Comma,
+            Plus => Plus,
+            Minus => Minus,
+            Asterisk => Asterisk,
+            Slash => Slash,
+            Percent => Percent,
+            Caret => Caret,
+            EqEq => EqEq,
+            NotEq => NotEq,
+            Lt => Lt,
+            Le => Le,
+            Gt => Gt,
+            Ge => Ge,
+            AndAnd => AndAnd,
+            OrOr => OrOr,
+            Bang => Bang,
+            LParen => LParen,
+            RParen => RParen,
+            Op(symbol) => Op(Cow::Owned(symbol.into_owned())),
+        }
+    }
+}
+
+// ヘルパーメソッドを定義しておく
+impl<'a> Token<'a> {
+    /// 入力を借用しないトークンにする
+    pub fn into_owned(self) -> Token<'static> {
+        Token::new(self.value.into_owned(), self.loc)
+    }
+
+    pub(crate) fn number(n: u64, loc: Loc) -> Self {
+        Self::new(TokenKind::Number(n), loc)
+    }
+    pub(crate) fn float(f: f64, loc: Loc) -> Self {
+        Self::new(TokenKind::Float(f), loc)
+    }
+
+    pub(crate) fn ident(name: &'a str, loc: Loc) -> Self {
+        Self::new(TokenKind::Ident(Cow::Borrowed(name)), loc)
+    }
+
+    fn let_(loc: Loc) -> Self {
+        Self::new(TokenKind::Let, loc)
+    }
+
+    fn fn_(loc: Loc) -> Self {
+        Self::new(TokenKind::Fn, loc)
+    }
+
+    fn comma(loc: Loc) -> Self {
+        Self::new(TokenKind::Comma, loc)
+    }
+
+    pub(crate) fn plus(loc: Loc) -> Self {
+        Self::new(TokenKind::Plus, loc)
+    }
+
+    pub(crate) fn minus(loc: Loc) -> Self {
+        Self::new(TokenKind::Minus, loc)
+    }
+
+    pub(crate) fn asterisk(loc: Loc) -> Self {
+        Self::new(TokenKind::Asterisk, loc)
+    }
+
+    fn slash(loc: Loc) -> Self {
+        Self::new(TokenKind::Slash, loc)
+    }
+
+    fn percent(loc: Loc) -> Self {
+        Self::new(TokenKind::Percent, loc)
+    }
+
+    fn caret(loc: Loc) -> Self {
+        Self::new(TokenKind::Caret, loc)
+    }
+
+    pub(crate) fn lparen(loc: Loc) -> Self {
+        Self::new(TokenKind::LParen, loc)
+    }
+
+    pub(crate) fn rparen(loc: Loc) -> Self {
+        Self::new(TokenKind::RParen, loc)
+    }
+
+    pub(crate) fn op(symbol: &'a str, loc: Loc) -> Self {
+        Self::new(TokenKind::Op(Cow::Borrowed(symbol)), loc)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[non_exhaustive]
+pub enum LexErrorKind {
+    InvalidChar(char),
+    Eof,
+    /// 基数に合わない数字。`0b102`の`2`など
+    InvalidDigitForRadix {
+        digit: char,
+        radix: u32,
+    },
+    /// `0x`などの接頭辞の後に数字がない
+    MissingDigits {
+        radix: u32,
+    },
+    /// 整数がu64に、小数がf64に収まらない
+    NumberTooLarge,
+    /// `/*`に対応する`*/`がない
+    UnterminatedComment,
+}
+
+pub type LexError = Annot<LexErrorKind>;
+
+impl LexError {
+    pub(crate) fn invalid_char(c: char, loc: Loc) -> Self {
+        LexError::new(LexErrorKind::InvalidChar(c), loc)
+    }
+
+    fn eof(loc: Loc) -> Self {
+        LexError::new(LexErrorKind::Eof, loc)
+    }
+
+    fn too_large(loc: Loc) -> Self {
+        LexError::new(LexErrorKind::NumberTooLarge, loc)
+    }
+}
+
+// 字句解析器
+pub fn lex(input: &str) -> Result<Vec<Token<'_>>, LexError> {
+    lex_with(input, &[])
+}
+
+/// ユーザ定義の演算子の記号も認識する字句解析器
+/// customは長い記号が先に来るように並んでいること
+pub(crate) fn lex_with<'a>(
+    input: &'a str,
+    custom: &'a [String],
+) -> Result<Vec<Token<'a>>, LexError> {
+    // 最初のエラーで止まり、そのエラーを返す
+    Lexer::with_custom(input, custom).collect()
+}
+
+/// 入力を先頭から読み、トークンを1つずつ返す字句解析器
+/// 構文解析器はトークン列をベクタに集めずに必要な分だけ取り出す。エラーを返した後は何も返さない
+#[derive(Debug, Clone)]
+pub struct Lexer<'a> {
+    // 入力
+    input: &'a [u8],
+    // 次に読む位置
+    pos: usize,
+    // ユーザ定義の演算子の記号。長いものから順に並んでいる
+    custom: &'a [String],
+    // エラーを返したかどうか
+    failed: bool,
+}
+
+impl<'a> Lexer<'a> {
+    pub fn new(input: &'a str) -> Self {
+        Self::with_custom(input, &[])
+    }
+
+    pub(crate) fn with_custom(input: &'a str, custom: &'a [String]) -> Self {
+        Lexer {
+            input: input.as_bytes(),
+            pos: 0,
+            custom,
+            failed: false,
+        }
+    }
+
+    /// 次のトークンを読み、その終わりの位置とともに返す。入力が終われば(空白だけならそれも読み飛ばして)None
+    fn lex_token(&mut self) -> Option<Result<(Token<'a>, usize), LexError>> {
+        let input = self.input;
+        loop {
+            let pos = self.pos;
+            if pos >= input.len() {
+                return None;
+            }
+            // コメントは空白と同じく読み飛ばす
+            match skip_comment(input, pos) {
+                Some(Ok(end)) => {
+                    self.pos = end;
+                    continue;
+                }
+                Some(Err(e)) => return Some(Err(e)),
+                None => {}
+            }
+            // ユーザ定義の演算子は長い記号から試しているので、最初に一致したものが最長
+            let custom = self
+                .custom
+                .iter()
+                .find(|sym| input[pos..].starts_with(sym.as_bytes()));
+            let result = match input[pos] {
+                b'0'..=b'9' => lex_number(input, pos),
+                b'a'..=b'z' | b'A'..=b'Z' | b'_' => lex_ident(input, pos),
+                b'=' => lex_one_or_two(input, pos, b'=', TokenKind::Equal, TokenKind::EqEq),
+                b'!' => lex_one_or_two(input, pos, b'!', TokenKind::Bang, TokenKind::NotEq),
+                b'<' => lex_one_or_two(input, pos, b'<', TokenKind::Lt, TokenKind::Le),
+                b'>' => lex_one_or_two(input, pos, b'>', TokenKind::Gt, TokenKind::Ge),
+                b'&' => lex_twice(input, pos, b'&', TokenKind::AndAnd),
+                b'|' => lex_twice(input, pos, b'|', TokenKind::OrOr),
+                b',' => lex_comma(input, pos),
+                b'+' => lex_plus(input, pos),
+                b'-' => lex_minus(input, pos),
+                b'*' => lex_asterisk(input, pos),
+                b'/' => lex_slash(input, pos),
+                b'%' => lex_percent(input, pos),
+                b'^' => lex_caret(input, pos),
+                b'(' => lex_lparen(input, pos),
+                b')' => lex_rparen(input, pos),
+                // 空白を扱う。トークンは返さずに続きを読む
+                b' ' | b'\n' | b'\t' => match skip_spaces(input, pos) {
+                    Ok(((), p)) => {
+                        self.pos = p;
+                        continue;
+                    }
+                    Err(e) => Err(e),
+                },
+                // それ以外が来たらエラー
+                _ => Err(invalid_char_at(input, pos)),
+            };
+            // 組み込みの記号と比べても最長一致にする。&を登録しても&&は&&のまま読む
+            // 同じ長さならユーザ定義の演算子を優先する
+            if let Some(sym) = custom {
+                let end = pos + sym.len();
+                if !matches!(result, Ok((_, builtin_end)) if builtin_end > end) {
+                    return Some(Ok((Token::op(sym, Loc(pos, end)), end)));
+                }
+            }
+            return Some(result);
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Result<Token<'a>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.lex_token()? {
+            Ok((tok, end)) => {
+                self.pos = end;
+                Some(Ok(tok))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+impl FusedIterator for Lexer<'_> {}
+
+/// posから始まる文字を不正な文字としてエラーにする。位置はUTF-8の1文字全体を指す
+pub(crate) fn invalid_char_at(input: &[u8], pos: usize) -> LexError {
+    // 先頭のバイトから文字のバイト数がわかる
+    let len = match input[pos] {
+        0x00..=0x7f => 1,
+        0xc0..=0xdf => 2,
+        0xe0..=0xef => 3,
+        _ => 4,
+    };
+    let end = (pos + len).min(input.len());
+    let c = std::str::from_utf8(&input[pos..end])
+        .ok()
+        .and_then(|s| s.chars().next())
+        .unwrap_or(char::REPLACEMENT_CHARACTER);
+    LexError::invalid_char(c, Loc(pos, end))
+}
+
+/// posのバイトが期待するものであれば1バイト消費してposを1進める
+pub(crate) fn consume_byte(input: &[u8], pos: usize, b: u8) -> Result<(u8, usize), LexError> {
+    // postが入力サイズ以上なら入力が終わっている
+    // 1バイト期待しているのに終わっているのでエラー
+    if input.len() <= pos {
+        return Err(LexError::eof(Loc(pos, pos)));
+    }
+    // 入力が期待するものでなければエラー
+    if input[pos] != b {
+        return Err(invalid_char_at(input, pos));
+    }
+
+    Ok((b, pos + 1))
+}
+
+/// bの後に"="が続けば2文字のトークンtwo、続かなければ1文字のトークンoneとして読む
+pub(crate) fn lex_one_or_two(
+    input: &[u8],
+    start: usize,
+    b: u8,
+    one: TokenKind<'static>,
+    two: TokenKind<'static>,
+) -> Result<(Token<'static>, usize), LexError> {
+    let (_, end) = consume_byte(input, start, b)?;
+    match consume_byte(input, end, b'=') {
+        Ok((_, end)) => Ok((Token::new(two, Loc(start, end)), end)),
+        Err(_) => Ok((Token::new(one, Loc(start, end)), end)),
+    }
+}
+
+/// bが2つ続く記号を読む
+pub(crate) fn lex_twice(
+    input: &[u8],
+    start: usize,
+    b: u8,
+    kind: TokenKind<'static>,
+) -> Result<(Token<'static>, usize), LexError> {
+    let (_, end) = consume_byte(input, start, b)?;
+    let (_, end) = consume_byte(input, end, b)?;
+    Ok((Token::new(kind, Loc(start, end)), end))
+}
+
+pub(crate) fn lex_comma(input: &[u8], start: usize) -> Result<(Token<'static>, usize), LexError> {
+    consume_byte(input, start, b',').map(|(_, end)| (Token::comma(Loc(start, end)), end))
+}
+
+pub(crate) fn lex_plus(input: &[u8], start: usize) -> Result<(Token<'static>, usize), LexError> {
+    // Result::mapを使うことで結果が正常だった場合の処理を簡潔に書ける
+    // これはこのコードと等価
+    // ```
+    // match consume_byte(input, start, b'+') {
+    //     Ok((_, end)) => Ok((Token::plus(Loc(start, end)), end)),
+    //     Err(err) => Err(err),
+    // }
+    // ```
+    consume_byte(input, start, b'+').map(|(_, end)| (Token::plus(Loc(start, end)), end))
+}
+
+pub(crate) fn lex_minus(input: &[u8], start: usize) -> Result<(Token<'static>, usize), LexError> {
+    consume_byte(input, start, b'-').map(|(_, end)| (Token::minus(Loc(start, end)), end))
+}
+
+pub(crate) fn lex_asterisk(
+    input: &[u8],
+    start: usize,
+) -> Result<(Token<'static>, usize), LexError> {
+    consume_byte(input, start, b'*').map(|(_, end)| (Token::asterisk(Loc(start, end)), end))
+}
+
+pub(crate) fn lex_slash(input: &[u8], start: usize) -> Result<(Token<'static>, usize), LexError> {
+    consume_byte(input, start, b'/').map(|(_, end)| (Token::slash(Loc(start, end)), end))
+}
+
+pub(crate) fn lex_percent(input: &[u8], start: usize) -> Result<(Token<'static>, usize), LexError> {
+    consume_byte(input, start, b'%').map(|(_, end)| (Token::percent(Loc(start, end)), end))
+}
+
+pub(crate) fn lex_caret(input: &[u8], start: usize) -> Result<(Token<'static>, usize), LexError> {
+    consume_byte(input, start, b'^').map(|(_, end)| (Token::caret(Loc(start, end)), end))
+}
+
+pub(crate) fn lex_lparen(input: &[u8], start: usize) -> Result<(Token<'static>, usize), LexError> {
+    consume_byte(input, start, b'(').map(|(_, end)| (Token::lparen(Loc(start, end)), end))
+}
+
+pub(crate) fn lex_rparen(input: &[u8], start: usize) -> Result<(Token<'static>, usize), LexError> {
+    consume_byte(input, start, b')').map(|(_, end)| (Token::rparen(Loc(start, end)), end))
+}
+
+pub(crate) fn lex_number(input: &[u8], pos: usize) -> Result<(Token<'static>, usize), LexError> {
+    // 0x、0o、0bで始まる整数
+    let radix = match input.get(pos + 1) {
+        Some(b'x') if input[pos] == b'0' => Some(16),
+        Some(b'o') if input[pos] == b'0' => Some(8),
+        Some(b'b') if input[pos] == b'0' => Some(2),
+        _ => None,
+    };
+    if let Some(radix) = radix {
+        return lex_radix_number(input, pos, radix);
+    }
+
+    // 数字の間には_を挟んで区切ってよい
+    let is_digit = |b: u8| b.is_ascii_digit() || b == b'_';
+    let start = pos;
+    let mut end = recognize_many(input, start, is_digit);
+    let mut is_float = false;
+    // 小数部。"."の直後に数字が続くときだけ読む
+    if input.get(end) == Some(&b'.') && input.get(end + 1).is_some_and(u8::is_ascii_digit) {
+        end = recognize_many(input, end + 1, is_digit);
+        is_float = true;
+    }
+    // 指数部。"e"の後に(符号と)数字が続くときだけ読む
+    if let Some(b'e') | Some(b'E') = input.get(end) {
+        let digits = match input.get(end + 1) {
+            Some(b'+') | Some(b'-') => end + 2,
+            _ => end + 1,
+        };
+        if input.get(digits).is_some_and(u8::is_ascii_digit) {
+            end = recognize_many(input, digits, is_digit);
+            is_float = true;
+        }
+    }
+    let loc = Loc(start, end);
+    // 区切りの_を除いた文字列を数値に変換する。区切りがなければ入力をそのまま使う
+    let digits = &input[start..end];
+    let s: Cow<str> = if digits.contains(&b'_') {
+        Cow::Owned(
+            digits
+                .iter()
+                .filter(|&&b| b != b'_')
+                .map(|&b| b as char)
+                .collect(),
+        )
+    } else {
+        // 数字と.、e、符号だけを読んでいるのでfrom_utf8は常に成功する
+        Cow::Borrowed(std::str::from_utf8(digits).unwrap())
+    };
+    let tok = if is_float {
+        // 大きすぎる小数は無限大になるのでエラーにする
+        match s.parse::<f64>() {
+            Ok(f) if f.is_finite() => Token::float(f, loc),
+            _ => return Err(LexError::too_large(loc)),
+        }
+    } else {
+        match s.parse() {
+            Ok(n) => Token::number(n, loc),
+            // 数字だけを集めているので、失敗するのはu64に収まらないときだけ
+            Err(_) => return Err(LexError::too_large(loc)),
+        }
+    };
+    Ok((tok, end))
+}
+
+/// 0x、0o、0bで始まる整数を読む。英数字の続く限りを1つのリテラルとし、基数に合わない数字はエラーにする
+pub(crate) fn lex_radix_number(
+    input: &[u8],
+    start: usize,
+    radix: u32,
+) -> Result<(Token<'static>, usize), LexError> {
+    // 接頭辞の2文字を飛ばす
+    let digits = start + 2;
+    let end = recognize_many(input, digits, |b| b.is_ascii_alphanumeric() || b == b'_');
+    let mut value = String::new();
+    for (i, &b) in input[digits..end].iter().enumerate() {
+        let c = b as char;
+        if c.is_digit(radix) {
+            value.push(c);
+        } else if c != '_' {
+            let kind = LexErrorKind::InvalidDigitForRadix { digit: c, radix };
+            let at = digits + i;
+            return Err(LexError::new(kind, Loc(at, at + 1)));
+        }
+    }
+    let loc = Loc(start, end);
+    if value.is_empty() {
+        return Err(LexError::new(LexErrorKind::MissingDigits { radix }, loc));
+    }
+    match u64::from_str_radix(&value, radix) {
+        Ok(n) => Ok((Token::number(n, loc.clone()), end)),
+        Err(_) => Err(LexError::too_large(loc)),
+    }
+}
+
+pub(crate) fn lex_ident(input: &[u8], pos: usize) -> Result<(Token<'_>, usize), LexError> {
+    use std::str::from_utf8;
+
+    let start = pos;
+    let end = recognize_many(input, start, |b| b.is_ascii_alphanumeric() || b == b'_');
+    // 英数字とアンダースコアだけを読んでいるのでfrom_utf8は常に成功する
+    let s = from_utf8(&input[start..end]).unwrap();
+    // キーワードは識別子として扱わない
+    let tok = match s {
+        "let" => Token::let_(Loc(start, end)),
+        "fn" => Token::fn_(Loc(start, end)),
+        "if" => Token::new(TokenKind::If, Loc(start, end)),
+        "then" => Token::new(TokenKind::Then, Loc(start, end)),
+        "else" => Token::new(TokenKind::Else, Loc(start, end)),
+        "true" => Token::new(TokenKind::True, Loc(start, end)),
+        "false" => Token::new(TokenKind::False, Loc(start, end)),
+        _ => Token::ident(s, Loc(start, end)),
+    };
+    Ok((tok, end))
+}
+
+pub(crate) fn skip_spaces(input: &[u8], pos: usize) -> Result<((), usize), LexError> {
+    let pos = recognize_many(input, pos, |b| b" \n\t".contains(&b));
+    Ok(((), pos))
+}
+
+/// posから始まるコメントを読み飛ばし、コメントの終わりの位置を返す。コメントでなければNone
+/// `#`から行末まで(改行は含まない)と、`/*`から`*/`までがコメント。ブロックコメントは入れ子にできない
+pub(crate) fn skip_comment(input: &[u8], pos: usize) -> Option<Result<usize, LexError>> {
+    let rest = &input[pos..];
+    if rest.starts_with(b"#") {
+        Some(Ok(recognize_many(input, pos, |b| b != b'\n')))
+    } else if rest.starts_with(b"/*") {
+        let end = rest[2..]
+            .windows(2)
+            .position(|w| w == b"*/")
+            .map(|i| pos + i + 4);
+        // 閉じていないときは開きの/*を指す
+        let loc = Loc(pos, pos + 2);
+        Some(end.ok_or_else(|| LexError::new(LexErrorKind::UnterminatedComment, loc)))
+    } else {
+        None
+    }
+}
+
+/// 入力の中のコメントの区間を順に返す。閉じていないブロックコメントは入力の終わりまでとする
+pub(crate) fn comments(src: &str) -> Vec<Loc> {
+    let input = src.as_bytes();
+    let mut spans = Vec::new();
+    let mut pos = 0;
+    while pos < input.len() {
+        match skip_comment(input, pos) {
+            Some(end) => {
+                let end = end.unwrap_or(input.len());
+                spans.push(Loc(pos, end));
+                pos = end;
+            }
+            None => pos += 1,
+        }
+    }
+    spans
+}
+
+pub(crate) fn recognize_many(input: &[u8], mut pos: usize, mut f: impl FnMut(u8) -> bool) -> usize {
+    while pos < input.len() && f(input[pos]) {
+        pos += 1;
+    }
+    pos
+}
+
+#[test]
+fn test_lexer() {
+    assert_eq!(
+        lex("1 + 2 * 3 - -10"),
+        Ok(vec![
+            Token::number(1, Loc(0, 1)),
+            Token::plus(Loc(2, 3)),
+            Token::number(2, Loc(4, 5)),
+            Token::asterisk(Loc(6, 7)),
+            Token::number(3, Loc(8, 9)),
+            Token::minus(Loc(10, 11)),
+            Token::minus(Loc(12, 13)),
+            Token::number(10, Loc(13, 15)),
+        ])
+    );
+}
+
+#[test]
+fn test_lexer_literals() {
+    let lex_one = |s: &'static str| lex(s).map(|tokens| tokens[0].value.clone());
+    assert_eq!(lex_one("0xff"), Ok(TokenKind::Number(255)));
+    assert_eq!(lex_one("0o17"), Ok(TokenKind::Number(15)));
+    assert_eq!(lex_one("0b1010"), Ok(TokenKind::Number(10)));
+    assert_eq!(lex_one("0xdead_BEEF"), Ok(TokenKind::Number(0xdead_beef)));
+    assert_eq!(lex_one("1_000"), Ok(TokenKind::Number(1000)));
+    assert_eq!(lex_one("1_000.5"), Ok(TokenKind::Float(1000.5)));
+
+    let kind = |s: &'static str| lex(s).map_err(|e| (e.value, e.loc));
+    assert_eq!(
+        kind("1 + 0b102"),
+        Err((
+            LexErrorKind::InvalidDigitForRadix {
+                digit: '2',
+                radix: 2
+            },
+            Loc(8, 9)
+        ))
+    );
+    assert_eq!(
+        kind("0x"),
+        Err((LexErrorKind::MissingDigits { radix: 16 }, Loc(0, 2)))
+    );
+    assert_eq!(
+        kind("18446744073709551616"),
+        Err((LexErrorKind::NumberTooLarge, Loc(0, 20)))
+    );
+    assert_eq!(
+        kind("0x1_0000_0000_0000_0000"),
+        Err((LexErrorKind::NumberTooLarge, Loc(0, 23)))
+    );
+    assert_eq!(
+        kind("1e999"),
+        Err((LexErrorKind::NumberTooLarge, Loc(0, 5)))
+    );
+    assert_eq!(
+        lex_one("18446744073709551615"),
+        Ok(TokenKind::Number(u64::MAX))
+    );
+}
+
+#[test]
+fn test_lexer_comments() {
+    assert_eq!(
+        lex("1 # c ( ;\n+ /* a\n * b */ 2 #"),
+        Ok(vec![
+            Token::number(1, Loc(0, 1)),
+            Token::plus(Loc(10, 11)),
+            Token::number(2, Loc(25, 26)),
+        ])
+    );
+    // ブロックコメントは入れ子にできない
+    assert_eq!(lex("/* /* */ 1 */").map(|tokens| tokens.len()), Ok(3));
+    assert_eq!(
+        lex("1 /* 2"),
+        Err(LexError::new(LexErrorKind::UnterminatedComment, Loc(2, 4)))
+    );
+    assert_eq!(lex("# only a comment"), Ok(vec![]));
+}
+
+#[test]
+fn test_lexer_stream() {
+    // 必要な分だけ読み、最初のエラーの後は何も返さない
+    let mut lexer = Lexer::new("1 + $ 2 €");
+    assert_eq!(lexer.next(), Some(Ok(Token::number(1, Loc(0, 1)))));
+    assert_eq!(lexer.next(), Some(Ok(Token::plus(Loc(2, 3)))));
+    assert_eq!(
+        lexer.next(),
+        Some(Err(LexError::invalid_char('$', Loc(4, 5))))
+    );
+    assert_eq!(lexer.next(), None);
+    assert_eq!(lexer.next(), None);
+
+    // 空白だけの入力は何も返さない
+    assert_eq!(Lexer::new(" \n\t").next(), None);
+    assert_eq!(
+        Lexer::new("1 2 €").collect::<Result<Vec<_>, _>>(),
+        Err(LexError::invalid_char('€', Loc(4, 7)))
+    );
+}
+
+#[test]
+fn test_lexer_borrows_input() {
+    // 識別子は入力の一部を、演算子は演算子表の記号をそのまま指す
+    let input = "abc ** d";
+    let custom = ["**".to_string()];
+    let tokens = lex_with(input, &custom).unwrap();
+    let ptrs: Vec<_> = tokens
+        .iter()
+        .map(|tok| match tok.value {
+            TokenKind::Ident(Cow::Borrowed(s)) | TokenKind::Op(Cow::Borrowed(s)) => s.as_ptr(),
+            ref other => panic!("not borrowed: {:?}", other),
+        })
+        .collect();
+    assert_eq!(
+        ptrs,
+        [input.as_ptr(), custom[0].as_ptr(), input[7..].as_ptr()]
+    );
+    // エラーに入れるトークンは入力から切り離す
+    let owned = tokens[0].clone().into_owned();
+    assert!(matches!(owned.value, TokenKind::Ident(Cow::Owned(_))));
+    assert_eq!(owned, tokens[0]);
+}
+
+/// まだ閉じていない括弧の数。REPLはこれが0になるまで続きの行を読む
+/// 閉じていないブロックコメントも1つと数える。それ以外の字句解析できない入力は
+/// 続けても直らないので0とする
+pub(crate) fn unclosed_parens(input: &str, custom: &[String]) -> usize {
+    let mut depth = 0usize;
+    for tok in Lexer::with_custom(input, custom) {
+        match tok {
+            Ok(tok) => match tok.value {
+                TokenKind::LParen => depth += 1,
+                // 余分な閉じ括弧は構文解析でエラーにする
+                TokenKind::RParen => depth = depth.saturating_sub(1),
+                _ => {}
+            },
+            Err(e) if e.value == LexErrorKind::UnterminatedComment => return depth + 1,
+            Err(_) => return 0,
+        }
+    }
+    depth
+}
+
+#[test]
+fn test_unclosed_parens() {
+    assert_eq!(unclosed_parens("1 + 2", &[]), 0);
+    assert_eq!(unclosed_parens("f((1 +", &[]), 2);
+    assert_eq!(unclosed_parens("(1 +\n2) * (3", &[]), 1);
+    assert_eq!(unclosed_parens("1) + (2", &[]), 1);
+    assert_eq!(unclosed_parens("(1 + $", &[]), 0);
+    // コメントの中の括弧は数えない
+    assert_eq!(unclosed_parens("1 # (", &[]), 0);
+    assert_eq!(unclosed_parens("(1 /* ) */", &[]), 1);
+    assert_eq!(unclosed_parens("1 + /* (", &[]), 1);
+}
+
+impl fmt::Display for TokenKind<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::TokenKind::*;
+        match self {
+            Number(n) => n.fmt(f),
+            Float(n) => n.fmt(f),
+            Ident(name) => name.fmt(f),
+            Let => write!(f, "let"),
+            Fn => write!(f, "fn"),
+            If => write!(f, "if"),
+            Then => write!(f, "then"),
+            Else => write!(f, "else"),
+            True => write!(f, "true"),
+            False => write!(f, "false"),
+            Equal => write!(f, "="),
+            Comma => write!(f, ","),
+            Plus => write!(f, "+"),
+            Minus => write!(f, "-"),
+            Asterisk => write!(f, "*"),
+            Slash => write!(f, "/"),
+            Percent => write!(f, "%"),
+            Caret => write!(f, "^"),
+            EqEq => write!(f, "=="),
+            NotEq => write!(f, "!="),
+            Lt => write!(f, "<"),
+            Le => write!(f, "<="),
+            Gt => write!(f, ">"),
+            Ge => write!(f, ">="),
+            AndAnd => write!(f, "&&"),
+            OrOr => write!(f, "||"),
+            Bang => write!(f, "!"),
+            LParen => write!(f, "("),
+            RParen => write!(f, ")"),
+            Op(s) => s.fmt(f),
+        }
+    }
+}
+
+impl fmt::Display for Loc {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}-{}", self.0, self.1)
+    }
+}
+
+impl fmt::Display for LexError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::LexErrorKind::*;
+        let loc = &self.loc;
+        match self.value {
+            InvalidChar(c) => write!(f, "{}: invalid char '{}'", loc, c),
+            Eof => write!(f, "End of file"),
+            InvalidDigitForRadix { digit, radix } => {
+                write!(f, "{}: invalid digit '{}' for base {}", loc, digit, radix)
+            }
+            MissingDigits { radix } => write!(f, "{}: no digits in a base {} literal", loc, radix),
+            NumberTooLarge => write!(f, "{}: number is too large", loc),
+            UnterminatedComment => write!(f, "{}: unterminated block comment", loc),
+        }
+    }
+}
+
+impl StdError for LexError {}
@@ -0,0 +1,38 @@
+//! 四則演算を中心とした小さな式言語の字句解析器・構文解析器・評価器
+//!
+//! 文字列は`str::parse`で構文木にでき、`Interpreter`でそのまま評価できる。
+//!
+//! ```
+//! use parser::{Ast, Interpreter};
+//!
+//! let ast = "1 + 2 * 3".parse::<Ast>().unwrap();
+//! let value = Interpreter::new().eval(&ast).unwrap();
+//! assert_eq!(value.to_string(), "7");
+//! ```
+//!
+//! 失敗したときは`Error`や`InterpreterError`が返る。どちらも`diagnostic`で
+//! 表示用の`Diagnostic`に変換できる。
+
+pub mod ast;
+pub mod compile;
+pub mod diagnostics;
+pub mod diff;
+pub mod format;
+pub mod interp;
+pub mod lexer;
+pub mod optimize;
+pub mod parser;
+pub mod trace;
+pub mod typeck;
+
+pub use crate::ast::{Ast, AstKind, BinOp, BinOpKind, UniOp, UniOpKind};
+pub use crate::compile::Emitter;
+pub use crate::diagnostics::{Diagnostic, ErrorFormat, Label, SourceMap};
+pub use crate::diff::{derivative, DiffError, DiffErrorKind};
+pub use crate::format::{format_source, AstFormatter};
+pub use crate::interp::{Interpreter, InterpreterError, InterpreterErrorKind, Number, Value};
+pub use crate::lexer::{lex, Annot, LexError, LexErrorKind, Lexer, Loc, Token, TokenKind};
+pub use crate::optimize::Optimizer;
+pub use crate::parser::{parse, split_statements, Assoc, Error, OperatorTable, ParseError};
+pub use crate::trace::{Trace, TraceStep, Tracer};
+pub use crate::typeck::{Type, TypeChecker, TypeError, TypeErrorKind};
@@ -0,0 +1,327 @@
+//! 定数畳み込みと代数的な簡約
+
+#[cfg(test)]
+use crate::ast::arb_ast;
+use crate::ast::{ensure_stack, Ast, AstKind, BinOp, BinOpKind, UniOp, UniOpKind};
+use crate::interp::{Interpreter, Number, Value};
+use crate::lexer::{Annot, Loc};
+#[cfg(test)]
+use crate::typeck::TypeChecker;
+use num_traits::ToPrimitive;
+
+/// 定数畳み込みと代数的な簡約を行う最適化器を表すデータ型
+/// 置き換えた式には元の式の位置をそのまま持たせる
+pub struct Optimizer;
+
+impl Default for Optimizer {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Optimizer {
+    pub fn new() -> Self {
+        Optimizer
+    }
+
+    pub fn optimize(&mut self, expr: &Ast) -> Ast {
+        self.optimize_node(expr).0
+    }
+
+    /// 最適化した式と、それがリテラルと演算だけでできているかを返す
+    /// 定数かどうかを子から積み上げて求め、長い連鎖で木を何度もたどり直さないようにする
+    fn optimize_node(&mut self, expr: &Ast) -> (Ast, bool) {
+        ensure_stack(|| self.optimize_inner(expr))
+    }
+
+    fn optimize_inner(&mut self, expr: &Ast) -> (Ast, bool) {
+        use self::AstKind::*;
+        let loc = expr.loc.clone();
+        let (optimized, constant) = match expr.value {
+            Num(_) | Float(_) | Bool(_) => return (expr.clone(), true),
+            Var(_) | Error => return (expr.clone(), false),
+            Let { ref var, ref e } => (Ast::let_(var.clone(), self.optimize(e), loc), false),
+            FnDef {
+                ref name,
+                ref params,
+                ref body,
+            } => (
+                Ast::fn_def(name.clone(), params.clone(), self.optimize(body), loc),
+                false,
+            ),
+            // 関数呼び出しは後から定義される関数に置き換わりうるので定数に含めない
+            Call { ref name, ref args } => {
+                let args = args.iter().map(|arg| self.optimize(arg)).collect();
+                (Ast::call(name.clone(), args, loc), false)
+            }
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => {
+                let (cond, cond_constant) = self.optimize_node(cond);
+                // 条件が定数なら選ばれる枝だけを残す
+                let branch = match cond.value {
+                    Bool(true) => Some(then),
+                    Bool(false) => Some(els),
+                    _ => None,
+                };
+                if let Some(branch) = branch {
+                    let (branch, constant) = self.optimize_node(branch);
+                    return (relocate(branch, loc), constant);
+                }
+                let (then, then_constant) = self.optimize_node(then);
+                let (els, els_constant) = self.optimize_node(els);
+                (
+                    Ast::if_(cond, then, els, loc),
+                    cond_constant && then_constant && els_constant,
+                )
+            }
+            // 簡約は子や孫を組み替えるだけなので、定数かどうかは子から決まる
+            UniOp { ref op, ref e } => {
+                let (e, constant) = self.optimize_node(e);
+                (self.simplify_uniop(op, e, loc), constant)
+            }
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => {
+                let (l, l_constant) = self.optimize_node(l);
+                let (r, r_constant) = self.optimize_node(r);
+                (self.simplify_binop(op, l, r, loc), l_constant && r_constant)
+            }
+        };
+        if constant {
+            (fold(optimized), true)
+        } else {
+            (optimized, false)
+        }
+    }
+
+    fn simplify_uniop(&mut self, op: &UniOp, e: Ast, loc: Loc) -> Ast {
+        match (&op.value, e.value) {
+            // +x => x
+            (UniOpKind::Plus, e) => Ast::new(e, loc),
+            // --x => x。-2^63の符号の反転は溢れるので、そうならない式だけ
+            (
+                UniOpKind::Minus,
+                AstKind::UniOp {
+                    op:
+                        Annot {
+                            value: UniOpKind::Minus,
+                            ..
+                        },
+                    e,
+                },
+            ) if can_negate(&e) => relocate(*e, loc),
+            // !!x => x
+            (
+                UniOpKind::Not,
+                AstKind::UniOp {
+                    op:
+                        Annot {
+                            value: UniOpKind::Not,
+                            ..
+                        },
+                    e,
+                },
+            ) => relocate(*e, loc),
+            (_, e_value) => Ast::uniop(op.clone(), Ast::new(e_value, e.loc), loc),
+        }
+    }
+
+    fn simplify_binop(&mut self, op: &BinOp, l: Ast, r: Ast, loc: Loc) -> Ast {
+        use self::BinOpKind::*;
+        match (
+            &op.value,
+            is_int(&l, 0),
+            is_int(&l, 1),
+            is_int(&r, 0),
+            is_int(&r, 1),
+        ) {
+            // x + 0 => x, 0 + x => x, x - 0 => x
+            (Add, true, _, _, _) => relocate(r, loc),
+            (Add, _, _, true, _) | (Sub, _, _, true, _) => relocate(l, loc),
+            // 0 - x => -x
+            (Sub, true, _, _, _) => self.simplify_uniop(&UniOp::minus(op.loc.clone()), r, loc),
+            // x * 1 => x, 1 * x => x, x / 1 => x, x ^ 1 => x
+            (Mult, _, true, _, _) => relocate(r, loc),
+            (Mult, _, _, _, true) | (Div, _, _, _, true) | (Pow, _, _, _, true) => relocate(l, loc),
+            // a - -b => a + b。--xと同じく-bが溢れない場合だけ
+            (Sub, _, _, _, _) => match r.value {
+                AstKind::UniOp {
+                    op:
+                        Annot {
+                            value: UniOpKind::Minus,
+                            ..
+                        },
+                    e,
+                } if can_negate(&e) => {
+                    Ast::binop(BinOp::add(op.loc.clone()), l, relocate(*e, r.loc), loc)
+                }
+                r_value => Ast::binop(op.clone(), l, Ast::new(r_value, r.loc), loc),
+            },
+            _ => Ast::binop(op.clone(), l, r, loc),
+        }
+    }
+}
+
+/// 式の位置を付け替える。簡約した結果が元の式全体を覆うようにする
+pub(crate) fn relocate(mut expr: Ast, loc: Loc) -> Ast {
+    expr.loc = loc;
+    expr
+}
+
+/// 整数のリテラルnかどうか
+pub(crate) fn is_int(expr: &Ast, n: u64) -> bool {
+    expr.value == AstKind::Num(n)
+}
+
+/// 符号を反転しても溢れない式かどうか。溢れるのは-2^63だけなので、i64に収まる整数のリテラルと
+/// 結果が必ず小数になる式に限る。変数は-2^63が束縛されていることがあるので含めない
+pub(crate) fn can_negate(expr: &Ast) -> bool {
+    match expr.value {
+        AstKind::Num(n) => n.to_i64().is_some(),
+        _ => is_float(expr),
+    }
+}
+
+/// 評価すると小数になるかエラーになる式かどうか。算術演算は片方が小数なら結果も小数になる
+fn is_float(expr: &Ast) -> bool {
+    use self::AstKind::*;
+    use self::BinOpKind::*;
+    ensure_stack(|| match expr.value {
+        Float(_) => true,
+        UniOp { ref op, ref e } => op.value != UniOpKind::Not && is_float(e),
+        BinOp {
+            ref op,
+            ref l,
+            ref r,
+        } => matches!(op.value, Add | Sub | Mult | Div | Mod | Pow) && (is_float(r) || is_float(l)),
+        _ => false,
+    })
+}
+
+/// 定数の式を評価してリテラルに置き換える
+/// 評価が失敗する式はそのまま残し、実行時に同じエラーが起きるようにする
+pub(crate) fn fold(expr: Ast) -> Ast {
+    let loc = expr.loc.clone();
+    let lit = |kind| Ast::new(kind, loc.clone());
+    let neg = |e| Ast::uniop(UniOp::minus(loc.clone()), e, loc.clone());
+    match Interpreter::new().eval(&expr) {
+        Ok(Value::Bool(b)) => lit(AstKind::Bool(b)),
+        Ok(Value::Num(Number::Int(n))) if n >= 0 => lit(AstKind::Num(n as u64)),
+        Ok(Value::Num(Number::Int(n))) => neg(lit(AstKind::Num(n.unsigned_abs()))),
+        Ok(Value::Num(Number::Float(f))) if f.is_sign_negative() => neg(lit(AstKind::Float(-f))),
+        Ok(Value::Num(Number::Float(f))) => lit(AstKind::Float(f)),
+        // 有理数はリテラルで書けないので畳み込まない
+        Ok(Value::Num(_)) | Err(_) => expr,
+    }
+}
+
+#[test]
+fn test_optimizer() {
+    let optimize = |s: &str| Optimizer::new().optimize(&s.parse().unwrap());
+    // 定数は畳み込まれ、元の式全体の位置を持つ
+    assert_eq!(optimize("1 + 2 * 3"), Ast::num(7, Loc(0, 9)));
+    assert_eq!(
+        optimize("1 - 4"),
+        Ast::uniop(UniOp::minus(Loc(0, 5)), Ast::num(3, Loc(0, 5)), Loc(0, 5))
+    );
+    assert_eq!(
+        optimize("if 2 > 1 then x else y"),
+        Ast::var("x", Loc(0, 22))
+    );
+    // 恒等式による簡約
+    assert_eq!(optimize("(x + 0) * 1"), Ast::var("x", Loc(1, 11)));
+    assert_eq!(
+        optimize("--(x * 0.5)"),
+        Ast::binop(
+            BinOp::mult(Loc(5, 6)),
+            Ast::var("x", Loc(3, 4)),
+            Ast::float(0.5, Loc(7, 10)),
+            Loc(0, 10)
+        )
+    );
+    // 変数には-2^63が束縛されていることがあり、その符号の反転は溢れるので消さない
+    assert_eq!(optimize("--x"), "--x".parse().unwrap());
+    // 0 - -x => --x で止まる
+    assert_eq!(
+        optimize("0 - -x"),
+        Ast::uniop(
+            UniOp::minus(Loc(2, 3)),
+            Ast::uniop(UniOp::minus(Loc(4, 5)), Ast::var("x", Loc(5, 6)), Loc(4, 6)),
+            Loc(0, 6)
+        )
+    );
+    assert_eq!(optimize("0 * 7"), Ast::num(0, Loc(0, 5)));
+    // 0を掛けても0乗しても変数は消さない。束縛されていなければエラーになり、小数なら結果も小数になる
+    assert_eq!(optimize("0 * x"), "0 * x".parse().unwrap());
+    assert_eq!(optimize("x ^ 0"), "x ^ 0".parse().unwrap());
+    // 小数を掛けた結果は小数のまま
+    assert_eq!(optimize("0 * 1.5"), Ast::float(0.0, Loc(0, 7)));
+    assert_eq!(optimize("1.5 ^ 0"), Ast::float(1.0, Loc(0, 7)));
+    assert_eq!(
+        optimize("a - -(1 * 2)"),
+        Ast::binop(
+            BinOp::add(Loc(2, 3)),
+            Ast::var("a", Loc(0, 1)),
+            Ast::num(2, Loc(4, 11)),
+            Loc(0, 11)
+        )
+    );
+    assert_eq!(optimize("a - -b"), "a - -b".parse().unwrap());
+    // 0除算は畳み込まずに残す
+    assert_eq!(optimize("0 * (1 / 0)"), "0 * (1 / 0)".parse().unwrap());
+    assert_eq!(
+        optimize("x + 2 / (1 - 1)"),
+        Ast::binop(
+            BinOp::add(Loc(2, 3)),
+            Ast::var("x", Loc(0, 1)),
+            Ast::binop(
+                BinOp::div(Loc(6, 7)),
+                Ast::num(2, Loc(4, 5)),
+                Ast::num(0, Loc(9, 14)),
+                Loc(4, 14)
+            ),
+            Loc(0, 14)
+        )
+    );
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_optimizer_preserves_eval(ast in arb_ast()) {
+        // letで変数が書き換わるので、評価するたびに同じ環境から始める
+        let init = || {
+            let (mut interp, mut checker) = (Interpreter::new(), TypeChecker::new());
+            for stmt in &["let x = 3", "let y = true", "let m = -9223372036854775807 - 1"] {
+                let stmt = stmt.parse().unwrap();
+                interp.exec(&stmt).unwrap();
+                checker.check(&stmt).unwrap();
+            }
+            (interp, checker)
+        };
+        // 最適化は型の合った式だけを前提にする
+        proptest::prop_assume!(init().1.type_of(&ast).is_ok());
+        let optimized = Optimizer::new().optimize(&ast);
+        let expected = init().0.eval(&ast);
+        let found = init().0.eval(&optimized);
+        match (&expected, &found) {
+            // x / 1 => x のように有理数が整数になることはあるので数値として比べる
+            // 小数かどうかは変わらない
+            (Ok(Value::Num(a)), Ok(Value::Num(b))) => {
+                proptest::prop_assert_eq!(a.compare(b), Some(std::cmp::Ordering::Equal));
+                let is_float = |n: &Number| matches!(n, Number::Float(_));
+                proptest::prop_assert_eq!(is_float(a), is_float(b), "{:?} {:?}", a, b);
+            }
+            (Ok(_), _) => proptest::prop_assert_eq!(expected, found),
+            // 0除算や未定義の変数などのエラーは消えない。簡約で式が置き換わると位置は広がることがある
+            (Err(e), _) => {
+                proptest::prop_assert_eq!(found.map_err(|e| e.value), Err(e.value.clone()))
+            }
+        }
+    }
+}
@@ -0,0 +1,1518 @@
+//! 構文解析器と演算子表
+
+#[cfg(test)]
+use crate::ast::AstKind;
+#[cfg(test)]
+use crate::ast::BinOpKind;
+use crate::ast::{ensure_stack, Ast, BinOp, UniOp};
+#[cfg(test)]
+use crate::diagnostics::SourceMap;
+#[cfg(test)]
+use crate::format::AstFormatter;
+#[cfg(test)]
+use crate::interp::{Interpreter, Number, Value};
+use crate::lexer::{
+    lex, lex_with, skip_comment, unclosed_parens, Annot, LexError, Lexer, Loc, Token, TokenKind,
+};
+use std::cell::Cell;
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::iter::Peekable;
+use std::rc::Rc;
+use std::str::FromStr;
+
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum ParseError {
+    /// 予期しないトークンがきた
+    UnexpectedToken(Token<'static>),
+    /// 式を期待していたのに式でないものがきた
+    NotExpression(Token<'static>),
+    /// 演算子を期待していたのに演算子でないものがきた
+    NotOperator(Token<'static>),
+    /// 括弧が閉じられていない
+    UnclosedOpenParen(Token<'static>),
+    /// 式の解析が終わったのにまだトークンが残っている
+    RedundantExpression(Token<'static>),
+    /// 括弧や演算子の入れ子が深すぎる
+    NestingTooDeep(Token<'static>),
+    /// パース途中で入力が終わった
+    Eof,
+}
+
+pub fn parse(tokens: Vec<Token<'_>>) -> Result<Ast, Vec<ParseError>> {
+    let tokens = tokens.into_iter().map(Ok);
+    let (ast, errors) = match parse_with(tokens, &OperatorTable::default()) {
+        Ok(parsed) => parsed,
+        Err(_) => unreachable!("字句解析済みのトークン列は字句エラーを含まない"),
+    };
+    if errors.is_empty() {
+        Ok(ast)
+    } else {
+        Err(errors)
+    }
+}
+
+/// 演算子表を指定して構文解析する
+/// トークンは字句解析器から必要な分だけ取り出し、最初の字句エラーで読むのをやめてそのエラーを返す
+/// 構文エラーがあっても最後まで読み、エラーの箇所をErrorノードにした木と見つかったエラーをすべて返す
+pub(crate) fn parse_with<'a>(
+    tokens: impl IntoIterator<Item = Result<Token<'a>, LexError>>,
+    ops: &OperatorTable,
+) -> Result<(Ast, Vec<ParseError>), LexError> {
+    // 入力が途中で終わったときは最後のトークンの直後を指す。最後まで読んでから決まる
+    let end = Cell::new(0);
+    let mut lex_error = None;
+    // 字句エラーは入力の終わりとして構文解析器に見せ、後で返す
+    let tokens = tokens.into_iter().map_while(|tok| match tok {
+        Ok(tok) => {
+            end.set(tok.loc.1);
+            Some(tok)
+        }
+        Err(e) => {
+            lex_error = Some(e);
+            None
+        }
+    });
+    let mut st = ParseState {
+        ops,
+        errors: Vec::new(),
+        end: &end,
+        depth: 0,
+    };
+    // 入力をイテレータにし、Peekableにする
+    let mut tokens = tokens.peekable();
+    let ret = parse_stmt(&mut tokens, &mut st);
+    // 残ったトークンはまとめて1つのエラーにする
+    if let Some(tok) = tokens.next() {
+        st.errors
+            .push(ParseError::RedundantExpression(tok.into_owned()));
+    }
+    // 構文エラーより字句エラーを優先するので、残りの入力も字句解析しておく
+    tokens.for_each(drop);
+    match lex_error {
+        Some(e) => Err(e),
+        None => Ok((ret, st.errors)),
+    }
+}
+
+/// スクリプトを文の区間に分ける。文は;か、括弧の外の改行で終わる
+/// 括弧の中なら式を複数行に分けて書ける。空白とコメントだけの文は除く
+pub fn split_statements(src: &str) -> Vec<Loc> {
+    let input = src.as_bytes();
+    let mut spans = Vec::new();
+    let mut start = 0;
+    let mut depth = 0usize;
+    // 空白とコメント以外があったかどうか
+    let mut has_token = false;
+    let mut i = 0;
+    // 区切りはASCIIの文字なのでバイト単位で見てよい
+    while i < input.len() {
+        // コメントの中の括弧や;は数えない。#コメントの後の改行は文の終わりになる
+        match skip_comment(input, i) {
+            Some(Ok(end)) => {
+                i = end;
+                continue;
+            }
+            // 閉じていないコメントは入力の終わりまで続く。エラーを報告するため文として残す
+            Some(Err(_)) => {
+                has_token = true;
+                break;
+            }
+            None => {}
+        }
+        let end = match input[i] {
+            b'(' => {
+                depth += 1;
+                false
+            }
+            b')' => {
+                depth = depth.saturating_sub(1);
+                false
+            }
+            b';' => true,
+            b'\n' => depth == 0,
+            _ => false,
+        };
+        if end {
+            if has_token {
+                spans.push(Loc(start, i));
+            }
+            start = i + 1;
+            depth = 0;
+            has_token = false;
+        } else if !input[i].is_ascii_whitespace() {
+            has_token = true;
+        }
+        i += 1;
+    }
+    if has_token {
+        spans.push(Loc(start, src.len()));
+    }
+    spans
+}
+
+#[test]
+fn test_split_statements() {
+    let src = "let x = 1; f(x,\n  2)\n\n  x + (1\n)\n";
+    let stmts: Vec<_> = split_statements(src)
+        .into_iter()
+        .map(|span| src[span.0..span.1].trim())
+        .collect();
+    assert_eq!(stmts, vec!["let x = 1", "f(x,\n  2)", "x + (1\n)"]);
+
+    // コメントの中の区切りは無視し、コメントだけの文は除く
+    let src = "# a; b\n1 /* ; ( */ + 2 # c\n/* d */\n(3 # )\n)";
+    let stmts: Vec<_> = split_statements(src)
+        .into_iter()
+        .map(|span| src[span.0..span.1].trim())
+        .collect();
+    assert_eq!(stmts, vec!["1 /* ; ( */ + 2 # c", "(3 # )\n)"]);
+    // 閉じていないコメントはエラーにするため文として残す
+    assert_eq!(split_statements("1\n/* 2\n3"), vec![Loc(0, 1), Loc(2, 8)]);
+
+    // 位置はスクリプト全体の上の位置になり、エラーのある文の後も解析を続ける
+    let results = OperatorTable::default().parse_script("1 +\n(2 $ 3)\nx");
+    assert_eq!(results.len(), 3);
+    assert_eq!(results[0].0, Loc(0, 3));
+    assert_eq!(
+        results[1].1,
+        Err(Error::Lexer(LexError::invalid_char('$', Loc(7, 8))))
+    );
+    assert_eq!(results[2].1, Ok(Ast::var("x", Loc(12, 13))));
+}
+
+/// 構文解析の間持ち回す状態
+pub(crate) struct ParseState<'a> {
+    ops: &'a OperatorTable,
+    /// 見つかった構文エラー
+    errors: Vec<ParseError>,
+    /// 読み終えたトークンの終わりの位置
+    end: &'a Cell<usize>,
+    /// 解析中の式の入れ子の深さ
+    depth: usize,
+}
+
+impl ParseState<'_> {
+    /// 入力が途中で終わったときにErrorノードに付ける位置
+    fn eof(&self) -> Loc {
+        let end = self.end.get();
+        Loc(end, end + 1)
+    }
+}
+
+/// エラーの後で読み飛ばしをやめるトークン。閉じ括弧や区切り、演算子から解析を再開する
+pub(crate) fn is_sync(kind: &TokenKind<'_>, ops: &OperatorTable) -> bool {
+    use self::TokenKind::*;
+    matches!(kind, RParen | Comma | Then | Else) || ops.post_op(kind).is_some()
+}
+
+/// 同期点まで読み飛ばし、読み飛ばした区間を返す。括弧の中はまとめて読み飛ばす
+pub(crate) fn skip_to_sync<'a, Tokens>(
+    tokens: &mut Peekable<Tokens>,
+    ops: &OperatorTable,
+) -> Option<Loc>
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    let mut skipped: Option<Loc> = None;
+    let mut depth = 0;
+    while let Some(tok) = tokens.peek() {
+        match tok.value {
+            TokenKind::LParen => depth += 1,
+            TokenKind::RParen if depth > 0 => depth -= 1,
+            ref kind if depth == 0 && is_sync(kind, ops) => break,
+            _ => (),
+        }
+        let loc = tokens.next().unwrap().loc;
+        skipped = Some(skipped.map_or(loc.clone(), |s| s.merge(&loc)));
+    }
+    skipped
+}
+
+/// 式の入れ子の深さの上限。木をたどる再帰は`ensure_stack`でスタックを継ぎ足すが、
+/// 木を捨てるときの再帰はそうできないので、木の深さはこの範囲に抑える
+/// 左結合の演算子はおよそこの数まで続けて書ける
+pub(crate) const MAX_DEPTH: usize = 10_000;
+
+/// 入れ子が深すぎればエラーを記録し、式の残りを読み飛ばしてその区間を返す
+/// 対応する開き括弧のない閉じ括弧か、括弧の外の`,` `then` `else`の手前で止まる
+pub(crate) fn skip_too_deep<'a, Tokens>(
+    tokens: &mut Peekable<Tokens>,
+    st: &mut ParseState,
+) -> Option<Loc>
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    use self::TokenKind::*;
+    if st.depth < MAX_DEPTH {
+        return None;
+    }
+    st.errors.push(ParseError::NestingTooDeep(
+        tokens.peek()?.clone().into_owned(),
+    ));
+    let mut skipped: Option<Loc> = None;
+    let mut depth = 0;
+    while let Some(tok) = tokens.peek() {
+        match tok.value {
+            LParen => depth += 1,
+            RParen | Comma | Then | Else if depth == 0 => break,
+            RParen => depth -= 1,
+            _ => (),
+        }
+        let loc = tokens.next().unwrap().loc;
+        skipped = Some(skipped.map_or(loc.clone(), |s| s.merge(&loc)));
+    }
+    skipped
+}
+
+/// 括弧の中でエラーが起きたとき、対応する閉じ括弧まで読み飛ばしてその位置を返す
+/// depthはまだ閉じていない括弧の数
+pub(crate) fn skip_to_rparen<'a, Tokens>(
+    tokens: &mut Peekable<Tokens>,
+    mut depth: usize,
+) -> Option<Loc>
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    for tok in tokens.by_ref() {
+        match tok.value {
+            TokenKind::LParen => depth += 1,
+            TokenKind::RParen if depth == 1 => return Some(tok.loc),
+            TokenKind::RParen => depth -= 1,
+            _ => (),
+        }
+    }
+    None
+}
+
+/// kindのトークンを読む。なければエラーを記録して同期点まで読み飛ばす
+/// 読み飛ばした先にkindがあれば、読み飛ばした区間を含めてeをErrorノードにして続ける
+/// それでもなければeと読み飛ばした区間をあわせた位置を返す
+pub(crate) fn expect_or_skip<'a, Tokens>(
+    tokens: &mut Peekable<Tokens>,
+    st: &mut ParseState,
+    kind: TokenKind<'static>,
+    e: Ast,
+) -> Result<Ast, Loc>
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    match tokens.peek() {
+        Some(tok) if tok.value == kind => {
+            tokens.next();
+            return Ok(e);
+        }
+        Some(tok) => st
+            .errors
+            .push(ParseError::UnexpectedToken(tok.clone().into_owned())),
+        None => st.errors.push(ParseError::Eof),
+    }
+    let loc = match skip_to_sync(tokens, st.ops) {
+        Some(skipped) => e.loc.merge(&skipped),
+        None => e.loc,
+    };
+    match tokens.peek() {
+        Some(tok) if tok.value == kind => {
+            tokens.next();
+            Ok(Ast::error(loc))
+        }
+        _ => Err(loc),
+    }
+}
+
+/// 演算子の結合性
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Assoc {
+    /// 左結合。a - b - c は (a - b) - c
+    Left,
+    /// 右結合。a ^ b ^ c は a ^ (b ^ c)
+    Right,
+}
+
+/// 単項演算子からASTを組み立てる関数。引数は演算子の位置と被演算子
+pub(crate) type UnaryBuilder = Rc<dyn Fn(Loc, Ast) -> Ast>;
+/// 二項演算子からASTを組み立てる関数。引数は演算子の位置と左右の被演算子
+pub(crate) type BinaryBuilder = Rc<dyn Fn(Loc, Ast, Ast) -> Ast>;
+
+/// 前置演算子
+#[derive(Clone)]
+pub(crate) struct PrefixOp {
+    /// 結合力。大きいほど強く結合する
+    pub(crate) bp: u8,
+    build: UnaryBuilder,
+}
+
+/// 被演算子の後ろに現れる演算子
+#[derive(Clone)]
+pub(crate) enum PostOp {
+    /// 中置演算子
+    Infix {
+        bp: u8,
+        assoc: Assoc,
+        build: BinaryBuilder,
+    },
+    /// 後置演算子
+    Postfix { bp: u8, build: UnaryBuilder },
+}
+
+/// 演算子表。演算子ごとに結合力、結合性、前置/中置/後置の種類を持つ
+/// 実行時に演算子を追加すればその演算子もパースできるようになる
+#[derive(Clone)]
+pub struct OperatorTable {
+    pub(crate) prefix: HashMap<String, PrefixOp>,
+    /// 中置演算子と後置演算子。被演算子の直後に来るので同じ記号を両方には登録できない
+    pub(crate) post: HashMap<String, PostOp>,
+    /// 組み込みのトークンでない記号。長いものから順に並べておく
+    custom: Vec<String>,
+}
+
+impl Default for OperatorTable {
+    /// 組み込みの演算子だけを持つ演算子表を作る
+    fn default() -> Self {
+        let mut ops = OperatorTable {
+            prefix: HashMap::new(),
+            post: HashMap::new(),
+            custom: Vec::new(),
+        };
+        ops.add_infix("||", 2, Assoc::Left, binop_with(BinOp::or))
+            .add_infix("&&", 4, Assoc::Left, binop_with(BinOp::and))
+            .add_infix("==", 6, Assoc::Left, binop_with(BinOp::eq))
+            .add_infix("!=", 6, Assoc::Left, binop_with(BinOp::ne))
+            .add_infix("<", 6, Assoc::Left, binop_with(BinOp::lt))
+            .add_infix("<=", 6, Assoc::Left, binop_with(BinOp::le))
+            .add_infix(">", 6, Assoc::Left, binop_with(BinOp::gt))
+            .add_infix(">=", 6, Assoc::Left, binop_with(BinOp::ge))
+            .add_infix("+", 10, Assoc::Left, binop_with(BinOp::add))
+            .add_infix("-", 10, Assoc::Left, binop_with(BinOp::sub))
+            .add_infix("*", 20, Assoc::Left, binop_with(BinOp::mult))
+            .add_infix("/", 20, Assoc::Left, binop_with(BinOp::div))
+            .add_infix("%", 20, Assoc::Left, binop_with(BinOp::modulo))
+            .add_prefix("+", 30, uniop_with(UniOp::plus))
+            .add_prefix("-", 30, uniop_with(UniOp::minus))
+            .add_prefix("!", 30, uniop_with(UniOp::not))
+            // 単項演算子より強いので-2^2は-(2^2)になる
+            .add_infix("^", 40, Assoc::Right, binop_with(BinOp::pow));
+        ops
+    }
+}
+
+/// 二項演算のASTを作る関数を返す。位置は両辺をあわせた区間になる
+pub(crate) fn binop_with(op: fn(Loc) -> BinOp) -> impl Fn(Loc, Ast, Ast) -> Ast {
+    move |loc, l, r| {
+        let loc_all = l.loc.merge(&r.loc);
+        Ast::binop(op(loc), l, r, loc_all)
+    }
+}
+
+/// 単項演算のASTを作る関数を返す。位置は演算子と被演算子をあわせた区間になる
+pub(crate) fn uniop_with(op: fn(Loc) -> UniOp) -> impl Fn(Loc, Ast) -> Ast {
+    move |loc, e| {
+        let loc_all = loc.merge(&e.loc);
+        Ast::uniop(op(loc), e, loc_all)
+    }
+}
+
+/// 演算子として登録できるトークンの記号を返す
+pub(crate) fn op_symbol<'a>(kind: &'a TokenKind<'_>) -> Option<&'a str> {
+    use self::TokenKind::*;
+    match kind {
+        Plus => Some("+"),
+        Minus => Some("-"),
+        Asterisk => Some("*"),
+        Slash => Some("/"),
+        Percent => Some("%"),
+        Caret => Some("^"),
+        EqEq => Some("=="),
+        NotEq => Some("!="),
+        Lt => Some("<"),
+        Le => Some("<="),
+        Gt => Some(">"),
+        Ge => Some(">="),
+        AndAnd => Some("&&"),
+        OrOr => Some("||"),
+        Bang => Some("!"),
+        Op(s) => Some(s),
+        _ => None,
+    }
+}
+
+impl OperatorTable {
+    /// 前置演算子を登録する
+    pub fn add_prefix(
+        &mut self,
+        symbol: &str,
+        bp: u8,
+        build: impl Fn(Loc, Ast) -> Ast + 'static,
+    ) -> &mut Self {
+        self.register_symbol(symbol, self.prefix.contains_key(symbol));
+        let op = PrefixOp {
+            bp,
+            build: Rc::new(build),
+        };
+        self.prefix.insert(symbol.to_string(), op);
+        self
+    }
+
+    /// 中置演算子を登録する
+    pub fn add_infix(
+        &mut self,
+        symbol: &str,
+        bp: u8,
+        assoc: Assoc,
+        build: impl Fn(Loc, Ast, Ast) -> Ast + 'static,
+    ) -> &mut Self {
+        self.register_symbol(symbol, self.post.contains_key(symbol));
+        let op = PostOp::Infix {
+            bp,
+            assoc,
+            build: Rc::new(build),
+        };
+        self.post.insert(symbol.to_string(), op);
+        self
+    }
+
+    /// 後置演算子を登録する
+    // 組み込みの後置演算子はないので、外から登録するときだけ使う
+    pub fn add_postfix(
+        &mut self,
+        symbol: &str,
+        bp: u8,
+        build: impl Fn(Loc, Ast) -> Ast + 'static,
+    ) -> &mut Self {
+        self.register_symbol(symbol, self.post.contains_key(symbol));
+        let op = PostOp::Postfix {
+            bp,
+            build: Rc::new(build),
+        };
+        self.post.insert(symbol.to_string(), op);
+        self
+    }
+
+    /// 記号を字句解析器が認識できるようにする。boundは同じ種類の演算子としてもう登録されているかどうか
+    ///
+    /// # Panics
+    /// 記号が空だったり、ASCIIの記号以外や括弧、引数と文の区切りの`,`と`;`を含んでいたり、
+    /// コメントの始まりを含んでいたりするとパニックする。組み込みの演算子を同じ種類の演算子として登録し直そうとしてもパニックする。
+    /// 整形器などは組み込みの演算子の結合力をこの表から引くので、置き換えられると木を書き戻せない
+    fn register_symbol(&mut self, symbol: &str, bound: bool) {
+        assert!(
+            !symbol.is_empty()
+                && symbol != "="
+                && !symbol.contains("/*")
+                && symbol
+                    .bytes()
+                    .all(|b| b.is_ascii_punctuation() && !b"()_#,;".contains(&b)),
+            "invalid operator symbol: {:?}",
+            symbol
+        );
+        let is_builtin = lex(symbol).is_ok_and(|tokens| tokens.len() == 1);
+        assert!(
+            !(is_builtin && bound),
+            "cannot redefine builtin operator: {:?}",
+            symbol
+        );
+        if !is_builtin && !self.custom.iter().any(|s| s == symbol) {
+            self.custom.push(symbol.to_string());
+            // 最長一致させるため長い記号を先に試す
+            self.custom.sort_by_key(|s| std::cmp::Reverse(s.len()));
+        }
+    }
+
+    /// この演算子表の記号を認識して字句解析する
+    pub fn lex<'a>(&'a self, input: &'a str) -> Result<Vec<Token<'a>>, LexError> {
+        lex_with(input, &self.custom)
+    }
+
+    /// まだ閉じていない括弧とブロックコメントの数。REPLはこれが0になるまで続きの行を読む
+    pub fn unclosed_parens(&self, input: &str) -> usize {
+        unclosed_parens(input, &self.custom)
+    }
+
+    fn prefix_op(&self, kind: &TokenKind<'_>) -> Option<&PrefixOp> {
+        op_symbol(kind).and_then(|s| self.prefix.get(s))
+    }
+
+    fn post_op(&self, kind: &TokenKind<'_>) -> Option<&PostOp> {
+        op_symbol(kind).and_then(|s| self.post.get(s))
+    }
+
+    /// この演算子表の演算子を使って文字列を解析する
+    pub fn parse(&self, input: &str) -> Result<Ast, Error> {
+        let (ast, errors) = self.parse_partial(input)?;
+        if errors.is_empty() {
+            Ok(ast)
+        } else {
+            Err(Error::Parser(errors))
+        }
+    }
+
+    /// 構文エラーがあっても最後まで解析し、エラーの箇所をErrorノードにした木と構文エラーをすべて返す
+    pub fn parse_partial(&self, input: &str) -> Result<(Ast, Vec<ParseError>), LexError> {
+        parse_with(Lexer::with_custom(input, &self.custom), self)
+    }
+
+    /// 複数の文からなるスクリプトを解析する。文は;か括弧の外の改行で区切る
+    /// 構文エラーがあっても残りの文の解析を続け、文ごとの区間と結果を返す
+    pub fn parse_script(&self, src: &str) -> Vec<(Loc, Result<Ast, Error>)> {
+        split_statements(src)
+            .into_iter()
+            .map(|span| {
+                let result = self.parse_at(src, &span);
+                (span, result)
+            })
+            .collect()
+    }
+
+    /// srcのspanの部分を1つの文として解析する。位置はsrc全体の上の位置になる
+    fn parse_at(&self, src: &str, span: &Loc) -> Result<Ast, Error> {
+        let shift = |loc: &Loc| Loc(loc.0 + span.0, loc.1 + span.0);
+        let tokens = Lexer::with_custom(&src[span.0..span.1], &self.custom).map(|tok| match tok {
+            Ok(tok) => Ok(Token::new(tok.value, shift(&tok.loc))),
+            Err(e) => Err(LexError::new(e.value, shift(&e.loc))),
+        });
+        let (ast, errors) = parse_with(tokens, self)?;
+        if errors.is_empty() {
+            Ok(ast)
+        } else {
+            Err(Error::Parser(errors))
+        }
+    }
+}
+
+/// STMT = "let", IDENT, "=", EXPR
+///      | "fn", IDENT, "(", [IDENT, {",", IDENT}], ")", "=", EXPR
+///      | EXPR ;
+pub(crate) fn parse_stmt<'a, Tokens>(tokens: &mut Peekable<Tokens>, st: &mut ParseState) -> Ast
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    match tokens.peek().map(|tok| &tok.value) {
+        Some(TokenKind::Let) => {
+            // "let"
+            let let_loc = tokens.next().unwrap().loc;
+            // , IDENT, "="
+            let header = expect_ident(tokens).and_then(|var| {
+                expect_token(tokens, TokenKind::Equal)?;
+                Ok(var)
+            });
+            let var = match header {
+                Ok(var) => var,
+                Err(e) => return stmt_error(tokens, st, e, let_loc),
+            };
+            // , EXPR
+            let e = parse_expr(tokens, st);
+            let loc = let_loc.merge(&e.loc);
+            Ast::let_(var, e, loc)
+        }
+        Some(TokenKind::Fn) => {
+            // "fn"
+            let fn_loc = tokens.next().unwrap().loc;
+            // , IDENT, "(", [IDENT, {",", IDENT}], ")", "="
+            let header = expect_ident(tokens).and_then(|name| {
+                let lparen = expect_token(tokens, TokenKind::LParen)?;
+                let (params, _) = parse_list(tokens, lparen, expect_ident)?;
+                expect_token(tokens, TokenKind::Equal)?;
+                Ok((name, params))
+            });
+            let (name, params) = match header {
+                Ok(header) => header,
+                Err(e) => return stmt_error(tokens, st, e, fn_loc),
+            };
+            // , EXPR
+            let body = parse_expr(tokens, st);
+            let loc = fn_loc.merge(&body.loc);
+            Ast::fn_def(name, params, body, loc)
+        }
+        // | EXPR
+        _ => parse_expr(tokens, st),
+    }
+}
+
+/// 文の頭が壊れているときはエラーを記録し、残りを読み飛ばして文全体をErrorノードにする
+pub(crate) fn stmt_error<'a, Tokens>(
+    tokens: &mut Peekable<Tokens>,
+    st: &mut ParseState,
+    e: ParseError,
+    start: Loc,
+) -> Ast
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    st.errors.push(e);
+    tokens.for_each(drop);
+    Ast::error(Loc(start.0, st.eof().0))
+}
+
+/// 識別子を1つ読む
+pub(crate) fn expect_ident<'a, Tokens>(
+    tokens: &mut Peekable<Tokens>,
+) -> Result<Annot<String>, ParseError>
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    match tokens.next() {
+        Some(Token {
+            value: TokenKind::Ident(name),
+            loc,
+        }) => Ok(Annot::new(name.into_owned(), loc)),
+        Some(tok) => Err(ParseError::UnexpectedToken(tok.into_owned())),
+        None => Err(ParseError::Eof),
+    }
+}
+
+/// 期待する種類のトークンを1つ読む
+pub(crate) fn expect_token<'a, Tokens>(
+    tokens: &mut Peekable<Tokens>,
+    kind: TokenKind<'static>,
+) -> Result<Token<'a>, ParseError>
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    match tokens.next() {
+        Some(tok) if tok.value == kind => Ok(tok),
+        Some(tok) => Err(ParseError::UnexpectedToken(tok.into_owned())),
+        None => Err(ParseError::Eof),
+    }
+}
+
+/// "(" を読んだ後の [ITEM, {",", ITEM}], ")" を読む
+/// 要素と閉じ括弧の位置を返す
+pub(crate) fn parse_list<'a, Tokens, T>(
+    tokens: &mut Peekable<Tokens>,
+    lparen: Token<'a>,
+    mut item: impl FnMut(&mut Peekable<Tokens>) -> Result<T, ParseError>,
+) -> Result<(Vec<T>, Loc), ParseError>
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    let mut items = Vec::new();
+    if let Some(TokenKind::RParen) = tokens.peek().map(|tok| &tok.value) {
+        let rparen = tokens.next().unwrap();
+        return Ok((items, rparen.loc));
+    }
+    loop {
+        items.push(item(tokens)?);
+        match tokens.next() {
+            Some(Token {
+                value: TokenKind::Comma,
+                ..
+            }) => continue,
+            Some(Token {
+                value: TokenKind::RParen,
+                loc,
+            }) => return Ok((items, loc)),
+            Some(tok) => return Err(ParseError::UnexpectedToken(tok.into_owned())),
+            None => return Err(ParseError::UnclosedOpenParen(lparen.into_owned())),
+        }
+    }
+}
+
+pub(crate) fn parse_expr<'a, Tokens>(tokens: &mut Peekable<Tokens>, st: &mut ParseState) -> Ast
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    parse_expr_bp(tokens, st, 0)
+}
+
+/// 結合力がmin_bp以上の演算子だけを取り込んで式をパースする(Pratt法)
+/// 再帰するたびと演算子を1つ取り込むたびに入れ子を1段深くし、木の深さを抑える
+pub(crate) fn parse_expr_bp<'a, Tokens>(
+    tokens: &mut Peekable<Tokens>,
+    st: &mut ParseState,
+    min_bp: u8,
+) -> Ast
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    ensure_stack(|| parse_expr_bp_inner(tokens, st, min_bp))
+}
+
+fn parse_expr_bp_inner<'a, Tokens>(
+    tokens: &mut Peekable<Tokens>,
+    st: &mut ParseState,
+    min_bp: u8,
+) -> Ast
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    let ops = st.ops;
+    if let Some(loc) = skip_too_deep(tokens, st) {
+        return Ast::error(loc);
+    }
+    let depth = st.depth;
+    st.depth += 1;
+    // 前置演算子があればその結合力で被演算子をパースする。なければATOM
+    let mut e = match tokens.peek().and_then(|tok| ops.prefix_op(&tok.value)) {
+        Some(op) => {
+            let loc = tokens.next().unwrap().loc;
+            let e = parse_expr_bp(tokens, st, op.bp);
+            (op.build)(loc, e)
+        }
+        None => parse_atom(tokens, st),
+    };
+    while let Some(tok) = tokens.peek() {
+        let op = match ops.post_op(&tok.value) {
+            Some(op) => op,
+            // 前置演算子としてだけ登録された記号は被演算子の後ろには置けない
+            // 同じ結合力の左結合の中置演算子とみなして右辺まで読み、全体をErrorノードにする
+            None if ops.prefix_op(&tok.value).is_some() => {
+                let bp = ops.prefix_op(&tok.value).unwrap().bp;
+                if bp < min_bp {
+                    break;
+                }
+                st.errors
+                    .push(ParseError::NotOperator(tokens.next().unwrap().into_owned()));
+                let r = parse_expr_bp(tokens, st, bp + 1);
+                e = Ast::error(e.loc.merge(&r.loc));
+                continue;
+            }
+            // 演算子でなければ式はここで終わり
+            None => break,
+        };
+        match op {
+            PostOp::Infix { bp, assoc, build } => {
+                // 結合力が弱い演算子は呼び出し元に任せる
+                if *bp < min_bp {
+                    break;
+                }
+                st.depth += 1;
+                if let Some(skipped) = skip_too_deep(tokens, st) {
+                    e = Ast::error(e.loc.merge(&skipped));
+                    break;
+                }
+                let loc = tokens.next().unwrap().loc;
+                // 左結合なら右辺では同じ結合力の演算子を取り込まない
+                let r_bp = match assoc {
+                    Assoc::Left => bp + 1,
+                    Assoc::Right => *bp,
+                };
+                let r = parse_expr_bp(tokens, st, r_bp);
+                e = build(loc, e, r);
+            }
+            PostOp::Postfix { bp, build } => {
+                if *bp < min_bp {
+                    break;
+                }
+                st.depth += 1;
+                if let Some(skipped) = skip_too_deep(tokens, st) {
+                    e = Ast::error(e.loc.merge(&skipped));
+                    break;
+                }
+                let loc = tokens.next().unwrap().loc;
+                e = build(loc, e);
+            }
+        }
+    }
+    st.depth = depth;
+    e
+}
+
+pub(crate) fn parse_atom<'a, Tokens>(tokens: &mut Peekable<Tokens>, st: &mut ParseState) -> Ast
+where
+    Tokens: Iterator<Item = Token<'a>>,
+{
+    let tok = match tokens.peek() {
+        Some(tok) if !is_sync(&tok.value, st.ops) => tokens.next().unwrap(),
+        // 閉じ括弧や演算子は読まずに残し、呼び出し元で解析を再開させる
+        Some(tok) => {
+            let loc = tok.loc.clone();
+            st.errors
+                .push(ParseError::NotExpression(tok.clone().into_owned()));
+            return Ast::error(loc);
+        }
+        None => {
+            st.errors.push(ParseError::Eof);
+            return Ast::error(st.eof());
+        }
+    };
+    match tok.value {
+        // UNUMBER
+        TokenKind::Number(n) => Ast::num(n, tok.loc),
+        // | UFLOAT
+        TokenKind::Float(f) => Ast::float(f, tok.loc),
+        // | "true" | "false"
+        TokenKind::True => Ast::bool(true, tok.loc),
+        TokenKind::False => Ast::bool(false, tok.loc),
+        // | "if", EXPR, "then", EXPR, "else", EXPR
+        TokenKind::If => {
+            // thenやelseが見つからなければ、そこまでの全体をErrorノードにする
+            let cond = parse_expr(tokens, st);
+            let cond = match expect_or_skip(tokens, st, TokenKind::Then, cond) {
+                Ok(cond) => cond,
+                Err(loc) => return Ast::error(tok.loc.merge(&loc)),
+            };
+            let then = parse_expr(tokens, st);
+            let then = match expect_or_skip(tokens, st, TokenKind::Else, then) {
+                Ok(then) => then,
+                Err(loc) => return Ast::error(tok.loc.merge(&loc)),
+            };
+            // else節はできるだけ長く取る
+            let els = parse_expr(tokens, st);
+            let loc = tok.loc.merge(&els.loc);
+            Ast::if_(cond, then, els, loc)
+        }
+        // | IDENT, "(", [EXPR, {",", EXPR}], ")"
+        TokenKind::Ident(name) => match tokens.peek() {
+            Some(Token {
+                value: TokenKind::LParen,
+                ..
+            }) => {
+                let lparen = tokens.next().unwrap();
+                match parse_list(tokens, lparen, |tokens| Ok(parse_expr(tokens, st))) {
+                    Ok((args, rparen)) => {
+                        let loc = tok.loc.merge(&rparen);
+                        Ast::call(Annot::new(name.into_owned(), tok.loc), args, loc)
+                    }
+                    // 引数の区切りがおかしければ閉じ括弧まで読み飛ばし、呼び出し全体をErrorノードにする
+                    Err(e) => {
+                        let depth = match e {
+                            ParseError::UnexpectedToken(Token {
+                                value: TokenKind::LParen,
+                                ..
+                            }) => 2,
+                            _ => 1,
+                        };
+                        st.errors.push(e);
+                        let end = skip_to_rparen(tokens, depth).unwrap_or_else(|| st.eof());
+                        Ast::error(tok.loc.merge(&end))
+                    }
+                }
+            }
+            // | IDENT
+            _ => Ast::var(&name, tok.loc),
+        },
+        // | "(", EXPR, ")" ;
+        TokenKind::LParen => {
+            let e = parse_expr(tokens, st);
+            match tokens.next() {
+                Some(Token {
+                    value: TokenKind::RParen,
+                    ..
+                }) => e,
+                // 余計なトークンがあれば対応する閉じ括弧まで読み飛ばす
+                Some(t) => {
+                    let depth = if t.value == TokenKind::LParen { 2 } else { 1 };
+                    st.errors.push(ParseError::UnexpectedToken(t.into_owned()));
+                    let end = skip_to_rparen(tokens, depth).unwrap_or_else(|| st.eof());
+                    Ast::error(tok.loc.merge(&end))
+                }
+                // 閉じていなくても中の式はそのまま使う
+                None => {
+                    st.errors
+                        .push(ParseError::UnclosedOpenParen(tok.into_owned()));
+                    e
+                }
+            }
+        }
+        // 式の始まりでないトークンは同期点まで読み飛ばす
+        _ => {
+            let loc = match skip_to_sync(tokens, st.ops) {
+                Some(skipped) => tok.loc.merge(&skipped),
+                None => tok.loc.clone(),
+            };
+            st.errors.push(ParseError::NotExpression(tok.into_owned()));
+            Ast::error(loc)
+        }
+    }
+}
+
+#[test]
+fn test_parser() {
+    // 1 + 2 * 3 - -10
+    let ast = parse(vec![
+        Token::number(1, Loc(0, 1)),
+        Token::plus(Loc(2, 3)),
+        Token::number(2, Loc(4, 5)),
+        Token::asterisk(Loc(6, 7)),
+        Token::number(3, Loc(8, 9)),
+        Token::minus(Loc(10, 11)),
+        Token::minus(Loc(12, 13)),
+        Token::number(10, Loc(13, 15)),
+    ]);
+    assert_eq!(
+        ast,
+        Ok(Ast::binop(
+            BinOp::sub(Loc(10, 11)),
+            Ast::binop(
+                BinOp::add(Loc(2, 3)),
+                Ast::num(1, Loc(0, 1)),
+                Ast::binop(
+                    BinOp::new(BinOpKind::Mult, Loc(6, 7)),
+                    Ast::num(2, Loc(4, 5)),
+                    Ast::num(3, Loc(8, 9)),
+                    Loc(4, 9)
+                ),
+                Loc(0, 9)
+            ),
+            Ast::uniop(
+                UniOp::minus(Loc(12, 13)),
+                Ast::num(10, Loc(13, 15)),
+                Loc(12, 15)
+            ),
+            Loc(0, 15)
+        ))
+    )
+}
+
+#[test]
+fn test_parser_stream() {
+    // 字句エラーは構文エラーより優先し、最初の1つを返す
+    let ops = OperatorTable::default();
+    assert_eq!(
+        ops.parse_partial("(1 + $ 2 €"),
+        Err(LexError::invalid_char('$', Loc(5, 6)))
+    );
+    // 構文解析が途中で止まっても、残りの入力の字句エラーは見つける
+    assert_eq!(
+        ops.parse_partial("1 2 $"),
+        Err(LexError::invalid_char('$', Loc(4, 5)))
+    );
+    // 入力の終わりの位置は最後のトークンの直後になる
+    assert_eq!(
+        ops.parse_partial("1 +  "),
+        Ok((
+            Ast::binop(
+                BinOp::add(Loc(2, 3)),
+                Ast::num(1, Loc(0, 1)),
+                Ast::error(Loc(3, 4)),
+                Loc(0, 4)
+            ),
+            vec![ParseError::Eof]
+        ))
+    );
+}
+
+#[test]
+fn test_parser_recovery() {
+    let ops = OperatorTable::default();
+    let partial = |s: &str| ops.parse_partial(s).unwrap();
+    // 被演算子がなくても後ろの演算子から解析を続ける
+    assert_eq!(
+        partial("1 + * 2"),
+        (
+            Ast::binop(
+                BinOp::add(Loc(2, 3)),
+                Ast::num(1, Loc(0, 1)),
+                Ast::binop(
+                    BinOp::mult(Loc(4, 5)),
+                    Ast::error(Loc(4, 5)),
+                    Ast::num(2, Loc(6, 7)),
+                    Loc(4, 7)
+                ),
+                Loc(0, 7)
+            ),
+            vec![ParseError::NotExpression(Token::asterisk(Loc(4, 5)))]
+        )
+    );
+    // 1回の解析ですべてのエラーを集め、括弧の中は閉じ括弧まで読み飛ばす
+    let (ast, errors) = partial("1 + * 2 + (3 4) + f(5 6, 7) + (8");
+    assert_eq!(ast.to_string(), "1 + <error> * 2 + <error> + <error> + 8");
+    assert_eq!(
+        errors,
+        vec![
+            ParseError::NotExpression(Token::asterisk(Loc(4, 5))),
+            ParseError::UnexpectedToken(Token::number(4, Loc(13, 14))),
+            ParseError::UnexpectedToken(Token::number(6, Loc(22, 23))),
+            ParseError::UnclosedOpenParen(Token::lparen(Loc(30, 31))),
+        ]
+    );
+    // thenの前の余計なトークンは条件に含めて読み飛ばす
+    let (ast, errors) = partial("if x y then 1 else");
+    assert_eq!(ast.to_string(), "if <error> then 1 else <error>");
+    assert_eq!(
+        errors,
+        vec![
+            ParseError::UnexpectedToken(Token::ident("y", Loc(5, 6))),
+            ParseError::Eof
+        ]
+    );
+    // 前置だけの演算子を中置の位置に置くと、両辺をまとめてErrorノードにする
+    let (ast, errors) = partial("1 ! 2 * 3 + 4");
+    assert_eq!(
+        (ast.to_string(), errors),
+        (
+            "<error> * 3 + 4".to_string(),
+            vec![ParseError::NotOperator(Token::new(
+                TokenKind::Bang,
+                Loc(2, 3)
+            ))]
+        )
+    );
+    // 文の頭が壊れていれば文全体がErrorノードになる
+    assert_eq!(
+        partial("let 1 = 2 +"),
+        (
+            Ast::error(Loc(0, 11)),
+            vec![ParseError::UnexpectedToken(Token::number(1, Loc(4, 5)))]
+        )
+    );
+    assert_eq!(
+        "1 + ) 2".parse::<Ast>(),
+        Err(Error::Parser(vec![
+            ParseError::NotExpression(Token::rparen(Loc(4, 5))),
+            ParseError::RedundantExpression(Token::rparen(Loc(4, 5))),
+        ]))
+    );
+}
+
+/// 木がErrorノードを含むかどうか
+#[cfg(test)]
+pub(crate) fn has_error(ast: &Ast) -> bool {
+    use self::AstKind::*;
+    match ast.value {
+        Error => true,
+        Num(_) | Float(_) | Bool(_) | Var(_) => false,
+        Let { ref e, .. } | UniOp { ref e, .. } => has_error(e),
+        FnDef { ref body, .. } => has_error(body),
+        Call { ref args, .. } => args.iter().any(has_error),
+        If {
+            ref cond,
+            ref then,
+            ref els,
+        } => has_error(cond) || has_error(then) || has_error(els),
+        BinOp { ref l, ref r, .. } => has_error(l) || has_error(r),
+    }
+}
+
+#[cfg(test)]
+proptest::proptest! {
+    #[test]
+    fn test_parser_recovery_terminates(
+        words in proptest::collection::vec(
+            proptest::sample::select(vec![
+                "1", "x", "f", "(", ")", ",", "+", "-", "*", "^", "!", "==", "&&",
+                "if", "then", "else", "let", "fn", "=", "true",
+            ]),
+            0..16,
+        )
+    ) {
+        // どんなトークン列でも止まり、Errorノードを作ったときは必ずエラーを報告する
+        let (ast, errors) = OperatorTable::default().parse_partial(&words.join(" ")).unwrap();
+        proptest::prop_assert!(!has_error(&ast) || !errors.is_empty());
+    }
+
+    #[test]
+    fn test_parse_never_panics(src in "[0-9a-fox_ .+*/%^()<>=!&|,;#\n\t-]{0,48}|\\PC{0,16}") {
+        // fuzz/のファズターゲットと同じことを、字句解析を通りやすい入力で確かめる
+        if let Err(e) = src.parse::<Ast>() {
+            let sm = SourceMap::new("t", &src);
+            for d in e.diagnostics(&src) {
+                d.render(&sm);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_parser_pow() {
+    // -2^2 は -(2^2)
+    assert_eq!(
+        "-2^2".parse::<Ast>(),
+        Ok(Ast::uniop(
+            UniOp::minus(Loc(0, 1)),
+            Ast::binop(
+                BinOp::pow(Loc(2, 3)),
+                Ast::num(2, Loc(1, 2)),
+                Ast::num(2, Loc(3, 4)),
+                Loc(1, 4)
+            ),
+            Loc(0, 4)
+        ))
+    );
+    // 2^3^2 は 2^(3^2)
+    assert_eq!(
+        "2^3^2".parse::<Ast>(),
+        Ok(Ast::binop(
+            BinOp::pow(Loc(1, 2)),
+            Ast::num(2, Loc(0, 1)),
+            Ast::binop(
+                BinOp::pow(Loc(3, 4)),
+                Ast::num(3, Loc(2, 3)),
+                Ast::num(2, Loc(4, 5)),
+                Loc(2, 5)
+            ),
+            Loc(0, 5)
+        ))
+    );
+    // 2*3%4^-1 は (2*3)%(4^(-1))
+    assert_eq!(
+        "2*3%4^-1".parse::<Ast>(),
+        Ok(Ast::binop(
+            BinOp::modulo(Loc(3, 4)),
+            Ast::binop(
+                BinOp::mult(Loc(1, 2)),
+                Ast::num(2, Loc(0, 1)),
+                Ast::num(3, Loc(2, 3)),
+                Loc(0, 3)
+            ),
+            Ast::binop(
+                BinOp::pow(Loc(5, 6)),
+                Ast::num(4, Loc(4, 5)),
+                Ast::uniop(UniOp::minus(Loc(6, 7)), Ast::num(1, Loc(7, 8)), Loc(6, 8)),
+                Loc(4, 8)
+            ),
+            Loc(0, 8)
+        ))
+    );
+}
+
+#[test]
+fn test_operator_table() {
+    let mut ops = OperatorTable::default();
+    // **を^と同じ右結合のべき乗として登録する
+    ops.add_infix("**", 40, Assoc::Right, binop_with(BinOp::pow))
+        // 後置の'で2乗を表す
+        .add_postfix("'", 50, |loc, e| {
+            let loc_all = e.loc.merge(&loc);
+            Ast::binop(BinOp::pow(loc.clone()), e, Ast::num(2, loc), loc_all)
+        })
+        // 前置の~で符号反転を表す
+        .add_prefix("~", 30, uniop_with(UniOp::minus));
+
+    assert_eq!(
+        lex_with("2**3*4", &ops.custom),
+        Ok(vec![
+            Token::number(2, Loc(0, 1)),
+            Token::op("**", Loc(1, 3)),
+            Token::number(3, Loc(3, 4)),
+            Token::asterisk(Loc(4, 5)),
+            Token::number(4, Loc(5, 6)),
+        ])
+    );
+    assert_eq!(
+        ops.parse("2**3**2"),
+        Ok(Ast::binop(
+            BinOp::pow(Loc(1, 3)),
+            Ast::num(2, Loc(0, 1)),
+            Ast::binop(
+                BinOp::pow(Loc(4, 6)),
+                Ast::num(3, Loc(3, 4)),
+                Ast::num(2, Loc(6, 7)),
+                Loc(3, 7)
+            ),
+            Loc(0, 7)
+        ))
+    );
+    assert_eq!(
+        ops.parse("~3'"),
+        Ok(Ast::uniop(
+            UniOp::minus(Loc(0, 1)),
+            Ast::binop(
+                BinOp::pow(Loc(2, 3)),
+                Ast::num(3, Loc(1, 2)),
+                Ast::num(2, Loc(2, 3)),
+                Loc(1, 3)
+            ),
+            Loc(0, 3)
+        ))
+    );
+    // 前置演算子を中置の位置には置けない
+    assert_eq!(
+        ops.parse("1 ~ 2"),
+        Err(Error::Parser(vec![ParseError::NotOperator(Token::op(
+            "~",
+            Loc(2, 3)
+        ))]))
+    );
+
+    let mut interp = Interpreter::new();
+    let ast = ops.parse("1 + 2 * 3' ** 2").unwrap();
+    assert_eq!(interp.eval(&ast), Ok(Value::Num(Number::Int(163))));
+}
+
+#[test]
+fn test_operator_table_builtin() {
+    // 組み込みの記号でも、まだ登録されていない種類の演算子としてなら登録できる
+    let mut ops = OperatorTable::default();
+    ops.add_postfix("!", 50, uniop_with(UniOp::minus))
+        .add_prefix("*", 30, uniop_with(UniOp::plus));
+    let ast = ops.parse("*2! - 1").unwrap();
+    assert_eq!(AstFormatter::new(&ops).format(&ast), "+-2 - 1");
+
+    // 組み込みの演算子は置き換えられない
+    let redefine: [fn(&mut OperatorTable); 3] = [
+        |ops| {
+            ops.add_postfix("-", 50, uniop_with(UniOp::minus));
+        },
+        |ops| {
+            ops.add_infix("*", 10, Assoc::Right, binop_with(BinOp::mult));
+        },
+        |ops| {
+            ops.add_prefix("!", 50, uniop_with(UniOp::not));
+        },
+    ];
+    for f in &redefine {
+        let result = std::panic::catch_unwind(|| f(&mut OperatorTable::default()));
+        assert!(result.is_err());
+    }
+
+    // 組み込みの記号の先頭部分を登録しても、組み込みの記号は最長一致で読まれる
+    let mut ops = OperatorTable::default();
+    ops.add_infix("&", 4, Assoc::Left, binop_with(BinOp::and))
+        .add_infix("|", 2, Assoc::Left, binop_with(BinOp::or));
+    assert_eq!(ops.parse("true && false"), "true && false".parse::<Ast>());
+    assert_eq!(ops.parse("true || false"), "true || false".parse::<Ast>());
+    assert_eq!(
+        ops.parse("true & false")
+            .map(|ast| AstFormatter::new(&ops).format(&ast)),
+        Ok("true && false".to_string())
+    );
+
+    // 引数と文の区切りは演算子にできない
+    let separator: [fn(&mut OperatorTable); 2] = [
+        |ops| {
+            ops.add_infix(",", 1, Assoc::Left, binop_with(BinOp::add));
+        },
+        |ops| {
+            ops.add_infix(";", 1, Assoc::Left, binop_with(BinOp::add));
+        },
+    ];
+    for f in &separator {
+        let result = std::panic::catch_unwind(|| f(&mut OperatorTable::default()));
+        assert!(result.is_err());
+    }
+}
+
+#[test]
+fn test_let() {
+    // let x = 1 + y
+    let ast = "let x = 1 + y".parse::<Ast>();
+    assert_eq!(
+        ast,
+        Ok(Ast::let_(
+            Annot::new("x".to_string(), Loc(4, 5)),
+            Ast::binop(
+                BinOp::add(Loc(10, 11)),
+                Ast::num(1, Loc(8, 9)),
+                Ast::var("y", Loc(12, 13)),
+                Loc(8, 13)
+            ),
+            Loc(0, 13)
+        ))
+    );
+}
+
+#[test]
+fn test_function() {
+    assert_eq!(
+        "fn f(x, y) = g(x) + h()".parse::<Ast>(),
+        Ok(Ast::fn_def(
+            Annot::new("f".to_string(), Loc(3, 4)),
+            vec![
+                Annot::new("x".to_string(), Loc(5, 6)),
+                Annot::new("y".to_string(), Loc(8, 9)),
+            ],
+            Ast::binop(
+                BinOp::add(Loc(18, 19)),
+                Ast::call(
+                    Annot::new("g".to_string(), Loc(13, 14)),
+                    vec![Ast::var("x", Loc(15, 16))],
+                    Loc(13, 17)
+                ),
+                Ast::call(
+                    Annot::new("h".to_string(), Loc(20, 21)),
+                    vec![],
+                    Loc(20, 23)
+                ),
+                Loc(13, 23)
+            ),
+            Loc(0, 23)
+        ))
+    );
+    assert_eq!(
+        "f(1, 2".parse::<Ast>(),
+        Err(Error::Parser(vec![ParseError::UnclosedOpenParen(
+            Token::lparen(Loc(1, 2))
+        )]))
+    );
+}
+
+#[test]
+fn test_conditional() {
+    // 比較は算術演算より弱く、&&は||より強い
+    assert_eq!(
+        "!a || 1 + 1 < 3 && b".parse::<Ast>(),
+        Ok(Ast::binop(
+            BinOp::or(Loc(3, 5)),
+            Ast::uniop(UniOp::not(Loc(0, 1)), Ast::var("a", Loc(1, 2)), Loc(0, 2)),
+            Ast::binop(
+                BinOp::and(Loc(16, 18)),
+                Ast::binop(
+                    BinOp::lt(Loc(12, 13)),
+                    Ast::binop(
+                        BinOp::add(Loc(8, 9)),
+                        Ast::num(1, Loc(6, 7)),
+                        Ast::num(1, Loc(10, 11)),
+                        Loc(6, 11)
+                    ),
+                    Ast::num(3, Loc(14, 15)),
+                    Loc(6, 15)
+                ),
+                Ast::var("b", Loc(19, 20)),
+                Loc(6, 20)
+            ),
+            Loc(0, 20)
+        ))
+    );
+    // else節は後ろの演算子も取り込む
+    assert_eq!(
+        "if true then 1 else 2 + 3".parse::<Ast>(),
+        Ok(Ast::if_(
+            Ast::bool(true, Loc(3, 7)),
+            Ast::num(1, Loc(13, 14)),
+            Ast::binop(
+                BinOp::add(Loc(22, 23)),
+                Ast::num(2, Loc(20, 21)),
+                Ast::num(3, Loc(24, 25)),
+                Loc(20, 25)
+            ),
+            Loc(0, 25)
+        ))
+    );
+}
+
+#[test]
+fn test_number() {
+    assert_eq!(
+        lex("1.25 1e-3 2.5E2 7"),
+        Ok(vec![
+            Token::float(1.25, Loc(0, 4)),
+            Token::float(1e-3, Loc(5, 9)),
+            Token::float(250.0, Loc(10, 15)),
+            Token::number(7, Loc(16, 17)),
+        ])
+    );
+}
+
+#[test]
+fn test_long_chain() {
+    // 左結合の演算子の長い連鎖は深い木になるが、上限までは解析でき、評価や整形もできる
+    for n in [300, MAX_DEPTH - 2] {
+        let src = format!("1{}", "+1".repeat(n));
+        let ast: Ast = src.parse().unwrap();
+        assert_eq!(
+            Interpreter::new().eval(&ast),
+            Ok(Value::Num(Number::Int(n as i64 + 1)))
+        );
+        let formatted = AstFormatter::new(&OperatorTable::default()).format(&ast);
+        assert_eq!(formatted, format!("1{}", " + 1".repeat(n)));
+    }
+}
+
+#[test]
+fn test_nesting_too_deep() {
+    let nested = |n: usize| format!("{}1{}", "(".repeat(n), ")".repeat(n));
+    // 上限までの入れ子は解析でき、評価しても再帰が深すぎない
+    let ast: Ast = nested(MAX_DEPTH - 1).parse().unwrap();
+    assert_eq!(
+        Interpreter::new().eval(&ast),
+        Ok(Value::Num(Number::Int(1)))
+    );
+
+    // 深すぎる入力はスタックを使い切る前にエラーにし、残りを読み飛ばす
+    let deep = [
+        nested(100_000),
+        "(".repeat(100_000),
+        format!("{}1", "-".repeat(100_000)),
+        format!("1{}", "^1".repeat(100_000)),
+        format!("f({}1{})", "g(".repeat(100_000), ")".repeat(100_000)),
+    ];
+    for src in &deep {
+        let (_, errors) = OperatorTable::default().parse_partial(src).unwrap();
+        assert!(
+            matches!(errors[0], ParseError::NestingTooDeep(_)),
+            "{:?}",
+            errors[0]
+        );
+    }
+    // 入れ子の外では解析を続ける
+    let src = format!("{} + )", nested(MAX_DEPTH));
+    let (_, errors) = OperatorTable::default().parse_partial(&src).unwrap();
+    let at = 2 * MAX_DEPTH + 4;
+    let rparen = Token::rparen(Loc(at, at + 1));
+    assert_eq!(
+        errors[1..],
+        [
+            ParseError::NotExpression(rparen.clone()),
+            ParseError::RedundantExpression(rparen)
+        ]
+    );
+}
+
+/// 字句解析エラーと構文解析エラーを統合するエラー型
+#[derive(Debug, Clone, PartialEq)]
+#[non_exhaustive]
+pub enum Error {
+    Lexer(LexError),
+    /// 1回の解析で見つかった構文エラーすべて。空になることはない
+    Parser(Vec<ParseError>),
+}
+
+impl From<LexError> for Error {
+    fn from(e: LexError) -> Self {
+        Error::Lexer(e)
+    }
+}
+
+impl From<Vec<ParseError>> for Error {
+    fn from(errors: Vec<ParseError>) -> Self {
+        Error::Parser(errors)
+    }
+}
+
+impl FromStr for Ast {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // 構文解析器が字句解析器からトークンを取り出しながら解析する
+        OperatorTable::default().parse(s)
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use self::ParseError::*;
+        match self {
+            UnexpectedToken(tok) => write!(f, "{}: {} is not expected", tok.loc, tok.value),
+            NotExpression(tok) => write!(
+                f,
+                "{}: '{}' is not a start of expression",
+                tok.loc, tok.value
+            ),
+            NotOperator(tok) => write!(f, "{}: '{}' is not an operator", tok.loc, tok.value),
+            UnclosedOpenParen(tok) => write!(f, "{}: '{}' is not closed", tok.loc, tok.value),
+            RedundantExpression(tok) => write!(
+                f,
+                "{}: expression after '{}' is redundant",
+                tok.loc, tok.value
+            ),
+            NestingTooDeep(tok) => write!(
+                f,
+                "{}: expression nested too deeply at '{}'",
+                tok.loc, tok.value
+            ),
+            Eof => write!(f, "End of file"),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "parser error")
+    }
+}
+
+impl StdError for ParseError {}
+
+impl StdError for Error {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        use self::Error::*;
+        match self {
+            Lexer(lex) => Some(lex),
+            // 原因としては最初の構文エラーを返す
+            Parser(errors) => errors.first().map(|e| e as &dyn StdError),
+        }
+    }
+}
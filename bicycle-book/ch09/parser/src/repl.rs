@@ -0,0 +1,494 @@
+//! 対話環境。入力をモードに応じて評価、コンパイル、表示する
+
+use parser::compile::{
+    DotEmitter, LatexEmitter, RpnCompiler, RpnReader, SexprEmitter, Vm, WatCompiler,
+};
+use parser::{
+    derivative, Ast, AstFormatter, Diagnostic, Emitter, ErrorFormat, Interpreter, OperatorTable,
+    Optimizer, SourceMap, Token, TokenKind, Tracer, Type, TypeChecker, TypeError,
+};
+use std::io;
+use std::io::Write;
+use std::str::FromStr;
+
+/// REPLの表示モード。:modeで切り替える
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Mode {
+    /// 型を検査して評価する
+    Eval,
+    /// 逆ポーランド記法にコンパイルする
+    Rpn,
+    /// 構文木を表示する
+    Ast,
+    /// トークン列を表示する
+    Tokens,
+    /// WATモジュールを出力する
+    Wat,
+    /// S式を出力する
+    Sexpr,
+    /// LaTeXの数式を出力する
+    Latex,
+    /// GraphvizのDOT形式で木の形を出力する
+    Dot,
+    /// 逆ポーランド記法を読んで中置記法に戻す
+    FromRpn,
+}
+
+impl Mode {
+    const ALL: &'static [Mode] = &[
+        Mode::Eval,
+        Mode::Rpn,
+        Mode::Ast,
+        Mode::Tokens,
+        Mode::Wat,
+        Mode::Sexpr,
+        Mode::Latex,
+        Mode::Dot,
+        Mode::FromRpn,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            Mode::Eval => "eval",
+            Mode::Rpn => "rpn",
+            Mode::Ast => "ast",
+            Mode::Tokens => "tokens",
+            Mode::Wat => "wat",
+            Mode::Sexpr => "sexpr",
+            Mode::Latex => "latex",
+            Mode::Dot => "dot",
+            Mode::FromRpn => "from-rpn",
+        }
+    }
+}
+
+impl FromStr for Mode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Mode::ALL
+            .iter()
+            .copied()
+            .find(|mode| mode.name() == s)
+            .ok_or_else(|| {
+                let names: Vec<_> = Mode::ALL.iter().map(|mode| mode.name()).collect();
+                format!("unknown mode `{}` (expected {})", s, names.join("|"))
+            })
+    }
+}
+
+/// 行の履歴を保存するファイル。カレントディレクトリに置く
+const HISTORY_FILE: &str = ".parser_history";
+
+const REPL_HELP: &str = "\
+:mode [<mode>]      show or switch what is done with the input
+                    (eval, rpn, ast, tokens, wat, sexpr, latex, dot, from-rpn)
+:vars               list defined variables and functions
+:reset              forget all variables and functions
+:load <file>        run a script in the current mode
+:type <expr>        show the inferred type of an expression
+:trace <stmt>       show each step of evaluating a statement
+:diff <var> <expr>  show the derivative of an expression
+:opt <expr>         show an expression before and after optimization
+:help               show this message
+Unclosed parentheses continue the input on the next line.";
+
+/// 対話環境。変数と関数は入力をまたいで保持する
+struct Repl {
+    ops: OperatorTable,
+    mode: Mode,
+    bigint: bool,
+    /// trueならインタプリタの代わりにスタックマシンで評価する
+    use_vm: bool,
+    format: ErrorFormat,
+    interp: Interpreter,
+    vm: Vm,
+    checker: TypeChecker,
+    compiler: RpnCompiler,
+    optimizer: Optimizer,
+}
+
+impl Repl {
+    fn new(mode: Mode, bigint: bool, use_vm: bool, format: ErrorFormat) -> Self {
+        let mut repl = Repl {
+            ops: OperatorTable::default(),
+            mode,
+            bigint,
+            use_vm,
+            format,
+            interp: Interpreter::new(),
+            vm: Vm::new(),
+            checker: TypeChecker::new(),
+            compiler: RpnCompiler::new(),
+            optimizer: Optimizer::new(),
+        };
+        repl.reset();
+        repl
+    }
+
+    /// 変数と関数の定義を忘れる
+    fn reset(&mut self) {
+        if self.bigint {
+            self.interp = Interpreter::with_bigint();
+            self.vm = Vm::with_bigint();
+        } else {
+            self.interp = Interpreter::new();
+            self.vm = Vm::new();
+        }
+        self.checker = TypeChecker::new();
+    }
+
+    /// 入力を読み終えるにはまだ行が必要か。式を引数に取るコマンドは引数の括弧を数える
+    fn needs_more(&self, input: &str) -> bool {
+        let trimmed = input.trim_start();
+        let expr = if !trimmed.starts_with(':') {
+            input
+        } else if let Some(expr) = trimmed.strip_prefix(":type ") {
+            expr
+        } else if let Some(expr) = trimmed.strip_prefix(":opt ") {
+            expr
+        } else if let Some(expr) = trimmed.strip_prefix(":trace ") {
+            expr
+        } else if let Some(expr) = trimmed.strip_prefix(":diff ") {
+            expr
+        } else {
+            return false;
+        };
+        self.ops.unclosed_parens(expr) > 0
+    }
+
+    /// 1つの入力を処理する。エラーは標準エラー出力に報告してfalseを返す
+    fn handle(&mut self, input: &str, out: &mut dyn Write) -> io::Result<bool> {
+        let trimmed = input.trim();
+        if !trimmed.starts_with(':') {
+            return self.run_source("<stdin>", input, out);
+        }
+        let (cmd, arg) = match trimmed.find(char::is_whitespace) {
+            Some(i) => (&trimmed[..i], trimmed[i..].trim()),
+            None => (trimmed, &trimmed[trimmed.len()..]),
+        };
+        // 引数の式の位置は入力した行の位置に直して表示する。argはinputの部分文字列
+        let at = arg.as_ptr() as usize - input.as_ptr() as usize;
+        match (cmd, arg) {
+            (":help", "") => writeln!(out, "{}", REPL_HELP)?,
+            (":mode", "") => writeln!(out, "{}", self.mode.name())?,
+            (":mode", name) => match name.parse() {
+                Ok(mode) => self.mode = mode,
+                Err(msg) => return Ok(self.fail(&msg)),
+            },
+            (":vars", "") => self.show_vars(out)?,
+            (":reset", "") => self.reset(),
+            (":load", "") => return Ok(self.fail(":load needs a file name")),
+            (":load", path) => match std::fs::read_to_string(path) {
+                Ok(src) => return self.run_source(path, &src, out),
+                Err(e) => return Ok(self.fail(&format!("{}: {}", path, e))),
+            },
+            (":type", expr) => {
+                let ty = self
+                    .ops
+                    .parse(expr)
+                    .map_err(|e| e.diagnostics(expr))
+                    .and_then(|ast| {
+                        let ty = self.checker.type_of(&ast);
+                        ty.map_err(|errors| errors.iter().map(TypeError::diagnostic).collect())
+                    });
+                match ty {
+                    Ok(ty) => writeln!(out, "{}", ty)?,
+                    Err(diagnostics) => return Ok(self.report_at(input, at, diagnostics)),
+                }
+            }
+            (":trace", _) if self.use_vm => {
+                return Ok(self.fail(":trace needs the interpreter (run without --vm)"))
+            }
+            (":trace", expr) => {
+                let ast = match self.ops.parse(expr) {
+                    Ok(ast) => ast,
+                    Err(e) => return Ok(self.report_at(input, at, e.diagnostics(expr))),
+                };
+                if let Err(errors) = self.checker.check(&ast) {
+                    let diagnostics = errors.iter().map(TypeError::diagnostic).collect();
+                    return Ok(self.report_at(input, at, diagnostics));
+                }
+                let trace = Tracer::new(&mut self.interp, &self.ops).trace(expr, &ast);
+                writeln!(out, "{}", trace)?;
+                if let Err(e) = trace.result {
+                    return Ok(self.report_at(input, at, vec![e.diagnostic()]));
+                }
+            }
+            (":diff", arg) => {
+                let (var, expr) = match arg.find(char::is_whitespace) {
+                    Some(i) => (&arg[..i], arg[i..].trim_start()),
+                    None => return Ok(self.fail(":diff needs a variable and an expression")),
+                };
+                if !matches!(
+                    self.ops.lex(var).as_deref(),
+                    Ok([Token {
+                        value: TokenKind::Ident(_),
+                        ..
+                    }])
+                ) {
+                    return Ok(self.fail(&format!("`{}` is not a variable", var)));
+                }
+                let at = expr.as_ptr() as usize - input.as_ptr() as usize;
+                let d = self
+                    .ops
+                    .parse(expr)
+                    .map_err(|e| e.diagnostics(expr))
+                    .and_then(|ast| derivative(&ast, var).map_err(|e| vec![e.diagnostic()]));
+                match d {
+                    Ok(d) => writeln!(out, "{}", AstFormatter::new(&self.ops).format(&d))?,
+                    Err(diagnostics) => return Ok(self.report_at(input, at, diagnostics)),
+                }
+            }
+            (":opt", expr) => match self.ops.parse(expr) {
+                Ok(ast) => {
+                    let fmt = AstFormatter::new(&self.ops);
+                    writeln!(out, "before: {}", fmt.format(&ast))?;
+                    writeln!(
+                        out,
+                        "after:  {}",
+                        fmt.format(&self.optimizer.optimize(&ast))
+                    )?;
+                }
+                Err(e) => return Ok(self.report_at(input, at, e.diagnostics(expr))),
+            },
+            _ => return Ok(self.fail(&format!("unknown command `{}` (try :help)", trimmed))),
+        }
+        Ok(true)
+    }
+
+    /// 定義済みの変数を値と型とともに、関数を引数と戻り値の型とともに名前順に表示する
+    fn show_vars(&self, out: &mut dyn Write) -> io::Result<()> {
+        let (mut vars, mut funcs): (Vec<_>, Vec<_>) = if self.use_vm {
+            (self.vm.variables().collect(), self.vm.functions().collect())
+        } else {
+            let interp = &self.interp;
+            (interp.variables().collect(), interp.functions().collect())
+        };
+        vars.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, value) in vars {
+            let ty = self.checker.var_type(name).unwrap_or(&Type::Any);
+            writeln!(out, "{}: {} = {}", name, ty, value)?;
+        }
+        funcs.sort_by(|a, b| a.0.cmp(b.0));
+        for (name, params) in funcs {
+            let ty = self.checker.return_type(name).unwrap_or(&Type::Any);
+            writeln!(out, "fn {}({}) -> {}", name, params.join(", "), ty)?;
+        }
+        Ok(())
+    }
+
+    /// ソースを現在のモードで処理する。構文エラーはすべての文について報告し、
+    /// 型や実行時のエラーは最初の1つで止まる
+    fn run_source(&mut self, name: &str, src: &str, out: &mut dyn Write) -> io::Result<bool> {
+        match self.mode {
+            Mode::Tokens => match self.ops.lex(src) {
+                Ok(tokens) => {
+                    for tok in tokens {
+                        writeln!(out, "{} {:?}", tok.loc, tok.value)?;
+                    }
+                }
+                Err(e) => return Ok(self.report(name, src, vec![e.diagnostic()])),
+            },
+            // 逆ポーランド記法は1行を1つの式として読む
+            Mode::FromRpn => {
+                let mut diagnostics = Vec::new();
+                let mut start = 0;
+                for line in src.split('\n') {
+                    if !line.trim().is_empty() {
+                        match RpnReader::new().read(line) {
+                            Ok(ast) => {
+                                writeln!(out, "{}", AstFormatter::new(&self.ops).format(&ast))?
+                            }
+                            Err(e) => diagnostics.push(e.diagnostic().offset(start)),
+                        }
+                    }
+                    start += line.len() + 1;
+                }
+                if !diagnostics.is_empty() {
+                    return Ok(self.report(name, src, diagnostics));
+                }
+            }
+            _ => {
+                let mut stmts = Vec::new();
+                let mut diagnostics = Vec::new();
+                for (span, result) in self.ops.parse_script(src) {
+                    match result {
+                        Ok(ast) => stmts.push(ast),
+                        Err(e) => diagnostics.extend(e.diagnostics(&src[..span.1])),
+                    }
+                }
+                if !diagnostics.is_empty() {
+                    return Ok(self.report(name, src, diagnostics));
+                }
+                for ast in &stmts {
+                    match self.exec_stmt(ast) {
+                        Ok(Some(text)) => writeln!(out, "{}", text)?,
+                        Ok(None) => (),
+                        Err(diagnostics) => return Ok(self.report(name, src, diagnostics)),
+                    }
+                }
+            }
+        }
+        Ok(true)
+    }
+
+    /// 文を現在のモードで処理し、表示する文字列を返す
+    fn exec_stmt(&mut self, ast: &Ast) -> Result<Option<String>, Vec<Diagnostic>> {
+        match self.mode {
+            Mode::Ast => Ok(Some(format!("{:?}", ast))),
+            Mode::Rpn => Ok(Some(self.compiler.compile(&self.optimizer.optimize(ast)))),
+            Mode::Sexpr => Ok(Some(SexprEmitter::new().emit(ast))),
+            Mode::Latex => Ok(Some(LatexEmitter::new(&self.ops).emit(ast))),
+            Mode::Dot => Ok(Some(DotEmitter::new().emit(ast))),
+            Mode::Wat => match WatCompiler::new().compile(ast) {
+                Ok(wat) => Ok(Some(wat.trim_end().to_string())),
+                Err(e) => Err(vec![e.diagnostic()]),
+            },
+            Mode::Eval => {
+                // 評価する前に型を検査し、見つかったエラーをすべて報告する
+                if let Err(errors) = self.checker.check(ast) {
+                    return Err(errors.iter().map(TypeError::diagnostic).collect());
+                }
+                let ret = if self.use_vm {
+                    self.vm.exec(ast)
+                } else {
+                    self.interp.exec(ast)
+                };
+                // 関数定義は値を持たない
+                ret.map(|n| n.map(|n| n.to_string()))
+                    .map_err(|e| vec![e.diagnostic()])
+            }
+            Mode::Tokens | Mode::FromRpn => unreachable!("handled by run_source"),
+        }
+    }
+
+    fn report(&self, name: &str, src: &str, diagnostics: Vec<Diagnostic>) -> bool {
+        self.format.emit(&SourceMap::new(name, src), &diagnostics);
+        false
+    }
+
+    /// inputのat以降を解析したときの診断を入力全体の位置に直して報告する
+    fn report_at(&self, input: &str, at: usize, diagnostics: Vec<Diagnostic>) -> bool {
+        let diagnostics = diagnostics.into_iter().map(|d| d.offset(at)).collect();
+        self.report("<stdin>", input, diagnostics)
+    }
+
+    fn fail(&self, msg: &str) -> bool {
+        eprintln!("error: {}", msg);
+        false
+    }
+}
+
+#[test]
+fn test_repl() {
+    let mut repl = Repl::new(Mode::Eval, false, false, ErrorFormat::Human);
+    let mut run = |input: &str| {
+        let mut out = Vec::new();
+        let ok = repl.handle(input, &mut out).unwrap();
+        (ok, String::from_utf8(out).unwrap())
+    };
+    assert_eq!(
+        run("let x = 2; fn sq(a) = a * a"),
+        (true, "2\n".to_string())
+    );
+    assert_eq!(
+        run(":vars"),
+        (true, "x: int = 2\nfn sq(a) -> number\n".to_string())
+    );
+    assert_eq!(run(":mode rpn"), (true, String::new()));
+    assert_eq!(run("sq(x) + 1"), (true, "x sq@1 1 +\n".to_string()));
+    assert_eq!(run(":mode"), (true, "rpn\n".to_string()));
+    assert_eq!(run(":mode tokens"), (true, String::new()));
+    assert_eq!(
+        run("1+x"),
+        (
+            true,
+            "0-1 Number(1)\n1-2 Plus\n2-3 Ident(\"x\")\n".to_string()
+        )
+    );
+    assert!(!run(":mode bytes").0);
+    run(":mode eval");
+    assert_eq!(run(" :type x * 2 "), (true, "int\n".to_string()));
+    assert_eq!(run(":reset"), (true, String::new()));
+    assert_eq!(run(":vars"), (true, String::new()));
+    assert!(!run("x").0);
+    assert!(!run(":nope").0);
+    assert!(run(":help").1.contains(":load <file>"));
+    assert_eq!(
+        run(":trace let y = 1 + 2 * 3"),
+        (
+            true,
+            "let y = 1 + 2 * 3\n            ^^^^^\nlet y = 1 + 6\n        ^^^^^\nlet y = 7\n"
+                .to_string()
+        )
+    );
+    assert_eq!(run("y"), (true, "7\n".to_string()));
+    assert_eq!(run(":diff t t ^ 2 * y"), (true, "2 * t * y\n".to_string()));
+    assert!(!run(":diff 1 x").0);
+    assert!(repl.needs_more(":type (1 +"));
+    assert!(!repl.needs_more(":load (a"));
+}
+
+/// REPLの起動時の設定
+#[derive(Debug, Clone, PartialEq)]
+pub struct ReplOptions {
+    pub mode: Mode,
+    pub bigint: bool,
+    pub vm: bool,
+    pub format: ErrorFormat,
+}
+
+/// 標準入力から1入力ずつ読んで処理する。入力が終われば0を返す
+pub fn run(opts: &ReplOptions) -> i32 {
+    let mut repl = Repl::new(opts.mode, opts.bigint, opts.vm, opts.format);
+
+    let mut editor = match rustyline::DefaultEditor::new() {
+        Ok(editor) => editor,
+        Err(e) => {
+            eprintln!("error: {}", e);
+            return 2;
+        }
+    };
+    // 端末から使うときだけ履歴を読み書きする。最初はファイルがないので読めなくてよい
+    let interactive = {
+        use std::io::IsTerminal;
+        io::stdin().is_terminal()
+    };
+    if interactive {
+        let _ = editor.load_history(HISTORY_FILE);
+    }
+    let stdout = io::stdout();
+    loop {
+        // 括弧が閉じるまで続きの行を読む
+        let mut input = match editor.readline("> ") {
+            Ok(line) => line,
+            Err(rustyline::error::ReadlineError::Interrupted) => continue,
+            Err(_) => break,
+        };
+        while repl.needs_more(&input) {
+            match editor.readline(". ") {
+                Ok(line) => {
+                    input.push('\n');
+                    input.push_str(&line);
+                }
+                Err(_) => break,
+            }
+        }
+        if input.trim().is_empty() {
+            continue;
+        }
+        if interactive {
+            let _ = editor.add_history_entry(input.as_str());
+        }
+        if repl.handle(&input, &mut stdout.lock()).is_err() {
+            break;
+        }
+    }
+    if interactive {
+        if let Err(e) = editor.save_history(HISTORY_FILE) {
+            eprintln!("{}: {}", HISTORY_FILE, e);
+        }
+    }
+    0
+}
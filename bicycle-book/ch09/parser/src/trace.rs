@@ -0,0 +1,309 @@
+//! 式の評価を1ステップずつ見せるトレーサ
+
+use crate::ast::{ensure_stack, Ast, AstKind, BinOpKind, UniOpKind};
+use crate::diagnostics::text_width;
+use crate::format::AstFormatter;
+use crate::interp::{Interpreter, InterpreterError, Value};
+#[cfg(test)]
+use crate::interp::{InterpreterErrorKind, Number};
+use crate::lexer::Loc;
+use crate::parser::{Assoc, OperatorTable};
+use std::fmt;
+
+/// 置き換えた値が置かれる位置。負の数や分数に括弧が要るかを決める
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Operand {
+    /// 演算子の被演算子ではない
+    Free,
+    /// 二項演算子の左辺
+    Left(u8, Assoc),
+    /// 二項演算子の右辺
+    Right(u8, Assoc),
+    /// 前置演算子の被演算子
+    Prefix(u8),
+}
+
+/// 簡約の1ステップ。textの中のlocの部分式を値に置き換えた
+#[derive(Debug, Clone, PartialEq)]
+pub struct TraceStep {
+    pub text: String,
+    pub loc: Loc,
+}
+
+/// 式を評価する過程
+#[derive(Debug, Clone, PartialEq)]
+pub struct Trace {
+    pub steps: Vec<TraceStep>,
+    /// 簡約し終えた式。途中でエラーになったらそこまで簡約した式
+    pub text: String,
+    /// 文を実行した結果。関数定義は値を持たない
+    pub result: Result<Option<Value>, InterpreterError>,
+}
+
+impl fmt::Display for Trace {
+    /// 各ステップの式と、その下に簡約した部分式の下線を表示する
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        // 改行は空白にしてもバイト位置が変わらない
+        let oneline = |s: &str| s.replace('\n', " ");
+        for step in &self.steps {
+            let text = oneline(&step.text);
+            writeln!(
+                f,
+                "{}\n{}{}",
+                text,
+                " ".repeat(text_width(&text[..step.loc.0])),
+                "^".repeat(text_width(&text[step.loc.0..step.loc.1]).max(1))
+            )?;
+        }
+        write!(f, "{}", oneline(&self.text))
+    }
+}
+
+/// インタプリタが式を簡約していく過程を記録する評価器
+/// 部分式は評価する順に1つずつ値に置き換え、元のソースを書き換えながら各ステップを記録する
+/// ユーザ定義の関数の本体には入らず、呼び出し全体を1ステップとする
+pub struct Tracer<'a> {
+    interp: &'a mut Interpreter,
+    /// 置き換えた値に括弧が要るかを演算子の結合力から決める
+    fmt: AstFormatter<'a>,
+    src: &'a str,
+    /// これまでに値に置き換えた元のソースの区間と値の表記。位置の順に並び、重ならない
+    edits: Vec<(Loc, String)>,
+    steps: Vec<TraceStep>,
+}
+
+impl<'a> Tracer<'a> {
+    /// 変数と関数はinterpのものを使い、letやfnの定義もinterpに残す
+    pub fn new(interp: &'a mut Interpreter, ops: &'a OperatorTable) -> Self {
+        Tracer {
+            interp,
+            fmt: AstFormatter::new(ops),
+            src: "",
+            edits: Vec::new(),
+            steps: Vec::new(),
+        }
+    }
+
+    /// srcを解析したstmtを実行し、その過程を返す。各ステップの式はsrc全体を書き換えたもの
+    pub fn trace(mut self, src: &'a str, stmt: &Ast) -> Trace {
+        use self::AstKind::*;
+        self.src = src;
+        let result = match stmt.value {
+            FnDef { .. } => self.interp.exec(stmt),
+            Let { ref e, .. } => self
+                .reduce(e, Operand::Free)
+                .and_then(|_| self.interp.exec(stmt)),
+            _ => self.reduce(stmt, Operand::Free).map(Some),
+        };
+        Trace {
+            text: self.text(),
+            steps: self.steps,
+            result,
+        }
+    }
+
+    /// 評価される部分式を先に簡約してから、式全体を値に置き換える
+    fn reduce(&mut self, expr: &Ast, at: Operand) -> Result<Value, InterpreterError> {
+        ensure_stack(|| self.reduce_inner(expr, at))
+    }
+
+    fn reduce_inner(&mut self, expr: &Ast, at: Operand) -> Result<Value, InterpreterError> {
+        use self::AstKind::*;
+        match expr.value {
+            // リテラルはすでに値
+            Num(_) | Float(_) | Bool(_) => return self.interp.eval(expr),
+            Var(_) => (),
+            Call { ref args, .. } => {
+                for arg in args {
+                    self.reduce(arg, Operand::Free)?;
+                }
+            }
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => {
+                // 選ばれなかった側は簡約しない
+                if self.reduce(cond, Operand::Free)?.into_bool(&cond.loc)? {
+                    self.reduce(then, Operand::Free)?;
+                } else {
+                    self.reduce(els, Operand::Free)?;
+                }
+            }
+            UniOp { ref op, ref e } => {
+                self.reduce(e, Operand::Prefix(self.fmt.prefix_bp(&op.value)))?;
+            }
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => {
+                let (bp, assoc) = self.fmt.infix_bp(&op.value);
+                let lv = self.reduce(l, Operand::Left(bp, assoc))?;
+                // 短絡評価で右辺が要らなければ簡約しない
+                let short = match (&op.value, &lv) {
+                    (BinOpKind::And, Value::Bool(b)) => !*b,
+                    (BinOpKind::Or, Value::Bool(b)) => *b,
+                    _ => false,
+                };
+                if !short {
+                    self.reduce(r, Operand::Right(bp, assoc))?;
+                }
+            }
+            Let { .. } | FnDef { .. } | Error => return self.interp.eval(expr),
+        }
+        // 部分式はすでに評価できたので、式全体を評価し直しても同じ値とエラーになる
+        let value = self.interp.eval(expr)?;
+        self.replace(&expr.loc, &value, at);
+        Ok(value)
+    }
+
+    /// 元のソースのlocの区間を値に置き換えて、そのステップを記録する
+    fn replace(&mut self, loc: &Loc, value: &Value, at: Operand) {
+        let loc = &self.balance(loc);
+        let text = self.text();
+        let start = self.to_current(loc.0);
+        let end = self.to_current(loc.1);
+        let mut repr = value.to_string();
+        // すでに括弧で囲まれていれば囲まなくてよい
+        let enclosed =
+            text[..start].trim_end().ends_with('(') && text[end..].trim_start().starts_with(')');
+        if !enclosed && self.needs_paren(&repr, at) {
+            repr = format!("({})", repr);
+        }
+        self.steps.push(TraceStep {
+            text,
+            loc: Loc(start, end),
+        });
+        // 置き換えた区間の中の置き換えは新しい置き換えにまとめる
+        self.edits.retain(|(l, _)| l.1 <= loc.0 || loc.1 <= l.0);
+        let i = self.edits.partition_point(|(l, _)| l.1 <= loc.0);
+        self.edits.insert(i, (loc.clone(), repr));
+    }
+
+    /// 括弧で囲んだ被演算子は括弧の中の位置を持つので、二項演算の区間は括弧の片側で切れることがある
+    /// 対応する括弧まで区間を広げる
+    fn balance(&self, loc: &Loc) -> Loc {
+        let depth = |s: &str| s.matches('(').count() as isize - s.matches(')').count() as isize;
+        let (mut start, mut end) = (loc.0, loc.1);
+        while start > 0 && depth(&self.src[start..end]) < 0 {
+            start = self.src[..start].rfind('(').unwrap_or(0);
+        }
+        while end < self.src.len() && depth(&self.src[start..end]) > 0 {
+            end = self.src[end..]
+                .find(')')
+                .map_or(self.src.len(), |i| end + i + 1);
+        }
+        Loc(start, end)
+    }
+
+    /// 値の表記をatに置いたとき、周りの演算子と結合しないよう括弧が要るか
+    /// 負の数は前置の-、分数は/の式として読まれる。AstFormatterと同じ規則で判断する
+    fn needs_paren(&self, repr: &str, at: Operand) -> bool {
+        let (div_bp, _) = self.fmt.infix_bp(&BinOpKind::Div);
+        let neg_bp = self.fmt.prefix_bp(&UniOpKind::Minus);
+        if repr.contains('/') {
+            match at {
+                Operand::Free => false,
+                Operand::Left(bp, assoc) => div_bp < bp || (div_bp == bp && assoc == Assoc::Right),
+                Operand::Right(bp, assoc) => div_bp < bp || (div_bp == bp && assoc == Assoc::Left),
+                Operand::Prefix(bp) => div_bp < bp,
+            }
+        } else if repr.starts_with('-') {
+            // 前置演算子は右側に置けば後ろの演算子を取り込まないが、左辺では取り込む
+            match at {
+                Operand::Left(bp, _) => neg_bp <= bp,
+                _ => false,
+            }
+        } else {
+            false
+        }
+    }
+
+    /// 元のソースの位置を、これまでの置き換えを適用したソースの位置に直す
+    fn to_current(&self, pos: usize) -> usize {
+        self.edits
+            .iter()
+            .take_while(|(l, _)| l.1 <= pos)
+            .fold(pos, |pos, (l, repr)| pos + repr.len() - (l.1 - l.0))
+    }
+
+    /// これまでの置き換えを適用したソース
+    fn text(&self) -> String {
+        let mut text = String::new();
+        let mut pos = 0;
+        for (loc, repr) in &self.edits {
+            text.push_str(&self.src[pos..loc.0]);
+            text.push_str(repr);
+            pos = loc.1;
+        }
+        text.push_str(&self.src[pos..]);
+        text
+    }
+}
+
+#[test]
+fn test_tracer() {
+    let ops = OperatorTable::default();
+    let trace = |interp: &mut Interpreter, src: &str| {
+        let ast = src.parse::<Ast>().unwrap();
+        Tracer::new(interp, &ops).trace(src, &ast)
+    };
+    let mut interp = Interpreter::new();
+    let t = trace(&mut interp, "1 + 2 * 3");
+    assert_eq!(
+        t.steps,
+        vec![
+            TraceStep {
+                text: "1 + 2 * 3".to_string(),
+                loc: Loc(4, 9)
+            },
+            TraceStep {
+                text: "1 + 6".to_string(),
+                loc: Loc(0, 5)
+            },
+        ]
+    );
+    assert_eq!(t.text, "7");
+    assert_eq!(t.result, Ok(Some(Value::Num(Number::Int(7)))));
+    assert_eq!(t.to_string(), "1 + 2 * 3\n    ^^^^^\n1 + 6\n^^^^^\n7");
+
+    // letの定義は残る。負の数は括弧で囲む
+    let t = trace(&mut interp, "let x = 1 - 4");
+    assert_eq!(t.text, "let x = -3");
+    let steps = |t: &Trace| t.steps.iter().map(|s| s.text.clone()).collect::<Vec<_>>();
+    let t = trace(&mut interp, "x ^ 2 + (x)");
+    assert_eq!(
+        steps(&t),
+        vec!["x ^ 2 + (x)", "(-3) ^ 2 + (x)", "9 + (x)", "9 + (-3)"]
+    );
+    assert_eq!(t.text, "6");
+    trace(&mut interp, "let h = 1 / 2");
+    let t = trace(&mut interp, "h ^ 2 - h");
+    assert_eq!(
+        steps(&t),
+        vec!["h ^ 2 - h", "(1/2) ^ 2 - h", "1/4 - h", "1/4 - 1/2"]
+    );
+    assert_eq!(t.text, "-1/4");
+
+    // 短絡評価とifは評価されない側を簡約しない
+    let t = trace(&mut interp, "if x < 0 || y then 1 else y");
+    assert_eq!(
+        steps(&t),
+        vec![
+            "if x < 0 || y then 1 else y",
+            "if -3 < 0 || y then 1 else y",
+            "if true || y then 1 else y",
+            "if true then 1 else y",
+        ]
+    );
+    assert_eq!(t.text, "1");
+
+    // エラーになったらそこまでの過程を返す
+    let t = trace(&mut interp, "abs(x) + 1 / (x + 3)");
+    assert_eq!(t.text, "3 + 1 / (0)");
+    assert_eq!(
+        t.result.map_err(|e| e.value),
+        Err(InterpreterErrorKind::DivisionByZero)
+    );
+}
@@ -0,0 +1,427 @@
+//! 実行前に型の誤りを見つける型検査器
+
+use crate::ast::{ensure_stack, Ast, AstKind, BinOpKind, UniOpKind};
+use crate::diagnostics::Diagnostic;
+use crate::lexer::{Annot, Loc};
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+
+/// 型検査で推論する型
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum Type {
+    /// 整数
+    Int,
+    /// 有理数。整数も含む
+    Rational,
+    /// 浮動小数点数
+    Float,
+    /// どの表現になるか実行するまでわからない数値
+    Number,
+    /// 真偽値
+    Bool,
+    /// 実行するまでわからない型。関数の引数など
+    Any,
+}
+
+impl Type {
+    /// エラーメッセージに使う型の名前
+    pub(crate) fn name(self) -> &'static str {
+        use self::Type::*;
+        match self {
+            Int => "int",
+            Rational => "rational",
+            Float => "float",
+            Number => "number",
+            Bool => "bool",
+            Any => "any",
+        }
+    }
+
+    fn is_number(self) -> bool {
+        use self::Type::*;
+        matches!(self, Int | Rational | Float | Number)
+    }
+
+    /// 二項演算の結果の型。数値の昇格規則に従う
+    fn join(self, other: Type) -> Type {
+        use self::Type::*;
+        match (self, other) {
+            (Float, _) | (_, Float) => Float,
+            (Int, Int) => Int,
+            (Int | Rational, Int | Rational) => Rational,
+            _ => Number,
+        }
+    }
+
+    /// ifの枝やmin、maxのように、どちらか一方の値がそのまま結果になる式の型
+    /// 昇格は起きないので、浮動小数点数とそれ以外が混ざれば実行するまでどちらになるかわからない
+    fn either(self, other: Type) -> Type {
+        use self::Type::*;
+        match (self, other) {
+            (t, e) if t == e => t,
+            // 整数は有理数にも含まれる
+            (Int | Rational, Int | Rational) => Rational,
+            _ => Number,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Hash)]
+#[non_exhaustive]
+pub enum TypeErrorKind {
+    /// 演算が求める型と式の型が合わない
+    Mismatch { expected: Type, found: Type },
+}
+
+pub type TypeError = Annot<TypeErrorKind>;
+
+impl TypeError {
+    fn mismatch(expected: Type, found: Type, loc: Loc) -> Self {
+        Self::new(TypeErrorKind::Mismatch { expected, found }, loc)
+    }
+}
+
+/// 評価の前に型を検査するデータ型
+pub struct TypeChecker {
+    /// 変数の型。REPLの行をまたいで保持される
+    vars: HashMap<String, Type>,
+    /// ユーザ定義の関数の戻り値の型
+    funcs: HashMap<String, Type>,
+    /// 検査中の関数の引数
+    params: Vec<String>,
+    /// 見つかった型エラー。検査を止めずにすべて集める
+    errors: Vec<TypeError>,
+}
+
+impl Default for TypeChecker {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TypeChecker {
+    pub fn new() -> Self {
+        TypeChecker {
+            vars: HashMap::new(),
+            funcs: HashMap::new(),
+            params: Vec::new(),
+            errors: Vec::new(),
+        }
+    }
+
+    /// 変数の型。まだ定義されていなければNone
+    pub fn var_type(&self, name: &str) -> Option<&Type> {
+        self.vars.get(name)
+    }
+
+    /// ユーザ定義の関数の戻り値の型。まだ定義されていなければNone
+    pub fn return_type(&self, name: &str) -> Option<&Type> {
+        self.funcs.get(name)
+    }
+
+    /// 文を検査する。エラーがなければletとfnの結果の型を覚えておく
+    pub fn check(&mut self, stmt: &Ast) -> Result<Option<Type>, Vec<TypeError>> {
+        match stmt.value {
+            AstKind::FnDef {
+                ref name,
+                ref params,
+                ref body,
+            } => {
+                // 再帰呼び出しに備えて、戻り値の型がわからないまま先に登録しておく
+                let prev = self.funcs.insert(name.value.clone(), Type::Any);
+                let ty = self.check_fn(params, body);
+                match self.take_errors() {
+                    Ok(()) => {
+                        self.funcs.insert(name.value.clone(), ty);
+                        Ok(None)
+                    }
+                    Err(errors) => {
+                        match prev {
+                            Some(prev) => self.funcs.insert(name.value.clone(), prev),
+                            None => self.funcs.remove(&name.value),
+                        };
+                        Err(errors)
+                    }
+                }
+            }
+            AstKind::Let { ref var, .. } => {
+                let ty = self.type_of(stmt)?;
+                self.vars.insert(var.value.clone(), ty);
+                Ok(Some(ty))
+            }
+            _ => self.type_of(stmt).map(Some),
+        }
+    }
+
+    /// 式の型を推論する。変数や関数の定義は覚えない
+    pub fn type_of(&mut self, expr: &Ast) -> Result<Type, Vec<TypeError>> {
+        let ty = self.infer(expr);
+        self.take_errors().map(|()| ty)
+    }
+
+    fn take_errors(&mut self) -> Result<(), Vec<TypeError>> {
+        if self.errors.is_empty() {
+            Ok(())
+        } else {
+            Err(std::mem::take(&mut self.errors))
+        }
+    }
+
+    fn check_fn(&mut self, params: &[Annot<String>], body: &Ast) -> Type {
+        self.params = params.iter().map(|p| p.value.clone()).collect();
+        let ty = self.infer(body);
+        self.params.clear();
+        ty
+    }
+
+    fn infer(&mut self, expr: &Ast) -> Type {
+        ensure_stack(|| self.infer_inner(expr))
+    }
+
+    fn infer_inner(&mut self, expr: &Ast) -> Type {
+        use self::AstKind::*;
+        match expr.value {
+            Num(_) => Type::Int,
+            Float(_) => Type::Float,
+            Bool(_) => Type::Bool,
+            // 引数の型は呼び出し側次第。未定義の変数は評価時にエラーになる
+            Var(ref name) if self.params.contains(name) => Type::Any,
+            Var(ref name) => self.vars.get(name).cloned().unwrap_or(Type::Any),
+            // 構文エラーの箇所はどの型にもなりうるものとして検査を続ける
+            Error => Type::Any,
+            Let { ref e, .. } => self.infer(e),
+            FnDef {
+                ref params,
+                ref body,
+                ..
+            } => {
+                self.check_fn(params, body);
+                Type::Any
+            }
+            Call { ref name, ref args } => {
+                let tys: Vec<_> = args.iter().map(|arg| self.infer(arg)).collect();
+                if let Some(ty) = self.funcs.get(&name.value) {
+                    return *ty;
+                }
+                // 組み込み関数の引数はすべて数値
+                for (ty, arg) in tys.iter().zip(args) {
+                    self.expect_number(*ty, &arg.loc);
+                }
+                match (name.value.as_str(), tys.as_slice()) {
+                    ("abs", [ty]) => ty.join(Type::Int),
+                    ("min", [l, r]) | ("max", [l, r]) => l.either(*r),
+                    ("pow", [l, r]) => pow_type(*l, *r),
+                    // 平方数なら正確な値、そうでなければ浮動小数点数になる
+                    ("sqrt", [_]) => Type::Number,
+                    _ => Type::Any,
+                }
+            }
+            If {
+                ref cond,
+                ref then,
+                ref els,
+            } => {
+                let ty = self.infer(cond);
+                self.expect_bool(ty, &cond.loc);
+                let t = self.infer(then);
+                let e = self.infer(els);
+                match (t, e) {
+                    (Type::Any, _) | (_, Type::Any) => Type::Any,
+                    (Type::Bool, Type::Bool) => Type::Bool,
+                    (t, e) if t.is_number() && e.is_number() => t.either(e),
+                    // 二つの枝の型はそろっていなければならない
+                    (t, e) => {
+                        let expected = if t.is_number() { Type::Number } else { t };
+                        self.errors
+                            .push(TypeError::mismatch(expected, e, els.loc.clone()));
+                        t
+                    }
+                }
+            }
+            UniOp { ref op, ref e } => {
+                let ty = self.infer(e);
+                match op.value {
+                    UniOpKind::Not => {
+                        self.expect_bool(ty, &e.loc);
+                        Type::Bool
+                    }
+                    _ => self.expect_number(ty, &e.loc),
+                }
+            }
+            BinOp {
+                ref op,
+                ref l,
+                ref r,
+            } => {
+                let lt = self.infer(l);
+                let rt = self.infer(r);
+                self.infer_binop(&op.value, (lt, &l.loc), (rt, &r.loc))
+            }
+        }
+    }
+
+    fn infer_binop(&mut self, op: &BinOpKind, l: (Type, &Loc), r: (Type, &Loc)) -> Type {
+        use self::BinOpKind::*;
+        match op {
+            And | Or => {
+                self.expect_bool(l.0, l.1);
+                self.expect_bool(r.0, r.1);
+                Type::Bool
+            }
+            Eq | Ne => {
+                // 右辺の型を左辺にそろえることを求める
+                match (l.0, r.0) {
+                    (Type::Any, _) | (_, Type::Any) | (Type::Bool, Type::Bool) => (),
+                    (lt, rt) if lt.is_number() && rt.is_number() => (),
+                    (lt, rt) => {
+                        let expected = if lt.is_number() { Type::Number } else { lt };
+                        self.errors
+                            .push(TypeError::mismatch(expected, rt, r.1.clone()));
+                    }
+                }
+                Type::Bool
+            }
+            Lt | Le | Gt | Ge => {
+                self.expect_number(l.0, l.1);
+                self.expect_number(r.0, r.1);
+                Type::Bool
+            }
+            _ => {
+                let lt = self.expect_number(l.0, l.1);
+                let rt = self.expect_number(r.0, r.1);
+                match op {
+                    // 整数同士の割り算は有理数になる
+                    Div if lt == Type::Int && rt == Type::Int => Type::Rational,
+                    Pow => pow_type(lt, rt),
+                    _ => lt.join(rt),
+                }
+            }
+        }
+    }
+
+    /// 数値であることを確かめて、数値の型を返す
+    fn expect_number(&mut self, ty: Type, loc: &Loc) -> Type {
+        match ty {
+            Type::Any => Type::Number,
+            ty if ty.is_number() => ty,
+            ty => {
+                self.errors
+                    .push(TypeError::mismatch(Type::Number, ty, loc.clone()));
+                Type::Number
+            }
+        }
+    }
+
+    /// 真偽値であることを確かめる
+    fn expect_bool(&mut self, ty: Type, loc: &Loc) {
+        if ty != Type::Bool && ty != Type::Any {
+            self.errors
+                .push(TypeError::mismatch(Type::Bool, ty, loc.clone()));
+        }
+    }
+}
+
+/// べき乗の結果の型。整数の指数なら正確に計算され、それ以外は浮動小数点数になりうる
+pub(crate) fn pow_type(l: Type, r: Type) -> Type {
+    use self::Type::*;
+    match (l, r) {
+        (Float, _) | (_, Float) => Float,
+        (Int | Rational, Int) => Rational,
+        _ => Number,
+    }
+}
+
+impl fmt::Display for Type {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.name())
+    }
+}
+
+impl fmt::Display for TypeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.value {
+            TypeErrorKind::Mismatch { expected, found } => {
+                write!(f, "expected {} but found {}", expected, found)
+            }
+        }
+    }
+}
+
+impl StdError for TypeError {
+    fn description(&self) -> &str {
+        match self.value {
+            TypeErrorKind::Mismatch { .. } => {
+                "the expression has a different type than the operation requires"
+            }
+        }
+    }
+}
+
+impl TypeError {
+    pub fn diagnostic(&self) -> Diagnostic {
+        match self.value {
+            TypeErrorKind::Mismatch { expected, found } => Diagnostic::new(
+                "E0201",
+                "mismatched types",
+                self.loc.clone(),
+                format!("expected {}, found {}", expected, found),
+            ),
+        }
+    }
+}
+
+#[test]
+fn test_type_checker() {
+    let mut checker = TypeChecker::new();
+    let mut type_of = |s: &str| checker.type_of(&s.parse().unwrap());
+    assert_eq!(type_of("1 + 2 * 3"), Ok(Type::Int));
+    assert_eq!(type_of("1 / 2"), Ok(Type::Rational));
+    assert_eq!(type_of("1 + 0.5"), Ok(Type::Float));
+    assert_eq!(type_of("2 ^ 3 < 9 || false"), Ok(Type::Bool));
+    assert_eq!(type_of("sqrt(2)"), Ok(Type::Number));
+    // 実行されるのは一方の枝だけなので、枝の型が違えばどちらの数値になるかわからない
+    assert_eq!(type_of("if true then 1 else 2.0"), Ok(Type::Number));
+    assert_eq!(type_of("if true then 1 else 1 / 2"), Ok(Type::Rational));
+    assert_eq!(type_of("if true then 1.5 else 2.0"), Ok(Type::Float));
+    assert_eq!(type_of("min(1, 2.0)"), Ok(Type::Number));
+    assert_eq!(type_of("max(1, 2)"), Ok(Type::Int));
+    // 見つかった型エラーはすべて報告する
+    assert_eq!(
+        type_of("-true + (1 && 2)"),
+        Err(vec![
+            TypeError::mismatch(Type::Number, Type::Bool, Loc(1, 5)),
+            TypeError::mismatch(Type::Bool, Type::Int, Loc(9, 10)),
+            TypeError::mismatch(Type::Bool, Type::Int, Loc(14, 15)),
+            TypeError::mismatch(Type::Number, Type::Bool, Loc(9, 15)),
+        ])
+    );
+    assert_eq!(
+        type_of("if 1 then 2 else false"),
+        Err(vec![
+            TypeError::mismatch(Type::Bool, Type::Int, Loc(3, 4)),
+            TypeError::mismatch(Type::Number, Type::Bool, Loc(17, 22)),
+        ])
+    );
+
+    // letとfnで定義したものの型は覚えておく
+    let mut checker = TypeChecker::new();
+    let mut check = |s: &str| checker.check(&s.parse().unwrap());
+    assert_eq!(check("let x = 1.5"), Ok(Some(Type::Float)));
+    assert_eq!(
+        check("fn f(n) = if n <= 1 then x else n * f(n - 1)"),
+        Ok(None)
+    );
+    // 再帰する枝の型は引数の型がわからないのでnumberになり、floatの枝とあわせてnumberになる
+    assert_eq!(check("f(3) * 2"), Ok(Some(Type::Number)));
+    assert_eq!(
+        check("let y = !x"),
+        Err(vec![TypeError::mismatch(
+            Type::Bool,
+            Type::Float,
+            Loc(9, 10)
+        )])
+    );
+    // エラーになった定義は覚えない
+    assert_eq!(check("y"), Ok(Some(Type::Any)));
+}
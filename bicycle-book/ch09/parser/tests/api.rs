@@ -0,0 +1,112 @@
+// クレートの外から公開APIだけを使う
+use parser::compile::{DotEmitter, LatexEmitter, RpnCompiler, SexprEmitter, Vm, WatCompiler};
+use parser::{
+    derivative, Assoc, Ast, AstFormatter, BinOp, DiffErrorKind, Emitter, Error, Interpreter,
+    InterpreterErrorKind, Loc, OperatorTable, Optimizer, TypeChecker, UniOp, Value,
+};
+
+#[test]
+fn test_parse_and_eval() {
+    let ast: Ast = "1 + 2 * 3".parse().unwrap();
+    assert_eq!(Interpreter::new().eval(&ast).unwrap().to_string(), "7");
+
+    // 文をまたいで変数と関数が残る
+    let mut interp = Interpreter::new();
+    for stmt in &["let x = 2", "fn sq(a) = a * a"] {
+        interp.exec(&stmt.parse().unwrap()).unwrap();
+    }
+    let value = interp.eval(&"sq(x) + 1".parse().unwrap()).unwrap();
+    assert_eq!(value.to_string(), "5");
+    let vars: Vec<_> = interp.variables().map(|(name, _)| name).collect();
+    assert_eq!(vars, ["x"]);
+    let funcs: Vec<_> = interp.functions().collect();
+    assert_eq!(funcs, [("sq", &["a".to_string()][..])]);
+}
+
+#[test]
+fn test_errors() {
+    match "1 +".parse::<Ast>() {
+        Err(Error::Parser(errors)) => {
+            let diagnostics = Error::Parser(errors).diagnostics("1 +");
+            assert_eq!(diagnostics[0].code, "E0106");
+        }
+        other => panic!("unexpected {:?}", other),
+    }
+    assert!(matches!("1 $ 2".parse::<Ast>(), Err(Error::Lexer(_))));
+
+    let e = Interpreter::new()
+        .eval(&"1 / 0".parse().unwrap())
+        .unwrap_err();
+    assert_eq!(e.value, InterpreterErrorKind::DivisionByZero);
+    assert_eq!(e.loc, Loc(0, 5));
+    // 標準のエラー型としても扱える
+    let e: Box<dyn std::error::Error> = Box::new(e);
+    assert!(!e.to_string().is_empty());
+}
+
+#[test]
+fn test_custom_operator() {
+    let mut ops = OperatorTable::default();
+    // <>を!=と同じ意味の演算子として登録する
+    ops.add_infix("<>", 6, Assoc::Left, |loc, l, r| {
+        let loc_all = l.loc.merge(&r.loc);
+        let eq = Ast::binop(BinOp::eq(loc.clone()), l, r, loc_all.clone());
+        Ast::uniop(UniOp::not(loc), eq, loc_all)
+    });
+    let ast = ops.parse("1 <> 2").unwrap();
+    assert_eq!(Interpreter::new().eval(&ast), Ok(Value::Bool(true)));
+}
+
+#[test]
+fn test_partial_tree() {
+    // 構文エラーの箇所を含む木を渡してもパニックしない
+    let (ast, errors) = OperatorTable::default().parse_partial("1 + ").unwrap();
+    assert!(!errors.is_empty());
+
+    let e = Interpreter::new().eval(&ast).unwrap_err();
+    assert_eq!(e.value, InterpreterErrorKind::SyntaxError);
+    assert_eq!(e.diagnostic().code, "E0309");
+    let e = Vm::new().exec(&ast).unwrap_err();
+    assert_eq!(e.value, InterpreterErrorKind::SyntaxError);
+    assert!(WatCompiler::new().compile(&ast).is_err());
+    assert_eq!(
+        derivative(&ast, "x").unwrap_err().value,
+        DiffErrorKind::NotDifferentiable("a syntax error")
+    );
+
+    assert_eq!(RpnCompiler::new().compile(&ast), "1 <error> +");
+    assert_eq!(SexprEmitter::new().emit(&ast), "(+ 1 <error>)");
+    let ops = OperatorTable::default();
+    assert!(LatexEmitter::new(&ops).emit(&ast).contains("error"));
+    assert!(DotEmitter::new().emit(&ast).contains("<error>"));
+}
+
+#[test]
+fn test_deep_tree() {
+    // 長い連鎖から作った深い木も、テストの小さなスタックのスレッドで扱える
+    let src = format!("x{}", " + x".repeat(9_000));
+    let ast: Ast = src.parse().unwrap();
+    let ops = OperatorTable::default();
+    assert_eq!(AstFormatter::new(&ops).format(&ast), src);
+    assert_eq!(ast.clone(), ast);
+    assert!(format!("{:?}", ast).starts_with("Annot { value: BinOp"));
+    assert!(TypeChecker::new().type_of(&ast).is_ok());
+    assert_eq!(
+        Optimizer::new().optimize(&ast).value,
+        ast.value,
+        "nothing to simplify"
+    );
+
+    let mut interp = Interpreter::new();
+    interp.exec(&"let x = 2".parse().unwrap()).unwrap();
+    assert_eq!(interp.eval(&ast).unwrap().to_string(), "18002");
+    let mut vm = Vm::new();
+    vm.exec(&"let x = 2".parse().unwrap()).unwrap();
+    assert_eq!(vm.exec(&ast).unwrap().unwrap().to_string(), "18002");
+    assert!(WatCompiler::new().compile(&ast).is_err());
+    assert!(derivative(&ast, "x").is_ok());
+    assert!(RpnCompiler::new().compile(&ast).ends_with("x +"));
+    SexprEmitter::new().emit(&ast);
+    LatexEmitter::new(&ops).emit(&ast);
+    DotEmitter::new().emit(&ast);
+}
@@ -0,0 +1,159 @@
+// 言語サーバを起動し、決めておいたメッセージを順に送って応答を確かめる
+use serde_json::{json, Value as Json};
+use std::io::Write;
+use std::process::{Command, Stdio};
+
+const URI: &str = "file:///test.calc";
+
+/// クライアントのメッセージをすべて送ってからサーバの出力を読み、メッセージに分ける
+fn session(messages: &[Json]) -> (Vec<Json>, Option<i32>) {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_parser-lsp"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    for msg in messages {
+        let body = msg.to_string();
+        write!(stdin, "Content-Length: {}\r\n\r\n{}", body.len(), body).unwrap();
+    }
+    drop(stdin);
+    let output = child.wait_with_output().unwrap();
+    let mut rest = &output.stdout[..];
+    let mut replies = Vec::new();
+    while !rest.is_empty() {
+        let header_end = rest.windows(4).position(|w| w == b"\r\n\r\n").unwrap();
+        let header = std::str::from_utf8(&rest[..header_end]).unwrap();
+        let len: usize = header["Content-Length: ".len()..].parse().unwrap();
+        let body = &rest[header_end + 4..header_end + 4 + len];
+        replies.push(serde_json::from_slice(body).unwrap());
+        rest = &rest[header_end + 4 + len..];
+    }
+    (replies, output.status.code())
+}
+
+fn request(id: u64, method: &str, params: Json) -> Json {
+    json!({ "jsonrpc": "2.0", "id": id, "method": method, "params": params })
+}
+
+fn notification(method: &str, params: Json) -> Json {
+    json!({ "jsonrpc": "2.0", "method": method, "params": params })
+}
+
+fn at(id: u64, method: &str, line: u64, character: u64) -> Json {
+    request(
+        id,
+        method,
+        json!({
+            "textDocument": { "uri": URI },
+            "position": { "line": line, "character": character },
+        }),
+    )
+}
+
+fn range(line: u64, start: u64, end: u64) -> Json {
+    json!({
+        "start": { "line": line, "character": start },
+        "end": { "line": line, "character": end },
+    })
+}
+
+/// idに対する応答の結果
+fn result(replies: &[Json], id: u64) -> &Json {
+    let reply = replies.iter().find(|r| r["id"] == id).unwrap();
+    &reply["result"]
+}
+
+#[test]
+fn test_lsp_session() {
+    let text = "let x = 2\nfn sq(a) = a * a\nsq(x) + 1\n1 +\n";
+    let formatting = request(
+        7,
+        "textDocument/formatting",
+        json!({ "textDocument": { "uri": URI }, "options": { "tabSize": 4, "insertSpaces": true } }),
+    );
+    let (replies, code) = session(&[
+        request(1, "initialize", json!({ "capabilities": {} })),
+        notification("initialized", json!({})),
+        notification(
+            "textDocument/didOpen",
+            json!({ "textDocument": { "uri": URI, "languageId": "calc", "version": 1, "text": text } }),
+        ),
+        // ホバー
+        at(2, "textDocument/hover", 2, 3),
+        at(3, "textDocument/hover", 2, 6),
+        at(4, "textDocument/hover", 2, 0),
+        // 定義への移動
+        at(5, "textDocument/definition", 2, 3),
+        at(6, "textDocument/definition", 1, 11),
+        // 構文エラーがあるうちは整形しない
+        formatting.clone(),
+        notification(
+            "textDocument/didChange",
+            json!({
+                "textDocument": { "uri": URI, "version": 2 },
+                "contentChanges": [{ "text": "let x=2\nx*3" }],
+            }),
+        ),
+        request(8, "textDocument/formatting", formatting["params"].clone()),
+        request(9, "workspace/symbol", json!({ "query": "" })),
+        request(10, "shutdown", Json::Null),
+        notification("exit", Json::Null),
+    ]);
+    assert_eq!(code, Some(0));
+
+    let capabilities = &result(&replies, 1)["capabilities"];
+    assert_eq!(capabilities["hoverProvider"], true);
+    assert_eq!(capabilities["definitionProvider"], true);
+    assert_eq!(capabilities["documentFormattingProvider"], true);
+
+    // 開いたときと変更したときに診断を送る
+    let published: Vec<_> = replies
+        .iter()
+        .filter(|r| r["method"] == "textDocument/publishDiagnostics")
+        .map(|r| &r["params"]["diagnostics"])
+        .collect();
+    assert_eq!(published.len(), 2);
+    let diagnostics = published[0].as_array().unwrap();
+    assert_eq!(diagnostics.len(), 1);
+    assert_eq!(
+        diagnostics[0]["range"]["start"],
+        json!({ "line": 3, "character": 3 })
+    );
+    assert_eq!(diagnostics[0]["source"], "parser");
+    assert_eq!(published[1], &json!([]));
+
+    let hover = |id| result(&replies, id)["contents"]["value"].clone();
+    assert_eq!(hover(2), "x: int = 2");
+    assert_eq!(hover(3), "sq(x) + 1: number = 5");
+    assert_eq!(hover(4), "fn sq(a) -> number");
+    assert_eq!(result(&replies, 3)["range"], range(2, 0, 9));
+
+    assert_eq!(
+        result(&replies, 5),
+        &json!({ "uri": URI, "range": range(0, 4, 5) })
+    );
+    assert_eq!(
+        result(&replies, 6),
+        &json!({ "uri": URI, "range": range(1, 6, 7) })
+    );
+
+    assert_eq!(result(&replies, 7), &Json::Null);
+    assert_eq!(
+        result(&replies, 8),
+        &json!([{
+            "range": { "start": { "line": 0, "character": 0 }, "end": { "line": 1, "character": 3 } },
+            "newText": "let x = 2\nx * 3\n",
+        }])
+    );
+
+    let unknown = replies.iter().find(|r| r["id"] == 9).unwrap();
+    assert_eq!(unknown["error"]["code"], -32601);
+}
+
+#[test]
+fn test_lsp_exit_without_shutdown() {
+    let (replies, code) = session(&[notification("exit", Json::Null)]);
+    assert!(replies.is_empty());
+    assert_eq!(code, Some(1));
+}